@@ -0,0 +1,66 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use dig_rs::dns::{DnsMessage, DnsQueryType, DnsRecordType};
+use dig_rs::message_ref::DnsMessageRef;
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Builds a response to `www.example.com A` with `answers` A records. When
+/// `compressed` is set, answer names are pointers back to the question,
+/// as servers usually send them; otherwise each name is spelled out.
+fn response(answers: u16, compressed: bool) -> Vec<u8> {
+    let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+    buf.extend_from_slice(&answers.to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    write_name(&mut buf, "www.example.com");
+    buf.extend_from_slice(&[0, 1, 0, 1]);
+    for i in 0..answers {
+        if compressed {
+            buf.extend_from_slice(&[0xc0, 12]);
+        } else {
+            write_name(&mut buf, "www.example.com");
+        }
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 192, 0, 2]);
+        buf.push(i as u8);
+    }
+    buf
+}
+
+fn bench_encode(c: &mut Criterion) {
+    c.bench_function("encode query", |b| {
+        b.iter(|| {
+            let mut message = DnsMessage::new(0x1234);
+            message
+                .set_query(black_box("www.example.com".to_string()), DnsQueryType::Recursive, DnsRecordType::A)
+                .unwrap();
+            message.to_bytes()
+        })
+    });
+}
+
+fn bench_parse(c: &mut Criterion) {
+    for &(name, compressed) in &[("parse simple", false), ("parse compressed", true)] {
+        let mut group = c.benchmark_group(name);
+        for &answers in &[1u16, 8, 24] {
+            let bytes = response(answers, compressed);
+            group.bench_with_input(BenchmarkId::new("owned", answers), &bytes, |b, bytes| {
+                b.iter(|| DnsMessage::from_bytes(black_box(bytes)).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("borrowed", answers), &bytes, |b, bytes| {
+                b.iter(|| {
+                    let message = DnsMessageRef::parse(black_box(bytes)).unwrap();
+                    message.answers().map(|answer| answer.unwrap().ttl).sum::<u32>()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_encode, bench_parse);
+criterion_main!(benches);
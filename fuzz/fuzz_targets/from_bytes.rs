@@ -0,0 +1,13 @@
+//! Feeds arbitrary bytes to the message parser, which must reject bad
+//! input with an error rather than panic. Anything that parses is encoded
+//! again, so the records it produced are exercised too.
+#![no_main]
+
+use dig_rs::dns::DnsMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = DnsMessage::from_bytes(data) {
+        let _ = message.to_bytes();
+    }
+});
@@ -0,0 +1,228 @@
+//! Capture logs record every request and response exactly as they went
+//! over the wire, one JSON object per line, so they can be replayed
+//! through the parser later or turned into test and fuzz fixtures.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dns::{DnsError, DnsMessage, DnsRecordType, QueryStats};
+
+/// CaptureEntry is one exchange with a nameserver.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureEntry {
+    /// When the request was sent, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub server: SocketAddr,
+    // Packets are hex strings, which keeps the log readable and diffable.
+    #[serde(with = "crate::hex")]
+    pub request: Vec<u8>,
+    #[serde(with = "crate::hex")]
+    pub response: Vec<u8>,
+}
+
+impl CaptureEntry {
+    /// An entry for `request` sent to `server` at `sent_at`, answered
+    /// with `response`.
+    pub fn new(server: SocketAddr, sent_at: SystemTime, request: &[u8], response: &[u8]) -> Self {
+        let timestamp_ms = sent_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        CaptureEntry {
+            timestamp_ms,
+            server,
+            request: request.to_vec(),
+            response: response.to_vec(),
+        }
+    }
+
+    /// Parses one line of a capture log.
+    pub fn from_line(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+}
+
+/// Appends `entry` to the capture log at `path`, creating it if needed.
+pub fn append(path: &Path, entry: &CaptureEntry) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // One write per entry, so concurrent writers don't interleave lines.
+    file.write_all(line.as_bytes())
+}
+
+/// Replay answers queries from a capture log instead of the network. A
+/// query gets the response to the first captured request that asked the
+/// same question.
+#[derive(Debug)]
+pub struct Replay {
+    entries: Vec<CaptureEntry>,
+    last_stats: Option<QueryStats>,
+    last_response: Vec<u8>,
+}
+
+impl Replay {
+    /// Loads the capture log at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let log = fs::read_to_string(path)?;
+        let entries = log
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(CaptureEntry::from_line)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Replay::new(entries))
+    }
+
+    pub fn new(entries: Vec<CaptureEntry>) -> Self {
+        Replay {
+            entries,
+            last_stats: None,
+            last_response: Vec::new(),
+        }
+    }
+
+    /// Answers `hostname` and `record` with their captured response,
+    /// skipping responses that did not parse, as a server failed over
+    /// from may have sent. A question that was never captured fails with
+    /// `NotFound`.
+    pub fn query(&mut self, hostname: &str, record: DnsRecordType) -> Result<DnsMessage, DnsError> {
+        let name = hostname.trim_end_matches('.');
+        let (entry, response) = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                DnsMessage::from_bytes(&entry.request).ok().is_some_and(|request| {
+                    request.question().is_some_and(|question| {
+                        question.record_type() == Some(record)
+                            && question.name().trim_end_matches('.').eq_ignore_ascii_case(name)
+                    })
+                })
+            })
+            .find_map(|entry| DnsMessage::from_bytes(&entry.response).ok().map(|response| (entry, response)))
+            .ok_or_else(|| {
+                let message = format!("no captured response for {} {}", hostname, record);
+                io::Error::new(io::ErrorKind::NotFound, message)
+            })?;
+        self.last_stats = Some(QueryStats {
+            server: entry.server,
+            sent_at: UNIX_EPOCH + Duration::from_millis(entry.timestamp_ms),
+            elapsed: Duration::from_secs(0),
+            received: entry.response.len(),
+            attempts: Vec::new(),
+        });
+        self.last_response = entry.response.clone();
+        Ok(response)
+    }
+
+    /// The stats of the captured exchange the last query was answered
+    /// from. The time it took isn't captured, so it shows as zero.
+    pub fn last_stats(&self) -> Option<&QueryStats> {
+        self.last_stats.as_ref()
+    }
+
+    pub fn last_response(&self) -> &[u8] {
+        &self.last_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsQueryType;
+    use crate::resolver::Resolver;
+    use crate::test_util::{mock_server, query_id, question, Packet};
+
+    #[test]
+    fn test_it_appends_an_entry_per_query() {
+        let server = mock_server(|query| {
+            let (name, qtype) = question(query);
+            Some(
+                Packet::response(query_id(query))
+                    .question(&name, qtype)
+                    .answer(&name, 1, 60, vec![192, 0, 2, 1])
+                    .build(),
+            )
+        });
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        resolver.set_recording(true);
+        let path = std::env::temp_dir().join(format!("dig-rs-capture-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for name in &["example.com", "example.org"] {
+            resolver.query(name, DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+            for entry in resolver.take_exchanges() {
+                append(&path, &entry).unwrap();
+            }
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let entries: Vec<CaptureEntry> = log.lines().map(|line| CaptureEntry::from_line(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].server, server);
+        assert!(entries[1].timestamp_ms > 0);
+        assert_eq!(entries[1].response, resolver.last_response());
+        let request = DnsMessage::from_bytes(&entries[1].request).unwrap();
+        assert_eq!(request.question().unwrap().name(), "example.org");
+        let response = DnsMessage::from_bytes(&entries[1].response).unwrap();
+        assert_eq!(response.transaction_id(), request.transaction_id());
+    }
+
+    #[test]
+    fn test_it_captures_every_exchange_of_a_failover() {
+        // The first server's answer has the right id but is too short to
+        // parse.
+        let broken = mock_server(|query| Some(query[..3].to_vec()));
+        let server = mock_server(|query| {
+            let (name, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&name, qtype).build())
+        });
+        let mut resolver = Resolver::new(&[broken, server], false).unwrap();
+        resolver.set_recording(true);
+        resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        let entries = resolver.take_exchanges();
+        assert_eq!(entries.iter().map(|entry| entry.server).collect::<Vec<_>>(), vec![broken, server]);
+        assert_eq!(entries[0].response.len(), 3);
+        assert!(resolver.take_exchanges().is_empty());
+
+        // Replaying skips the response that didn't parse.
+        let mut replay = Replay::new(entries);
+        replay.query("example.com", DnsRecordType::A).unwrap();
+        assert_eq!(replay.last_stats().unwrap().server, server);
+    }
+
+    #[test]
+    fn test_it_replays_captured_responses() {
+        let mut request = DnsMessage::new(0x1234);
+        request
+            .set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        let response = Packet::response(0x1234)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .build();
+        let entry = CaptureEntry {
+            timestamp_ms: 1618317296000,
+            server: "192.0.2.53:53".parse().unwrap(),
+            request: request.to_bytes(),
+            response: response.clone(),
+        };
+        let path = std::env::temp_dir().join(format!("dig-rs-replay-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        append(&path, &entry).unwrap();
+        let mut replay = Replay::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let answer = replay.query("EXAMPLE.com.", DnsRecordType::A).unwrap();
+        assert_eq!(answer.answers()[0].ip_address(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(replay.last_response(), &response[..]);
+        assert_eq!(replay.last_stats().unwrap().server, entry.server);
+        match replay.query("example.com", DnsRecordType::AAAA) {
+            Err(DnsError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+}
@@ -1,36 +1,352 @@
 use clap::{Arg, App};
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::ffi::OsString;
+use std::io::{self, BufRead, IsTerminal};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug)]
+use crate::dns::{
+    Backoff, ClientSubnet, DnsError, DnsQueryClass, DnsQueryType, DnsRecordType, NonAuthoritative, QueryOptions,
+    RetryPolicy, DEFAULT_PORT, DEFAULT_TIMEOUT, EDNS_DO,
+};
+use crate::hex;
+use crate::output::{ColorChoice, OutputFormat};
+use crate::resolver::{AddressQueries, LookupSource, ResolverSettings};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct AppConfig {
-    hostname: String,
-    dns_server: Vec<String>,
+    hostname: Option<String>,
+    dns_server: Vec<SocketAddr>,
+    rotate: bool,
+    address_queries: AddressQueries,
+    lookup: Vec<LookupSource>,
+    record_type: DnsRecordType,
+    batch_file: Option<String>,
+    stdin_names: bool,
+    raw_query: Option<Vec<u8>>,
+    raw_file: Option<String>,
+    capture_file: Option<String>,
+    replay_file: Option<String>,
+    qid: Option<u16>,
+    timeout: Option<u64>,
+    retry: RetryPolicy,
+    query_type: DnsQueryType,
+    family: Option<IpFamily>,
+    query_options: QueryOptions,
+    output_format: OutputFormat,
+    color: ColorChoice,
+    verbosity: u64,
+    nssearch: bool,
+    compare: bool,
+    llmnr: bool,
+    serial: bool,
+    check_delegation: bool,
+    all_servers: bool,
+    idn_display: bool,
+    size_report: bool,
+}
+
+/// ResolvConf holds the parts of resolv.conf that we understand.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResolvConf {
+    pub nameservers: Vec<String>,
+    /// `options rotate`: spread queries across the nameservers.
+    pub rotate: bool,
+    /// `options single-request` or `single-request-reopen`.
+    pub address_queries: AddressQueries,
+    /// `lookup file bind`: where to find addresses, in order.
+    pub lookup: Vec<LookupSource>,
 }
 
-pub fn parse_resolv_conf(resolv_conf_path: String) -> Vec<String> {
+pub fn parse_resolv_conf(resolv_conf_path: String) -> ResolvConf {
     let contents = std::fs::read_to_string(resolv_conf_path);
-    let mut nameservers = vec![];
-    if let Err(_) = contents {
-        return nameservers;
+    let mut resolv_conf = ResolvConf::default();
+    if contents.is_err() {
+        return resolv_conf;
     }
     let lines = contents.unwrap();
 
-    for line in lines.split('\n') {
-        if line.starts_with("nameserver ") {
-            let nameserver_line = line.strip_prefix("nameserver ").unwrap();
-            nameservers.push(nameserver_line.to_string());
+    for line in lines.lines() {
+        // Both `#` and `;` start a comment, either on its own line or
+        // trailing an entry.
+        let line = line.split(['#', ';']).next().unwrap();
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(nameserver) = fields.next() {
+                    resolv_conf.nameservers.push(nameserver.to_string());
+                }
+            }
+            // Unknown sources are left out rather than failing the file.
+            Some("lookup") => {
+                resolv_conf.lookup = fields
+                    .filter_map(|source| match source {
+                        "bind" => Some(LookupSource::Bind),
+                        "file" => Some(LookupSource::File),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            Some("options") => {
+                for option in fields {
+                    match option {
+                        "rotate" => resolv_conf.rotate = true,
+                        "single-request" if resolv_conf.address_queries == AddressQueries::Parallel => {
+                            resolv_conf.address_queries = AddressQueries::Sequential;
+                        }
+                        // Reopening implies sending one at a time as well.
+                        "single-request-reopen" => resolv_conf.address_queries = AddressQueries::SequentialReopen,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    resolv_conf
+}
+
+/// Digrc holds persistent defaults from a JSON config file, such as
+/// `{"type": "AAAA", "server": "9.9.9.9", "timeout": 2}`. Flags on the command line
+/// take precedence over them.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Digrc {
+    #[serde(rename = "type")]
+    pub record_type: Option<DnsRecordType>,
+    /// Used instead of the nameservers in resolv.conf.
+    pub server: Option<String>,
+    /// Seconds to wait for each server's answer.
+    pub timeout: Option<u64>,
+}
+
+/// Where the config file is looked for: `$XDG_CONFIG_HOME/dig-rs/digrc`
+/// if that exists, otherwise `$HOME/.digrc`.
+pub fn digrc_path() -> Option<PathBuf> {
+    if let Some(config_home) = env::var_os("XDG_CONFIG_HOME") {
+        let path = PathBuf::from(config_home).join("dig-rs").join("digrc");
+        if path.exists() {
+            return Some(path);
         }
     }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".digrc"))
+}
+
+/// Reads a config file. A missing file just means no defaults, but one
+/// that exists and is malformed is an error.
+pub fn parse_digrc(path: &Path) -> Result<Digrc, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e)),
+        Err(_) => Ok(Digrc::default()),
+    }
+}
+
+/// Turns a nameserver into the address queries are sent to, using
+/// `DEFAULT_PORT` unless the server names one (`1.2.3.4:5353`, `[::1]:5353`).
+///
+/// IP literals are used as they are. Hostnames are resolved exactly once,
+/// through the system resolver rather than our own, since we need a
+/// nameserver before we can resolve anything ourselves. The first address
+/// returned is used, and a hostname that does not resolve is an error.
+pub fn resolve_nameserver(server: &str) -> Result<SocketAddr, DnsError> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+    let addrs = if server.contains(':') {
+        server.to_socket_addrs()
+    } else {
+        (server, DEFAULT_PORT).to_socket_addrs()
+    };
+    addrs
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| DnsError::InvalidServer(server.to_string()))
+}
+
+/// The block size `+padding` pads to when none is given, as RFC 8467
+/// recommends for queries.
+const DEFAULT_PADDING: u16 = 128;
+
+/// Applies one of dig's `+option` or `+option=value` arguments, or its
+/// `+nooption` form, to `options`, or for `+[no]recurse` to `query_type`.
+fn apply_dig_option(options: &mut QueryOptions, query_type: &mut DnsQueryType, option: &str) -> Result<(), String> {
+    let name = option.trim_start_matches('+');
+    let (name, value) = match name.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (name, None),
+    };
+    let (name, on) = match name.strip_prefix("no") {
+        Some(name) => (name, false),
+        None => (name, true),
+    };
+    let invalid = || format!("invalid value for +{}: {}", name, value.unwrap_or(""));
+    match (name, value) {
+        ("cd" | "cdflag", None) => options.checking_disabled = on,
+        ("recurse", None) if on => *query_type = DnsQueryType::Recursive,
+        ("recurse", None) => *query_type = DnsQueryType::Iterative,
+        ("padding", None) => options.padding = Some(DEFAULT_PADDING).filter(|_| on),
+        ("ednsnegotiation", None) => options.edns_negotiation = on,
+        ("cookie", None) => options.cookie = on,
+        ("padding", Some(value)) if on => options.padding = Some(value.parse().map_err(|_| invalid())?),
+        ("ednsflags", None) if !on => options.edns_flags = None,
+        ("ednsflags", Some(value)) if on => {
+            let flags = match value.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => value.parse(),
+            };
+            let flags = flags.map_err(|_| invalid())?;
+            if flags & !EDNS_DO != 0 {
+                warn!("setting reserved EDNS flags: {:#06x}", flags & !EDNS_DO);
+            }
+            options.edns_flags = Some(flags);
+        }
+        _ => return Err(format!("unknown option: {}", option)),
+    }
+    Ok(())
+}
+
+/// IpFamily is the address family `-4` or `-6` restricts nameservers to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn includes(&self, server: &SocketAddr) -> bool {
+        match self {
+            IpFamily::V4 => server.is_ipv4(),
+            IpFamily::V6 => server.is_ipv6(),
+        }
+    }
+}
+
+/// Keeps only the servers in `family`, if one was asked for. It's an error
+/// for none to be left, rather than quietly using the other family.
+pub fn filter_family(servers: Vec<SocketAddr>, family: Option<IpFamily>) -> Result<Vec<SocketAddr>, String> {
+    let family = match family {
+        Some(family) => family,
+        None => return Ok(servers),
+    };
+    let servers: Vec<SocketAddr> = servers.into_iter().filter(|server| family.includes(server)).collect();
+    if servers.is_empty() {
+        let name = match family {
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        };
+        return Err(format!("no {} nameservers configured", name));
+    }
+    Ok(servers)
+}
+
+/// BatchEntry is one lookup read from a `--file` batch file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchEntry {
+    pub hostname: String,
+    pub record_type: DnsRecordType,
+}
+
+/// Reads `hostname [type]` lines, as batch files and stdin have them,
+/// one at a time. Blank lines and lines starting with `#` are skipped,
+/// and entries without a type use `default_type`.
+pub fn batch_entries<R: BufRead>(
+    reader: R,
+    default_type: DnsRecordType,
+) -> impl Iterator<Item = Result<BatchEntry, DnsError>> {
+    reader.lines().filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut fields = line.split_whitespace();
+        let hostname = fields.next().unwrap().to_string();
+        let record_type = match fields.next().map(str::parse) {
+            Some(Ok(t)) => t,
+            Some(Err(e)) => return Some(Err(e)),
+            None => default_type,
+        };
+        Some(Ok(BatchEntry {
+            hostname,
+            record_type,
+        }))
+    })
+}
+
+/// Whether stdin is a pipe or file with something in it. This waits for
+/// the first line to be written, but only when there is no hostname.
+fn stdin_has_names() -> bool {
+    let stdin = io::stdin();
+    !stdin.is_terminal() && stdin.lock().fill_buf().is_ok_and(|buf| !buf.is_empty())
+}
 
-    nameservers
+/// Parses a batch file of `hostname [type]` lines, as `batch_entries`
+/// reads them.
+pub fn parse_batch_file(
+    batch_path: &str,
+    default_type: DnsRecordType,
+) -> Result<Vec<BatchEntry>, DnsError> {
+    let file = std::fs::File::open(batch_path)?;
+    batch_entries(io::BufReader::new(file), default_type).collect()
 }
 
 impl AppConfig {
+    /// Builds the config from command line arguments, printing usage and
+    /// exiting on invalid input.
     pub fn from<I, T>(args: I) -> Self
     where
         I: Iterator<Item = T>,
         T: Into<OsString> + Clone
+    {
+        AppConfig::try_from(args).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Builds the config from command line arguments, returning invalid
+    /// input as a clap error instead of exiting. Without a hostname or
+    /// batch file, names are read from stdin if it isn't a terminal.
+    pub fn try_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: Iterator<Item = T>,
+        T: Into<OsString> + Clone
+    {
+        let digrc = match digrc_path() {
+            Some(path) => parse_digrc(&path)
+                .map_err(|e| clap::Error::with_description(&e, clap::ErrorKind::InvalidValue))?,
+            None => Digrc::default(),
+        };
+        AppConfig::parse(args, digrc, env::var("DIG_TYPE").ok(), stdin_has_names)
+    }
+
+    /// Like `try_from`, but with the given defaults in place of those
+    /// from the config file, no `DIG_TYPE`, and nothing to read from stdin.
+    pub fn try_from_with<I, T>(args: I, digrc: Digrc) -> Result<Self, clap::Error>
+    where
+        I: Iterator<Item = T>,
+        T: Into<OsString> + Clone
+    {
+        AppConfig::parse(args, digrc, None, || false)
+    }
+
+    /// Parses `args`, falling back to `dig_type`, the value of `DIG_TYPE`,
+    /// for the record type. A hostname may only be left out given a batch
+    /// file, or if `stdin_has_names` says there are names to read from
+    /// stdin, which is only asked when needed.
+    fn parse<I, T, F>(args: I, digrc: Digrc, dig_type: Option<String>, stdin_has_names: F) -> Result<Self, clap::Error>
+    where
+        I: Iterator<Item = T>,
+        T: Into<OsString> + Clone,
+        F: FnOnce() -> bool,
     {
         let app = App::new("dig-rs")
             .version("0.1")
@@ -38,31 +354,516 @@ impl AppConfig {
             .about("Rust version of dig")
             .arg(
                 Arg::with_name("hostname")
-                    .required(true)
                     .index(1)
             )
             .arg(
                 Arg::with_name("global-server")
                     .required(false)
                     .takes_value(true)
-                    .multiple(false)
+                    .multiple(true)
+                    .number_of_values(1)
                     .long("global-server")
+                    .help("Nameserver to query instead of those in resolv.conf; repeat for a failover set")
+            )
+            .arg(
+                Arg::with_name("type")
+                    .required(false)
+                    .takes_value(true)
+                    .long("type")
+                    .short("t")
+                    .validator(|t| t.parse::<DnsRecordType>().map(|_| ()).map_err(|e| e.to_string()))
+            )
+            .arg(
+                Arg::with_name("file")
+                    .required(false)
+                    .takes_value(true)
+                    .long("file")
+                    .short("f")
+                    .help("Read hostnames to look up from a file, one per line")
+            )
+            .arg(
+                Arg::with_name("raw")
+                    .required(false)
+                    .takes_value(true)
+                    .long("raw")
+                    .conflicts_with_all(&["file", "nssearch", "compare"])
+                    .help("Also save the response exactly as received to a file")
+            )
+            .arg(
+                Arg::with_name("capture")
+                    .required(false)
+                    .takes_value(true)
+                    .long("capture")
+                    .conflicts_with("llmnr")
+                    .help("Append every query and response as sent and received to a log file")
+            )
+            .arg(
+                Arg::with_name("replay")
+                    .required(false)
+                    .takes_value(true)
+                    .long("replay")
+                    .conflicts_with_all(&[
+                        "capture",
+                        "nssearch",
+                        "compare",
+                        "llmnr",
+                        "serial",
+                        "all-servers",
+                        "raw-query",
+                    ])
+                    .help("Answer queries from a --capture log instead of the network")
+            )
+            .arg(
+                Arg::with_name("ipv4")
+                    .required(false)
+                    .short("4")
+                    .conflicts_with("ipv6")
+                    .help("Only use IPv4 nameservers")
+            )
+            .arg(
+                Arg::with_name("ipv6")
+                    .required(false)
+                    .short("6")
+                    .help("Only use IPv6 nameservers")
+            )
+            .arg(
+                Arg::with_name("qid")
+                    .required(false)
+                    .takes_value(true)
+                    .long("qid")
+                    .validator(|id| id.parse::<u16>().map(|_| ()).map_err(|_| format!("invalid id: {}", id)))
+                    .help("Send every query with this transaction id instead of a random one")
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .required(false)
+                    .takes_value(true)
+                    .long("timeout")
+                    .validator(|t| t.parse::<u64>().map(|_| ()).map_err(|_| format!("invalid timeout: {}", t)))
+                    .help("Seconds to wait for each server's answer")
+            )
+            .arg(
+                Arg::with_name("tries")
+                    .required(false)
+                    .takes_value(true)
+                    .long("tries")
+                    .validator(|n| match n.parse::<u32>() {
+                        Ok(n) if n > 0 => Ok(()),
+                        _ => Err(format!("invalid number of tries: {}", n)),
+                    })
+                    .help("How many times to send a query that goes unanswered, per server")
+            )
+            .arg(
+                Arg::with_name("backoff")
+                    .required(false)
+                    .takes_value(true)
+                    .long("backoff")
+                    .possible_values(&["constant", "linear", "exponential"])
+                    .help("How the timeout grows from one try to the next")
+            )
+            .arg(
+                Arg::with_name("edns-version")
+                    .required(false)
+                    .takes_value(true)
+                    .long("edns-version")
+                    .validator(|v| v.parse::<u8>().map(|_| ()).map_err(|_| format!("invalid EDNS version: {}", v)))
+                    .help("Send an OPT record with this EDNS version")
+            )
+            .arg(
+                Arg::with_name("subnet")
+                    .required(false)
+                    .takes_value(true)
+                    .long("subnet")
+                    .validator(|s| s.parse::<ClientSubnet>().map(|_| ()).map_err(|e| e.to_string()))
+                    .help("Send the EDNS client subnet option for ADDRESS[/PREFIX]")
+            )
+            .arg(
+                Arg::with_name("raw-query")
+                    .required(false)
+                    .takes_value(true)
+                    .long("raw-query")
+                    .value_name("HEX")
+                    .validator(|hex| hex::decode(&hex).map(|_| ()))
+                    .help("Send this hex-encoded packet as it is instead of building a query")
+            )
+            .arg(
+                Arg::with_name("class")
+                    .required(false)
+                    .takes_value(true)
+                    .short("c")
+                    .long("class")
+                    .validator(|c| c.parse::<DnsQueryClass>().map(|_| ()).map_err(|e| e.to_string()))
+                    .help("Query class: IN, CH, HS or ANY")
+            )
+            .arg(
+                Arg::with_name("idn-display")
+                    .required(false)
+                    .long("idn-display")
+                    .help("Show internationalized names in Unicode rather than as xn-- labels")
+            )
+            .arg(
+                Arg::with_name("size-report")
+                    .required(false)
+                    .long("size-report")
+                    .help("Report each response's encoded size and what name compression saves")
+            )
+            .arg(
+                Arg::with_name("ndjson")
+                    .required(false)
+                    .long("ndjson")
+                    .help("Print each result as one line of JSON")
+            )
+            .arg(
+                Arg::with_name("zonefile")
+                    .required(false)
+                    .long("zonefile")
+                    .conflicts_with("ndjson")
+                    .help("Print answers in master file format")
+            )
+            .arg(
+                Arg::with_name("aa-only")
+                    .required(false)
+                    .long("aa-only")
+                    .help("Fail unless the server answers authoritatively")
+            )
+            .arg(
+                Arg::with_name("color")
+                    .required(false)
+                    .takes_value(true)
+                    .long("color")
+                    .possible_values(&["auto", "always", "never"])
+                    .default_value("auto")
+                    .help("Color output: always, never, or auto to color only on a terminal")
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .required(false)
+                    .multiple(true)
+                    .long("verbose")
+                    .short("v")
+                    .help("Log retries and failovers, repeat to log every packet")
+            )
+            .arg(
+                Arg::with_name("nssearch")
+                    .required(false)
+                    .long("nssearch")
+                    .requires("hostname")
+                    .conflicts_with("file")
+                    .help("Ask every authoritative nameserver of the zone for its SOA serial")
+            )
+            .arg(
+                Arg::with_name("compare")
+                    .required(false)
+                    .long("compare")
+                    .requires("hostname")
+                    .conflicts_with_all(&["file", "nssearch"])
+                    .help("Ask every configured nameserver and compare their answers")
+            )
+            .arg(
+                Arg::with_name("llmnr")
+                    .required(false)
+                    .long("llmnr")
+                    .requires("hostname")
+                    .conflicts_with_all(&["file", "nssearch", "compare"])
+                    .help("Resolve a single-label name with LLMNR on the local link")
+            )
+            .arg(
+                Arg::with_name("serial")
+                    .required(false)
+                    .long("serial")
+                    .requires("hostname")
+                    .conflicts_with_all(&["file", "nssearch", "compare", "llmnr"])
+                    .help("Print just the zone's SOA serial")
+            )
+            .arg(
+                Arg::with_name("check-delegation")
+                    .required(false)
+                    .long("check-delegation")
+                    .visible_alias("check-subdomain")
+                    .requires("hostname")
+                    .conflicts_with_all(&["file", "nssearch", "compare", "llmnr", "serial"])
+                    .help("Compare the zone's delegation at its parent with the zone's own NS records")
+            )
+            .arg(
+                Arg::with_name("all-servers")
+                    .required(false)
+                    .long("all-servers")
+                    .conflicts_with_all(&["nssearch", "compare", "llmnr", "serial", "check-delegation"])
+                    .help("Ask every configured nameserver and print each one's answer")
             );
 
-        let matches = app.get_matches_from(args);
+        // dig's `+option` arguments aren't something clap can parse, so
+        // they are picked out beforehand.
+        let (dig_options, args): (Vec<OsString>, Vec<OsString>) = args
+            .map(Into::into)
+            .partition(|arg| arg.to_str().is_some_and(|arg| arg.starts_with('+')));
+        let mut query_options = QueryOptions::default();
+        let mut query_type = DnsQueryType::default();
+        for option in dig_options {
+            apply_dig_option(&mut query_options, &mut query_type, option.to_str().unwrap())
+                .map_err(|e| clap::Error::with_description(&e, clap::ErrorKind::UnknownArgument))?;
+        }
+
+        let matches = app.get_matches_from_safe(args)?;
+        if matches.is_present("aa-only") {
+            query_options.non_authoritative = NonAuthoritative::Reject;
+        }
+        if let Some(version) = matches.value_of("edns-version") {
+            query_options.edns_version = Some(version.parse().unwrap());
+        }
+        if let Some(subnet) = matches.value_of("subnet") {
+            query_options.client_subnet = Some(subnet.parse().unwrap());
+        }
+        if let Some(class) = matches.value_of("class") {
+            query_options.class = class.parse().unwrap();
+        }
         let resolv_conf_path = env::var_os("DNS_FILE")
             .map(|v| v.to_str().unwrap().to_string())
-            .unwrap_or("/etc/resolv.conf".to_string());
-        let hostname: String = matches.value_of("hostname").unwrap().to_string();
-        let dns_server = matches
-            .value_of("global-server")
-            .map(|r: &str| Vec::from([r.to_string()]))
-            .unwrap_or_else(|| parse_resolv_conf(resolv_conf_path));
-        AppConfig {
+            .unwrap_or_else(|| "/etc/resolv.conf".to_string());
+        let hostname = matches.value_of("hostname").map(|h| h.to_string());
+        // Servers given on the command line are tried in the order given.
+        let global_servers: Option<Vec<String>> = match matches.values_of("global-server") {
+            Some(servers) => Some(servers.map(|s| s.to_string()).collect()),
+            None => digrc.server.clone().map(|s| vec![s]),
+        };
+        let resolv_conf = match global_servers {
+            Some(nameservers) => ResolvConf {
+                nameservers,
+                ..ResolvConf::default()
+            },
+            None => parse_resolv_conf(resolv_conf_path),
+        };
+        let dns_server = resolv_conf
+            .nameservers
+            .iter()
+            .map(|server| resolve_nameserver(server))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue))?;
+        let family = if matches.is_present("ipv4") {
+            Some(IpFamily::V4)
+        } else if matches.is_present("ipv6") {
+            Some(IpFamily::V6)
+        } else {
+            None
+        };
+        let dns_server = filter_family(dns_server, family)
+            .map_err(|e| clap::Error::with_description(&e, clap::ErrorKind::InvalidValue))?;
+        let rotate = resolv_conf.rotate;
+        let address_queries = resolv_conf.address_queries;
+        let lookup = resolv_conf.lookup;
+        // DIG_TYPE, like DNS_FILE, is a fallback for when nothing more
+        // specific was configured.
+        let env_type = match dig_type {
+            Some(t) => Some(t.parse::<DnsRecordType>().map_err(|e| {
+                clap::Error::with_description(&format!("invalid DIG_TYPE: {}", e), clap::ErrorKind::InvalidValue)
+            })?),
+            None => None,
+        };
+        let record_type = matches
+            .value_of("type")
+            .map(|t| t.parse().unwrap())
+            .or(digrc.record_type)
+            .or(env_type)
+            .unwrap_or(DnsRecordType::A);
+        let batch_file = matches.value_of("file").map(|f| f.to_string());
+        let raw_query = matches.value_of("raw-query").map(|hex| hex::decode(hex).unwrap());
+        let stdin_names = hostname.is_none() && batch_file.is_none() && raw_query.is_none();
+        if stdin_names && !stdin_has_names() {
+            return Err(clap::Error {
+                message: format!(
+                    "error: The following required arguments were not provided:\n    <hostname>\n\n{}\n\nFor more information try --help",
+                    matches.usage()
+                ),
+                kind: clap::ErrorKind::MissingRequiredArgument,
+                info: Some(vec!["hostname".to_string()]),
+            });
+        }
+        let raw_file = matches.value_of("raw").map(|f| f.to_string());
+        let capture_file = matches.value_of("capture").map(|f| f.to_string());
+        let replay_file = matches.value_of("replay").map(|f| f.to_string());
+        let qid = matches.value_of("qid").map(|id| id.parse().unwrap());
+        let timeout = matches.value_of("timeout").map(|t| t.parse().unwrap()).or(digrc.timeout);
+        let retry = RetryPolicy {
+            attempts: matches.value_of("tries").map_or(1, |n| n.parse().unwrap()),
+            backoff: match matches.value_of("backoff") {
+                Some("linear") => Backoff::Linear,
+                Some("exponential") => Backoff::Exponential,
+                _ => Backoff::Constant,
+            },
+            ..RetryPolicy::default()
+        };
+        let output_format = if matches.is_present("ndjson") {
+            OutputFormat::Ndjson
+        } else if matches.is_present("zonefile") {
+            OutputFormat::Zonefile
+        } else {
+            OutputFormat::Default
+        };
+        let color = match matches.value_of("color") {
+            Some("always") => ColorChoice::Always,
+            Some("never") => ColorChoice::Never,
+            _ => ColorChoice::Auto,
+        };
+        let verbosity = matches.occurrences_of("verbose");
+        let nssearch = matches.is_present("nssearch");
+        let compare = matches.is_present("compare");
+        let llmnr = matches.is_present("llmnr");
+        let serial = matches.is_present("serial");
+        let check_delegation = matches.is_present("check-delegation");
+        let all_servers = matches.is_present("all-servers");
+        let idn_display = matches.is_present("idn-display");
+        let size_report = matches.is_present("size-report");
+        Ok(AppConfig {
             hostname,
             dns_server,
+            rotate,
+            address_queries,
+            lookup,
+            record_type,
+            batch_file,
+            stdin_names,
+            raw_query,
+            raw_file,
+            capture_file,
+            replay_file,
+            qid,
+            timeout,
+            retry,
+            query_type,
+            family,
+            query_options,
+            output_format,
+            color,
+            verbosity,
+            nssearch,
+            compare,
+            llmnr,
+            serial,
+            check_delegation,
+            all_servers,
+            idn_display,
+            size_report,
+        })
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    pub fn dns_server(&self) -> &[SocketAddr] {
+        &self.dns_server
+    }
+
+    pub fn rotate(&self) -> bool {
+        self.rotate
+    }
+
+    pub fn record_type(&self) -> DnsRecordType {
+        self.record_type
+    }
+
+    pub fn batch_file(&self) -> Option<&str> {
+        self.batch_file.as_deref()
+    }
+
+    /// Whether the names to look up are to be read from stdin.
+    pub fn stdin_names(&self) -> bool {
+        self.stdin_names
+    }
+
+    /// The packet to send as it is, from `--raw-query`.
+    pub fn raw_query(&self) -> Option<&[u8]> {
+        self.raw_query.as_deref()
+    }
+
+    pub fn raw_file(&self) -> Option<&str> {
+        self.raw_file.as_deref()
+    }
+
+    pub fn capture_file(&self) -> Option<&str> {
+        self.capture_file.as_deref()
+    }
+
+    pub fn replay_file(&self) -> Option<&str> {
+        self.replay_file.as_deref()
+    }
+
+    pub fn qid(&self) -> Option<u16> {
+        self.qid
+    }
+
+    /// Whether queries ask for recursion, which `+norecurse` turns off.
+    pub fn query_type(&self) -> DnsQueryType {
+        self.query_type
+    }
+
+    pub fn family(&self) -> Option<IpFamily> {
+        self.family
+    }
+
+    /// The settings to build the `Resolver` for these arguments from. Like
+    /// dig, no search list is applied to the names looked up.
+    pub fn resolver_settings(&self) -> ResolverSettings {
+        ResolverSettings {
+            servers: self.dns_server.clone(),
+            rotate: self.rotate,
+            options: self.query_options,
+            transaction_id: self.qid,
+            timeout: self.timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+            retry: self.retry,
+            address_queries: self.address_queries,
+            lookup: self.lookup.clone(),
+            ..ResolverSettings::default()
         }
     }
+
+    pub fn query_options(&self) -> QueryOptions {
+        self.query_options
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
+    pub fn color(&self) -> ColorChoice {
+        self.color
+    }
+
+    pub fn verbosity(&self) -> u64 {
+        self.verbosity
+    }
+
+    pub fn nssearch(&self) -> bool {
+        self.nssearch
+    }
+
+    pub fn compare(&self) -> bool {
+        self.compare
+    }
+
+    pub fn llmnr(&self) -> bool {
+        self.llmnr
+    }
+
+    pub fn serial(&self) -> bool {
+        self.serial
+    }
+
+    pub fn idn_display(&self) -> bool {
+        self.idn_display
+    }
+
+    pub fn size_report(&self) -> bool {
+        self.size_report
+    }
+
+    pub fn check_delegation(&self) -> bool {
+        self.check_delegation
+    }
+
+    pub fn all_servers(&self) -> bool {
+        self.all_servers
+    }
 }
 
 #[cfg(test)]
@@ -71,16 +872,307 @@ mod tests {
 
     #[test]
     fn test_it_parses_matches() {
-        let app_config = AppConfig::from(vec!["dig-rs", "--global-server", "8.8.8.8", "google.com"].iter());
-        assert_eq!(app_config.hostname, "google.com".to_string());
-        assert_eq!(app_config.dns_server, vec!["8.8.8.8".to_string()]);
+        let args = ["dig-rs", "--global-server", "8.8.8.8", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.hostname, Some("google.com".to_string()));
+        assert_eq!(app_config.dns_server, vec!["8.8.8.8:53".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_it_keeps_explicit_server_ports() {
+        let args = ["dig-rs", "--global-server", "[::1]:5353", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.dns_server, vec!["[::1]:5353".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_it_rejects_invalid_servers_at_parse_time() {
+        // .invalid is reserved and guaranteed never to resolve (RFC 6761).
+        let args = ["dig-rs", "--global-server", "notanip.invalid", "google.com"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::InvalidValue);
+        assert!(err.message.contains("could not resolve nameserver: notanip.invalid"));
     }
 
     #[test]
     fn test_it_parses_resolv_conf() {
         std::env::set_var("DNS_FILE", "test/resolv.conf");
-        let app_config = AppConfig::from(vec!["dig-rs", "google.com"].iter());
-        assert_eq!(app_config.hostname, "google.com".to_string());
-        assert_eq!(app_config.dns_server, vec!["1.1.1.1".to_string()]);
+        let app_config = AppConfig::try_from_with(["dig-rs", "google.com"].iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.hostname, Some("google.com".to_string()));
+        assert_eq!(app_config.dns_server, vec!["1.1.1.1:53".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_it_parses_resolv_conf_comments_and_whitespace() {
+        let resolv_conf = parse_resolv_conf("test/resolv_comments.conf".to_string());
+        assert_eq!(
+            resolv_conf.nameservers,
+            vec!["9.9.9.9".to_string(), "1.0.0.1".to_string(), "8.8.4.4".to_string()]
+        );
+        assert!(!resolv_conf.rotate);
+    }
+
+    #[test]
+    fn test_it_parses_options_rotate() {
+        let resolv_conf = parse_resolv_conf("test/resolv_rotate.conf".to_string());
+        assert_eq!(resolv_conf.nameservers.len(), 2);
+        assert!(resolv_conf.rotate);
+    }
+
+    #[test]
+    fn test_it_parses_options_single_request() {
+        let resolv_conf = parse_resolv_conf("test/resolv_rotate.conf".to_string());
+        assert_eq!(resolv_conf.address_queries, AddressQueries::Parallel);
+        let resolv_conf = parse_resolv_conf("test/resolv_single_request.conf".to_string());
+        assert_eq!(resolv_conf.address_queries, AddressQueries::SequentialReopen);
+        assert!(!resolv_conf.rotate);
+    }
+
+    #[test]
+    fn test_it_parses_the_lookup_order() {
+        let resolv_conf = parse_resolv_conf("test/resolv_lookup.conf".to_string());
+        assert_eq!(resolv_conf.lookup, vec![LookupSource::File, LookupSource::Bind]);
+        let resolv_conf = parse_resolv_conf("test/resolv_rotate.conf".to_string());
+        assert!(resolv_conf.lookup.is_empty());
+    }
+
+    #[test]
+    fn test_it_resolves_nameservers() {
+        assert_eq!(resolve_nameserver("8.8.8.8").unwrap(), "8.8.8.8:53".parse().unwrap());
+        assert_eq!(resolve_nameserver("::1").unwrap(), "[::1]:53".parse().unwrap());
+        let localhost = resolve_nameserver("localhost").unwrap();
+        assert!(localhost.ip().is_loopback());
+        assert_eq!(localhost.port(), DEFAULT_PORT);
+        assert_eq!(resolve_nameserver("192.0.2.1").unwrap().port(), DEFAULT_PORT);
+        assert_eq!(resolve_nameserver("192.0.2.1:5353").unwrap().port(), 5353);
+    }
+
+    #[test]
+    fn test_it_round_trips_through_serde() {
+        let args = ["dig-rs", "--global-server", "8.8.8.8", "--type", "MX", "--ndjson", "-vv", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        let json = serde_json::to_string(&app_config).unwrap();
+        assert!(json.contains(r#""record_type":"MX""#));
+        assert!(json.contains(r#""output_format":"ndjson""#));
+        assert!(json.contains(r#""color":"auto""#));
+        assert_eq!(serde_json::from_str::<AppConfig>(&json).unwrap(), app_config);
+    }
+
+    #[test]
+    fn test_it_uses_digrc_defaults_unless_overridden() {
+        let digrc = parse_digrc(Path::new("test/digrc.json")).unwrap();
+        assert_eq!(digrc.server.as_deref(), Some("9.9.9.9"));
+
+        let args = ["dig-rs", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), digrc).unwrap();
+        assert_eq!(app_config.record_type, DnsRecordType::AAAA);
+        assert_eq!(app_config.dns_server, vec!["9.9.9.9:53".parse().unwrap()]);
+        assert_eq!(app_config.resolver_settings().timeout, Duration::from_secs(2));
+
+        let digrc = parse_digrc(Path::new("test/digrc.json")).unwrap();
+        let args = ["dig-rs", "--type", "MX", "--global-server", "1.1.1.1", "--timeout", "7", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), digrc).unwrap();
+        assert_eq!(app_config.record_type, DnsRecordType::MX);
+        assert_eq!(app_config.dns_server, vec!["1.1.1.1:53".parse().unwrap()]);
+        assert_eq!(app_config.resolver_settings().timeout, Duration::from_secs(7));
+
+        let app_config = AppConfig::try_from_with(["dig-rs", "google.com"].iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.resolver_settings().timeout, DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_it_parses_the_retry_policy() {
+        let args = ["dig-rs", "--tries", "3", "--backoff", "exponential", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        let retry = app_config.resolver_settings().retry;
+        assert_eq!((retry.attempts, retry.backoff), (3, Backoff::Exponential));
+
+        let app_config = AppConfig::try_from_with(["dig-rs", "google.com"].iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.resolver_settings().retry, RetryPolicy::default());
+        assert!(AppConfig::try_from_with(["dig-rs", "--tries", "0", "google.com"].iter(), Digrc::default()).is_err());
+    }
+
+    #[test]
+    fn test_it_reads_the_default_type_from_the_environment() {
+        let dig_type = || Some("aaaa".to_string());
+        let app_config = AppConfig::parse(["dig-rs", "google.com"].iter(), Digrc::default(), dig_type(), || false);
+        assert_eq!(app_config.unwrap().record_type, DnsRecordType::AAAA);
+        let args = ["dig-rs", "-t", "MX", "google.com"];
+        let overridden = AppConfig::parse(args.iter(), Digrc::default(), dig_type(), || false);
+        assert_eq!(overridden.unwrap().record_type, DnsRecordType::MX);
+    }
+
+    #[test]
+    fn test_it_parses_norecurse() {
+        let app_config = AppConfig::try_from_with(["dig-rs", "+norecurse", "google.com"].iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_type(), DnsQueryType::Iterative);
+        let mut message = crate::dns::DnsMessage::new(1);
+        message.set_query("google.com".to_string(), app_config.query_type(), DnsRecordType::A).unwrap();
+        assert!(!message.flags().rd);
+        assert_eq!(message.to_bytes()[2] & 0x01, 0);
+
+        let args = ["dig-rs", "+norecurse", "+recurse", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_type(), DnsQueryType::Recursive);
+    }
+
+    #[test]
+    fn test_it_parses_the_edns_version() {
+        let args = ["dig-rs", "--edns-version", "1", "+ednsnegotiation", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.edns_version, Some(1));
+        assert!(app_config.query_options.edns_negotiation);
+        let args = ["dig-rs", "--edns-version", "256", "google.com"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_it_parses_a_raw_query() {
+        let args = ["dig-rs", "--raw-query", "12340100000100000000000000000100"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.raw_query().unwrap()[..2], [0x12, 0x34]);
+        assert!(!app_config.stdin_names());
+        let args = ["dig-rs", "--raw-query", "123"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+        // A capture log has nothing to answer a raw query with.
+        let args = ["dig-rs", "--replay", "log.ndjson", "--raw-query", "12340100000100000000000000000100"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_it_parses_the_query_class() {
+        let args = ["dig-rs", "--class", "CH", "version.bind"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.class, DnsQueryClass::ChaosClass);
+        let args = ["dig-rs", "-c", "XX", "version.bind"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_it_parses_dig_options() {
+        let app_config = AppConfig::try_from_with(["dig-rs", "+cd", "google.com"].iter(), Digrc::default()).unwrap();
+        assert!(app_config.query_options.checking_disabled);
+        assert_eq!(app_config.hostname(), Some("google.com"));
+
+        let args = ["dig-rs", "google.com", "+cd", "+nocd"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert!(!app_config.query_options.checking_disabled);
+
+        let args = ["dig-rs", "--aa-only", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.non_authoritative, NonAuthoritative::Reject);
+
+        let args = ["dig-rs", "+padding", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.padding, Some(128));
+        let args = ["dig-rs", "+padding=468", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.padding, Some(468));
+        let args = ["dig-rs", "+ednsflags=0x8001", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.query_options.edns_flags, Some(0x8001));
+        let args = ["dig-rs", "+cookie", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert!(app_config.query_options.cookie);
+
+        let err = AppConfig::try_from_with(["dig-rs", "+bogus", "google.com"].iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn test_it_parses_multiple_global_servers() {
+        let args = ["dig-rs", "--global-server", "8.8.8.8", "--global-server", "1.1.1.1", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(
+            app_config.dns_server,
+            vec!["8.8.8.8:53".parse().unwrap(), "1.1.1.1:53".parse().unwrap()]
+        );
+        assert_eq!(app_config.hostname(), Some("google.com"));
+    }
+
+    #[test]
+    fn test_it_filters_servers_by_family() {
+        let servers: Vec<SocketAddr> = ["1.1.1.1:53", "[2606:4700:4700::1111]:53", "9.9.9.9:53"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            filter_family(servers.clone(), Some(IpFamily::V6)).unwrap(),
+            vec![servers[1]]
+        );
+        assert_eq!(filter_family(servers.clone(), None).unwrap(), servers);
+
+        let args = ["dig-rs", "-6", "--global-server", "[::1]:53", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.family(), Some(IpFamily::V6));
+        let args = ["dig-rs", "-6", "--global-server", "1.1.1.1", "google.com"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert!(err.message.contains("no IPv6 nameservers configured"));
+    }
+
+    #[test]
+    fn test_it_parses_qid() {
+        let args = ["dig-rs", "--qid", "4242", "google.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert_eq!(app_config.qid(), Some(4242));
+
+        let args = ["dig-rs", "--qid", "65536", "google.com"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn test_it_ignores_a_missing_digrc() {
+        let digrc = parse_digrc(Path::new("test/no-such-digrc")).unwrap();
+        assert_eq!(digrc, Digrc::default());
+    }
+
+    #[test]
+    fn test_it_parses_batch_file() {
+        let entries = parse_batch_file("test/batch.txt", DnsRecordType::A).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                BatchEntry { hostname: "google.com".to_string(), record_type: DnsRecordType::A },
+                BatchEntry { hostname: "example.com".to_string(), record_type: DnsRecordType::MX },
+                BatchEntry { hostname: "rust-lang.org".to_string(), record_type: DnsRecordType::AAAA },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_it_reads_names_from_stdin() {
+        let app_config = AppConfig::parse(["dig-rs", "--ndjson"].iter(), Digrc::default(), None, || true).unwrap();
+        assert!(app_config.stdin_names());
+        let piped = io::Cursor::new("example.com\nexample.org TXT\n");
+        let entries: Vec<BatchEntry> = batch_entries(piped, app_config.record_type()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                BatchEntry { hostname: "example.com".to_string(), record_type: DnsRecordType::A },
+                BatchEntry { hostname: "example.org".to_string(), record_type: DnsRecordType::TXT },
+            ]
+        );
+
+        // As when stdin is empty.
+        let err = AppConfig::parse(["dig-rs"].iter(), Digrc::default(), None, || false).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+        assert!(err.message.contains("USAGE:"));
+        let err = AppConfig::parse(["dig-rs", "--serial"].iter(), Digrc::default(), None, || true).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn test_it_parses_check_delegation() {
+        let args = ["dig-rs", "--check-subdomain", "sub.example.com"];
+        let app_config = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap();
+        assert!(app_config.check_delegation());
+        let args = ["dig-rs", "--check-delegation", "--nssearch", "example.com"];
+        let err = AppConfig::try_from_with(args.iter(), Digrc::default()).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
     }
 }
@@ -0,0 +1,139 @@
+use std::fmt;
+
+use crate::dns::{same_record, DnsMessage, DnsRecordType, Flags, ResourceRecord};
+use crate::output::{format_record, rcode_name};
+
+/// Section names the record section a `Difference` was found in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Section::Answer => "answer",
+            Section::Authority => "authority",
+            Section::Additional => "additional",
+        })
+    }
+}
+
+/// Difference is one way two messages differ, as `diff_messages` finds
+/// them. `a` and `b` are the messages in the order they were given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    /// The header flags other than the rcode.
+    Flags { a: Flags, b: Flags },
+    /// The response codes, including any extended bits from OPT.
+    Rcode { a: u16, b: u16 },
+    /// A record only the first message has.
+    OnlyInA { section: Section, record: ResourceRecord },
+    /// A record only the second message has.
+    OnlyInB { section: Section, record: ResourceRecord },
+}
+
+/// Renders one line per difference, records prefixed with `-` when only
+/// the first message has them and `+` when only the second does.
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Flags { a, b } => write!(f, "flags: {} vs {}", a, b),
+            Difference::Rcode { a, b } => write!(f, "status: {} vs {}", rcode_label(*a), rcode_label(*b)),
+            Difference::OnlyInA { section, record } => write!(f, "- {}: {}", section, format_record(record)),
+            Difference::OnlyInB { section, record } => write!(f, "+ {}: {}", section, format_record(record)),
+        }
+    }
+}
+
+fn rcode_label(rcode: u16) -> String {
+    if rcode <= u8::MAX.into() {
+        rcode_name(rcode as u8)
+    } else {
+        format!("RCODE{}", rcode)
+    }
+}
+
+/// Records of `a` that `b` lacks, and the other way around.
+fn diff_section(section: Section, a: &[ResourceRecord], b: &[ResourceRecord], differences: &mut Vec<Difference>) {
+    // The OPT record is about the transport, not part of the answer.
+    let records = |records: &[ResourceRecord]| -> Vec<ResourceRecord> {
+        records
+            .iter()
+            .filter(|record| record.record_type() != DnsRecordType::OPT.value())
+            .cloned()
+            .collect()
+    };
+    let (a, b) = (records(a), records(b));
+    for record in a.iter().filter(|record| !b.iter().any(|other| same_record(record, other))) {
+        differences.push(Difference::OnlyInA {
+            section,
+            record: record.clone(),
+        });
+    }
+    for record in b.iter().filter(|record| !a.iter().any(|other| same_record(record, other))) {
+        differences.push(Difference::OnlyInB {
+            section,
+            record: record.clone(),
+        });
+    }
+}
+
+/// Compares two messages, such as two servers' answers to the same
+/// question, reporting their header flags, response codes, and the records
+/// of each section that only one of them has. Record order is ignored.
+pub fn diff_messages(a: &DnsMessage, b: &DnsMessage) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let flags = |message: &DnsMessage| Flags {
+        rcode: 0,
+        ..message.flags()
+    };
+    if flags(a) != flags(b) {
+        differences.push(Difference::Flags { a: flags(a), b: flags(b) });
+    }
+    if a.extended_rcode() != b.extended_rcode() {
+        differences.push(Difference::Rcode {
+            a: a.extended_rcode(),
+            b: b.extended_rcode(),
+        });
+    }
+    diff_section(Section::Answer, a.answers(), b.answers(), &mut differences);
+    diff_section(Section::Authority, a.authority(), b.authority(), &mut differences);
+    diff_section(Section::Additional, a.additional(), b.additional(), &mut differences);
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Packet;
+
+    #[test]
+    fn test_it_reports_differing_records() {
+        let a = Packet::response(1)
+            .question("example.com", 1)
+            .answer("example.com", 1, 300, vec![192, 0, 2, 1])
+            .answer("example.com", 1, 300, vec![192, 0, 2, 2])
+            .build();
+        let b = Packet::response(2)
+            .flags(0x8580)
+            .question("example.com", 1)
+            .answer("EXAMPLE.com", 1, 60, vec![192, 0, 2, 2])
+            .answer("example.com", 1, 60, vec![192, 0, 2, 3])
+            .build();
+        let (a, b) = (DnsMessage::from_bytes(&a).unwrap(), DnsMessage::from_bytes(&b).unwrap());
+        let differences = diff_messages(&a, &b);
+        let lines: Vec<String> = differences.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "flags: qr rd ra vs qr aa rd ra",
+                "- answer: example.com.\t300\tIN\tA\t192.0.2.1",
+                "+ answer: example.com.\t60\tIN\tA\t192.0.2.3",
+            ]
+        );
+        assert!(diff_messages(&a, &a).is_empty());
+    }
+}
@@ -1,35 +1,230 @@
-use std::io::Error;
-use std::net::{ToSocketAddrs, UdpSocket};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
 use serde::{Serialize, Deserialize};
 
+use crate::capture::CaptureEntry;
+
+/// The largest UDP payload a client may expect without EDNS (RFC 1035).
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// The largest message TCP can carry, as its length prefix is 16 bits.
+pub const MAX_TCP_SIZE: usize = 65535;
+
+/// The port nameservers listen on for plain DNS over UDP and TCP.
+pub const DEFAULT_PORT: u16 = 53;
+
+/// The port for DNS over TLS (RFC 7858).
+pub const DOT_PORT: u16 = 853;
+
+/// How long to wait for a response before giving up on a server.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound on compression pointers followed while reading one name,
+/// which stops maliciously looping pointers.
+pub(crate) const MAX_POINTER_JUMPS: usize = 64;
+
+/// DnsError covers everything that can go wrong while building, sending
+/// or parsing a DNS message.
+#[derive(Debug)]
+pub enum DnsError {
+    /// The underlying socket failed, including timeouts.
+    Io(io::Error),
+    /// The received bytes are not a well-formed DNS message. `offset` is
+    /// where in the message the problem was found.
+    Parse { offset: usize, reason: &'static str },
+    /// A hostname could not be encoded, e.g. a label longer than 63 bytes.
+    InvalidName(String),
+    /// A record type name that we do not know about.
+    UnknownRecordType(String),
+    /// A class name that we do not know about.
+    UnknownClass(String),
+    /// There are no nameservers to send the query to.
+    NoServers,
+    /// A nameserver is neither an IP address nor a resolvable hostname.
+    InvalidServer(String),
+    /// The response did not contain the record we needed from it.
+    MissingRecord(DnsRecordType),
+    /// The question echoed in the response is not the one we asked.
+    QuestionMismatch,
+    /// The message would not fit the transport it is meant for.
+    MessageTooLarge { size: usize, limit: usize },
+    /// The overall time allowed for a lookup ran out.
+    Timeout,
+    /// Iterative resolution followed too many referrals, which usually
+    /// means nameservers that can only be found through themselves.
+    ReferralLimit,
+    /// An answer that had to be authoritative came without the AA bit.
+    NotAuthoritative(SocketAddr),
+    /// A UDP response was larger than the receive buffer, which holds as
+    /// much as the query said it could take, so it was cut short.
+    OversizedResponse { limit: usize },
+    /// The server does not implement the EDNS version of the query
+    /// (BADVERS). `supported` is the highest version it does.
+    BadVersion { requested: u8, supported: u8 },
+    /// The server rejected the query's cookie (BADCOOKIE), even when sent
+    /// back the server cookie it gave.
+    BadCookie,
+    /// Nothing listens on the server's port: the host answered a query
+    /// with ICMP port unreachable, which the socket reports as refused.
+    Unreachable(SocketAddr),
+    /// A client subnet that is not an address with an optional prefix
+    /// length that fits it.
+    InvalidSubnet(String),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Io(e) => write!(f, "{}", e),
+            DnsError::Parse { offset, reason } => {
+                write!(f, "malformed DNS message: {} at offset {}", reason, offset)
+            }
+            DnsError::InvalidName(name) => write!(f, "invalid hostname: {}", name),
+            DnsError::UnknownRecordType(name) => write!(f, "unknown record type: {}", name),
+            DnsError::UnknownClass(name) => write!(f, "unknown class: {}", name),
+            DnsError::NoServers => write!(f, "no nameservers configured"),
+            DnsError::InvalidServer(server) => write!(f, "could not resolve nameserver: {}", server),
+            DnsError::MissingRecord(record) => write!(f, "response has no {} record", record),
+            DnsError::QuestionMismatch => write!(f, "response question does not match the query"),
+            DnsError::MessageTooLarge { size, limit } => {
+                write!(f, "message of {} bytes exceeds the {} byte limit", size, limit)
+            }
+            DnsError::Timeout => write!(f, "lookup timed out"),
+            DnsError::ReferralLimit => write!(f, "too many referrals"),
+            DnsError::NotAuthoritative(server) => write!(f, "{} answered non-authoritatively", server),
+            DnsError::OversizedResponse { limit } => {
+                write!(f, "response exceeds the {} byte UDP limit and was truncated", limit)
+            }
+            DnsError::Unreachable(server) => write!(f, "{} refused the query: nothing is listening", server),
+            DnsError::InvalidSubnet(subnet) => write!(f, "invalid client subnet: {}", subnet),
+            DnsError::BadVersion { requested, supported } => {
+                write!(f, "server does not support EDNS version {} (highest is {})", requested, supported)
+            }
+            DnsError::BadCookie => write!(f, "server rejected the query's cookie"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> Self {
+        DnsError::Io(e)
+    }
+}
+
 /// DnsRecordType indicates the type of record being requested,
 /// or the type of record being returned in a response.
-#[derive(Clone, Copy, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum DnsRecordType {
     A = 1,
     NS = 2,
     CNAME = 5,
     SOA = 6,
+    NULL = 10,
     PTR = 12,
+    HINFO = 13,
     MX = 15,
     TXT = 16,
     AAAA = 28,
+    LOC = 29,
     SRV = 33,
+    SPF = 99,
     NAPTR = 35,
+    DNAME = 39,
     OPT = 41,
+    SSHFP = 44,
     IXFR = 251,
     AXFR = 252,
     ANY = 255,
+    URI = 256,
 }
 
+/// Mnemonics for every known record type, used for parsing and display.
+const RECORD_TYPE_NAMES: &[(DnsRecordType, &str)] = &[
+    (DnsRecordType::A, "A"),
+    (DnsRecordType::NS, "NS"),
+    (DnsRecordType::CNAME, "CNAME"),
+    (DnsRecordType::SOA, "SOA"),
+    (DnsRecordType::NULL, "NULL"),
+    (DnsRecordType::PTR, "PTR"),
+    (DnsRecordType::HINFO, "HINFO"),
+    (DnsRecordType::MX, "MX"),
+    (DnsRecordType::TXT, "TXT"),
+    (DnsRecordType::AAAA, "AAAA"),
+    (DnsRecordType::LOC, "LOC"),
+    (DnsRecordType::SRV, "SRV"),
+    (DnsRecordType::SPF, "SPF"),
+    (DnsRecordType::NAPTR, "NAPTR"),
+    (DnsRecordType::DNAME, "DNAME"),
+    (DnsRecordType::OPT, "OPT"),
+    (DnsRecordType::SSHFP, "SSHFP"),
+    (DnsRecordType::IXFR, "IXFR"),
+    (DnsRecordType::AXFR, "AXFR"),
+    (DnsRecordType::ANY, "ANY"),
+    (DnsRecordType::URI, "URI"),
+];
+
 impl DnsRecordType {
-    fn value(&self) -> u8 {
-        return *self as u8;
+    pub fn value(&self) -> u16 {
+        *self as u16
+    }
+
+    /// Looks up the record type for a wire value, if we know it.
+    pub fn from_u16(value: u16) -> Option<Self> {
+        RECORD_TYPE_NAMES
+            .iter()
+            .map(|(t, _)| *t)
+            .find(|t| t.value() == value)
+    }
+
+    fn name(&self) -> &'static str {
+        RECORD_TYPE_NAMES
+            .iter()
+            .find(|(t, _)| t == self)
+            .map(|(_, n)| *n)
+            .unwrap()
+    }
+}
+
+impl fmt::Display for DnsRecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DnsRecordType {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RECORD_TYPE_NAMES
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(s))
+            .map(|(t, _)| *t)
+            .ok_or_else(|| DnsError::UnknownRecordType(s.to_string()))
+    }
+}
+
+/// Renders a raw type value by mnemonic, or in the RFC 3597 `TYPEnnn`
+/// form when we don't know it.
+pub fn record_type_name(value: u16) -> String {
+    match DnsRecordType::from_u16(value) {
+        Some(t) => t.to_string(),
+        None => format!("TYPE{}", value),
     }
 }
 
 /// DnsQueryType indicates how the server returns the responses.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub enum DnsQueryType {
     /// In an Iterative query type, the client is responsible for
     /// doing additional requests if the first nameserver does not
@@ -37,135 +232,4007 @@ pub enum DnsQueryType {
     Iterative = 0,
     /// In a Recursive query type, the server is responsible for
     /// doing additional requests if it does not have the data.
+    #[default]
     Recursive,
 }
 
 impl DnsQueryType {
     fn value(&self) -> u16 {
-        return *self as u16;
+        *self as u16
     }
 }
 
 /// DnsQueryClass indicates the class of the query.
-#[derive(Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DnsQueryClass {
+    #[default]
     InternetClass = 1,
+    /// CHAOS, which servers use for asking about themselves, such as
+    /// `version.bind`.
+    ChaosClass = 3,
+    HesiodClass = 4,
+    /// NONE, which only appears in updates: a record of this class with
+    /// TTL 0 deletes the one record with its rdata (RFC 2136).
     NoClass = 254,
+    /// ANY, which matches every class in a question. In an update, a
+    /// record of this class with TTL 0 and no rdata deletes the whole
+    /// RRset.
     AllClass = 255,
 }
 
-/// QueryZone contains data for the Query/Zone section.
-#[derive(Debug)]
+impl DnsQueryClass {
+    pub fn value(&self) -> u16 {
+        *self as u16
+    }
+
+    /// Looks up the class for a wire value, if we know it.
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(DnsQueryClass::InternetClass),
+            3 => Some(DnsQueryClass::ChaosClass),
+            4 => Some(DnsQueryClass::HesiodClass),
+            254 => Some(DnsQueryClass::NoClass),
+            255 => Some(DnsQueryClass::AllClass),
+            _ => None,
+        }
+    }
+}
+
+/// Mnemonics for every known class, used for parsing and display.
+const CLASS_NAMES: &[(DnsQueryClass, &str)] = &[
+    (DnsQueryClass::InternetClass, "IN"),
+    (DnsQueryClass::ChaosClass, "CH"),
+    (DnsQueryClass::HesiodClass, "HS"),
+    (DnsQueryClass::NoClass, "NONE"),
+    (DnsQueryClass::AllClass, "ANY"),
+];
+
+impl FromStr for DnsQueryClass {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CLASS_NAMES
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(s))
+            .map(|(c, _)| *c)
+            .ok_or_else(|| DnsError::UnknownClass(s.to_string()))
+    }
+}
+
+/// Renders a raw class value by mnemonic, or in the RFC 3597 `CLASSnnn`
+/// form when we don't know it.
+pub fn class_name(value: u16) -> String {
+    match CLASS_NAMES.iter().find(|(c, _)| c.value() == value) {
+        Some((_, name)) => name.to_string(),
+        None => format!("CLASS{}", value),
+    }
+}
+
+/// QueryZone contains data for the Query/Zone section. The type and
+/// class are kept as numbers, so that a question of a type or class we
+/// don't know still parses, as `RecordData::Unknown` does for rdata.
+#[derive(Clone, Debug, PartialEq)]
 pub struct QueryZone {
     qz_name: Box<str>,
-    qz_type: DnsRecordType,
-    qz_class: DnsQueryClass,
+    qz_type: u16,
+    qz_class: u16,
+}
+
+impl QueryZone {
+    pub fn new(name: String, record_type: DnsRecordType, class: DnsQueryClass) -> Result<Self, DnsError> {
+        validate_name(&name)?;
+        Ok(QueryZone {
+            qz_name: name.into_boxed_str(),
+            qz_type: record_type.value(),
+            qz_class: class.value(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.qz_name
+    }
+
+    /// The type asked for, or `None` if it's one we don't know.
+    pub fn record_type(&self) -> Option<DnsRecordType> {
+        DnsRecordType::from_u16(self.qz_type)
+    }
+
+    /// The type asked for, as on the wire.
+    pub fn qtype(&self) -> u16 {
+        self.qz_type
+    }
+
+    /// The class asked in, or `None` if it's one we don't know.
+    pub fn class(&self) -> Option<DnsQueryClass> {
+        DnsQueryClass::from_u16(self.qz_class)
+    }
+
+    /// The class asked in, as on the wire.
+    pub fn qclass(&self) -> u16 {
+        self.qz_class
+    }
+
+    /// Whether `other` asks the same question: the same type and class,
+    /// and the same name ignoring case and any trailing dot.
+    pub fn matches(&self, other: &QueryZone) -> bool {
+        let name = |q: &QueryZone| q.qz_name.trim_end_matches('.').to_ascii_lowercase();
+        self.qz_type == other.qz_type && self.qz_class == other.qz_class && name(self) == name(other)
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.name(&self.qz_name);
+        writer.u16(self.qz_type);
+        writer.u16(self.qz_class);
+    }
+
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(QueryZone {
+            qz_name: reader.name()?.into_boxed_str(),
+            qz_type: reader.u16()?,
+            qz_class: reader.u16()?,
+        })
+    }
+}
+
+/// Soa is the rdata of a SOA record, describing a zone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Soa {
+    /// The primary nameserver for the zone.
+    pub mname: String,
+    /// The mailbox of the person responsible for the zone.
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// The TTL for negative answers from the zone (RFC 2308).
+    pub minimum: u32,
+}
+
+/// Renders the seven fields in dig's order, `mname rname serial refresh
+/// retry expire minimum`, with the names fully qualified.
+impl fmt::Display for Soa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}. {}. {} {} {} {} {}",
+            self.mname, self.rname, self.serial, self.refresh, self.retry, self.expire, self.minimum
+        )
+    }
+}
+
+impl Soa {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Soa {
+            mname: reader.name()?,
+            rname: reader.name()?,
+            serial: reader.u32()?,
+            refresh: reader.u32()?,
+            retry: reader.u32()?,
+            expire: reader.u32()?,
+            minimum: reader.u32()?,
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.name(&self.mname);
+        writer.name(&self.rname);
+        for field in &[self.serial, self.refresh, self.retry, self.expire, self.minimum] {
+            writer.u32(*field);
+        }
+    }
+}
+
+/// Mx is the rdata of an MX record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mx {
+    /// Lower values are preferred.
+    pub preference: u16,
+    /// The mail server.
+    pub exchange: String,
+}
+
+impl Mx {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Mx {
+            preference: reader.u16()?,
+            exchange: reader.name()?,
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.u16(self.preference);
+        writer.name(&self.exchange);
+    }
+}
+
+/// The mail servers named by the MX records among `records`, most
+/// preferred first. Servers of equal preference are shuffled, so that
+/// senders spread their mail across them (RFC 5321, section 5.1).
+pub fn sorted_mx(records: &[ResourceRecord]) -> Vec<Mx> {
+    let mut keyed: Vec<(u16, u64, Mx)> = records
+        .iter()
+        .filter_map(|record| record.mx())
+        .map(|mx| (mx.preference, random_u64(), mx))
+        .collect();
+    keyed.sort_by_key(|(preference, tiebreak, _)| (*preference, *tiebreak));
+    keyed.into_iter().map(|(_, _, mx)| mx).collect()
+}
+
+/// Srv is the rdata of an SRV record (RFC 2782).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Srv {
+    /// Lower values are preferred.
+    pub priority: u16,
+    /// The relative share of traffic among targets of equal priority.
+    pub weight: u16,
+    pub port: u16,
+    /// The host providing the service.
+    pub target: String,
+}
+
+impl Srv {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Srv {
+            priority: reader.u16()?,
+            weight: reader.u16()?,
+            port: reader.u16()?,
+            target: reader.name()?,
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.u16(self.priority);
+        writer.u16(self.weight);
+        writer.u16(self.port);
+        writer.name(&self.target);
+    }
+}
+
+/// Uri is the rdata of a URI record (RFC 7553), which locates a service
+/// by URI rather than by host and port as SRV does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Uri {
+    /// Lower values are preferred.
+    pub priority: u16,
+    /// The relative share of traffic among targets of equal priority.
+    pub weight: u16,
+    /// The URI, which takes up the rest of the rdata without a length
+    /// prefix.
+    pub target: String,
+}
+
+/// Renders `priority weight "target"`, the way master files have it.
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {:?}", self.priority, self.weight, self.target)
+    }
+}
+
+impl Uri {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Uri {
+            priority: reader.u16()?,
+            weight: reader.u16()?,
+            target: String::from_utf8_lossy(reader.rest()).into_owned(),
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.u16(self.priority);
+        writer.u16(self.weight);
+        writer.bytes(self.target.as_bytes());
+    }
+}
+
+/// Hinfo is the rdata of a HINFO record, which describes a host. RFC 8482
+/// servers also send one in reply to ANY queries they won't answer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hinfo {
+    pub cpu: String,
+    pub os: String,
+}
+
+/// Renders both character-strings quoted, the way master files have them.
+impl fmt::Display for Hinfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:?}", self.cpu, self.os)
+    }
+}
+
+impl Hinfo {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Hinfo {
+            cpu: reader.character_string()?,
+            os: reader.character_string()?,
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.character_string(&self.cpu);
+        writer.character_string(&self.os);
+    }
+}
+
+/// Loc is the rdata of a LOC record (RFC 1876): a location on the globe,
+/// with every field as it is on the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loc {
+    /// Always 0; other versions have a format we can't read.
+    pub version: u8,
+    /// The diameter of the located object. This and the two precisions
+    /// are in centimeters, encoded as a mantissa in the high nibble and a
+    /// power of ten in the low one.
+    pub size: u8,
+    pub horizontal_precision: u8,
+    pub vertical_precision: u8,
+    /// Thousandths of an arc second north of the equator, offset by 2^31.
+    pub latitude: u32,
+    /// Thousandths of an arc second east of Greenwich, offset by 2^31.
+    pub longitude: u32,
+    /// Centimeters above a point 100,000 meters below the WGS 84
+    /// reference spheroid.
+    pub altitude: u32,
+}
+
+/// The offset latitude and longitude are stored from.
+const LOC_EQUATOR: u32 = 1 << 31;
+
+/// The altitude of the WGS 84 reference spheroid, in LOC's centimeters.
+const LOC_ALTITUDE_BASE: i64 = 10_000_000;
+
+/// Renders a LOC size or precision in meters, e.g. `30m` or `0.50m`.
+fn loc_meters(encoded: u8) -> String {
+    let cm = u64::from(encoded >> 4) * 10u64.pow(u32::from(encoded & 0x0f).min(9));
+    if cm % 100 == 0 {
+        format!("{}m", cm / 100)
+    } else {
+        format!("{}.{:02}m", cm / 100, cm % 100)
+    }
+}
+
+/// Renders an offset angle as `degrees minutes seconds hemisphere`.
+fn loc_angle(angle: u32, positive: char, negative: char) -> String {
+    let (thousandths, hemisphere) = if angle >= LOC_EQUATOR {
+        (angle - LOC_EQUATOR, positive)
+    } else {
+        (LOC_EQUATOR - angle, negative)
+    };
+    let seconds = thousandths / 1000;
+    format!(
+        "{} {} {}.{:03} {}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        thousandths % 1000,
+        hemisphere
+    )
+}
+
+/// Renders the location in master file syntax, the way dig does:
+/// `42 21 54.000 N 71 6 18.000 W -24.00m 30m 10000m 10m`.
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let altitude = i64::from(self.altitude) - LOC_ALTITUDE_BASE;
+        let sign = if altitude < 0 { "-" } else { "" };
+        write!(
+            f,
+            "{} {} {}{}.{:02}m {} {} {}",
+            loc_angle(self.latitude, 'N', 'S'),
+            loc_angle(self.longitude, 'E', 'W'),
+            sign,
+            altitude.abs() / 100,
+            altitude.abs() % 100,
+            loc_meters(self.size),
+            loc_meters(self.horizontal_precision),
+            loc_meters(self.vertical_precision),
+        )
+    }
+}
+
+impl Loc {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Loc {
+            version: reader.u8()?,
+            size: reader.u8()?,
+            horizontal_precision: reader.u8()?,
+            vertical_precision: reader.u8()?,
+            latitude: reader.u32()?,
+            longitude: reader.u32()?,
+            altitude: reader.u32()?,
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.u8(self.version);
+        writer.u8(self.size);
+        writer.u8(self.horizontal_precision);
+        writer.u8(self.vertical_precision);
+        writer.u32(self.latitude);
+        writer.u32(self.longitude);
+        writer.u32(self.altitude);
+    }
+}
+
+/// Sshfp is the rdata of an SSHFP record (RFC 4255), the fingerprint of
+/// one of a host's SSH keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sshfp {
+    /// The key's algorithm: 1 for RSA, 2 DSA, 3 ECDSA, 4 Ed25519.
+    pub algorithm: u8,
+    /// How the fingerprint was made: 1 for SHA-1, 2 SHA-256.
+    pub fingerprint_type: u8,
+    pub fingerprint: Vec<u8>,
+}
+
+/// Renders `algorithm type fingerprint`, the fingerprint in lowercase hex.
+impl fmt::Display for Sshfp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.algorithm, self.fingerprint_type)?;
+        for b in &self.fingerprint {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sshfp {
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        Ok(Sshfp {
+            algorithm: reader.u8()?,
+            fingerprint_type: reader.u8()?,
+            fingerprint: reader.rest().to_vec(),
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.u8(self.algorithm);
+        writer.u8(self.fingerprint_type);
+        writer.bytes(&self.fingerprint);
+    }
+}
+
+/// RecordData is the rdata of a record, parsed according to its type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Loc(Loc),
+    Ns(String),
+    Cname(String),
+    /// The target of a DNAME record, which redirects every name below
+    /// the owner rather than the owner itself (RFC 6672).
+    Dname(String),
+    Soa(Soa),
+    /// The rdata of a NULL record, which can be anything at all; some
+    /// tunneling protocols carry their payload in it.
+    Null(Vec<u8>),
+    Ptr(String),
+    Hinfo(Hinfo),
+    Mx(Mx),
+    /// The character-strings of a TXT record, in order.
+    Txt(Vec<String>),
+    Srv(Srv),
+    Sshfp(Sshfp),
+    /// The character-strings of an SPF record, which has the same format
+    /// as TXT. The type is obsolete (RFC 7208), but some zones still have
+    /// it.
+    Spf(Vec<String>),
+    Uri(Uri),
+    /// The rdata of a type that isn't parsed, as it is.
+    Unknown { rtype: u16, data: Vec<u8> },
+}
+
+/// Reads the character-strings that make up the rest of the reader's
+/// buffer, as in TXT records.
+fn read_strings(reader: &mut Reader) -> Result<Vec<String>, DnsError> {
+    let mut strings = Vec::new();
+    while reader.pos < reader.buf.len() {
+        strings.push(reader.character_string()?);
+    }
+    Ok(strings)
+}
+
+impl RecordData {
+    /// Reads the rdata of a record of type `rtype`, which takes up the
+    /// rest of the reader's buffer.
+    fn read(rtype: u16, reader: &mut Reader) -> Result<Self, DnsError> {
+        let data = match DnsRecordType::from_u16(rtype) {
+            Some(DnsRecordType::A) => {
+                let b = reader.bytes(4)?;
+                RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            Some(DnsRecordType::AAAA) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(reader.bytes(16)?);
+                RecordData::Aaaa(Ipv6Addr::from(octets))
+            }
+            // Only version 0 is defined; others are kept as unknown data.
+            Some(DnsRecordType::LOC) if reader.buf.get(reader.pos) == Some(&0) => RecordData::Loc(Loc::read(reader)?),
+            Some(DnsRecordType::NS) => RecordData::Ns(reader.name()?),
+            Some(DnsRecordType::CNAME) => RecordData::Cname(reader.name()?),
+            Some(DnsRecordType::DNAME) => RecordData::Dname(reader.name()?),
+            Some(DnsRecordType::SOA) => RecordData::Soa(Soa::read(reader)?),
+            Some(DnsRecordType::NULL) => RecordData::Null(reader.rest().to_vec()),
+            Some(DnsRecordType::PTR) => RecordData::Ptr(reader.name()?),
+            Some(DnsRecordType::HINFO) => RecordData::Hinfo(Hinfo::read(reader)?),
+            Some(DnsRecordType::MX) => RecordData::Mx(Mx::read(reader)?),
+            Some(DnsRecordType::TXT) => RecordData::Txt(read_strings(reader)?),
+            Some(DnsRecordType::SRV) => RecordData::Srv(Srv::read(reader)?),
+            Some(DnsRecordType::SSHFP) => RecordData::Sshfp(Sshfp::read(reader)?),
+            Some(DnsRecordType::SPF) => RecordData::Spf(read_strings(reader)?),
+            Some(DnsRecordType::URI) => RecordData::Uri(Uri::read(reader)?),
+            _ => RecordData::Unknown {
+                rtype,
+                data: reader.rest().to_vec(),
+            },
+        };
+        Ok(data)
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        match self {
+            RecordData::A(address) => writer.bytes(&address.octets()),
+            RecordData::Aaaa(address) => writer.bytes(&address.octets()),
+            RecordData::Loc(loc) => loc.write(writer),
+            RecordData::Ns(name) | RecordData::Cname(name) | RecordData::Ptr(name) | RecordData::Dname(name) => {
+                writer.name(name)
+            }
+            RecordData::Soa(soa) => soa.write(writer),
+            RecordData::Hinfo(hinfo) => hinfo.write(writer),
+            RecordData::Mx(mx) => mx.write(writer),
+            RecordData::Txt(strings) | RecordData::Spf(strings) => {
+                for string in strings {
+                    writer.character_string(string);
+                }
+            }
+            RecordData::Srv(srv) => srv.write(writer),
+            RecordData::Sshfp(sshfp) => sshfp.write(writer),
+            RecordData::Uri(uri) => uri.write(writer),
+            RecordData::Null(data) | RecordData::Unknown { data, .. } => writer.bytes(data),
+        }
+    }
+
+    /// Whether names in this rdata may be compressed on the wire. RFC 3597
+    /// limits that to the types defined in RFC 1035.
+    fn compressible(&self) -> bool {
+        matches!(
+            self,
+            RecordData::Ns(_) | RecordData::Cname(_) | RecordData::Ptr(_) | RecordData::Soa(_) | RecordData::Mx(_)
+        )
+    }
 }
 
 /// ResourceRecord contains data for answers, authority, and addditional
 /// information sections.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ResourceRecord {
     rr_name: Box<str>,
+    rr_type: u16,
+    rr_class: u16,
+    rr_ttl: u32,
+    /// The rdata as it appeared on the wire, except that names inside the
+    /// rdata of known types are decompressed, so that it can be read
+    /// without the rest of the message.
+    rr_data: Vec<u8>,
+    /// The same rdata, parsed.
+    rr_parsed: RecordData,
+}
+
+impl ResourceRecord {
+    /// Creates a record. Names inside `rr_data` must not be compressed;
+    /// the encoder compresses them when the message is serialized. Rdata
+    /// that doesn't parse as its type is kept as `RecordData::Unknown`.
+    pub fn new(name: String, rr_type: u16, rr_class: u16, ttl: u32, rr_data: Vec<u8>) -> Self {
+        let mut reader = Reader::new(&rr_data);
+        let rr_parsed = match RecordData::read(rr_type, &mut reader) {
+            Ok(parsed) if reader.pos == rr_data.len() => parsed,
+            _ => RecordData::Unknown {
+                rtype: rr_type,
+                data: rr_data.clone(),
+            },
+        };
+        ResourceRecord {
+            rr_name: name.into_boxed_str(),
+            rr_type,
+            rr_class,
+            rr_ttl: ttl,
+            rr_data,
+            rr_parsed,
+        }
+    }
 
+    pub fn name(&self) -> &str {
+        &self.rr_name
+    }
+
+    /// The raw record type; see `DnsRecordType::from_u16`.
+    pub fn record_type(&self) -> u16 {
+        self.rr_type
+    }
+
+    /// The raw record class; see `DnsQueryClass::from_u16`.
+    pub fn class(&self) -> u16 {
+        self.rr_class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.rr_ttl
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.rr_data
+    }
+
+    /// The parsed rdata.
+    pub fn rdata(&self) -> &RecordData {
+        &self.rr_parsed
+    }
+
+    /// The address held by an A or AAAA record.
+    pub fn ip_address(&self) -> Option<IpAddr> {
+        match self.rr_parsed {
+            RecordData::A(address) => Some(IpAddr::V4(address)),
+            RecordData::Aaaa(address) => Some(IpAddr::V6(address)),
+            _ => None,
+        }
+    }
+
+    /// The name held by an NS, CNAME or PTR record.
+    pub fn target_name(&self) -> Option<String> {
+        match &self.rr_parsed {
+            RecordData::Ns(name) | RecordData::Cname(name) | RecordData::Ptr(name) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// The mail server named by an MX record.
+    pub fn mx(&self) -> Option<Mx> {
+        match &self.rr_parsed {
+            RecordData::Mx(mx) => Some(mx.clone()),
+            _ => None,
+        }
+    }
+
+    /// The zone described by a SOA record.
+    pub fn soa(&self) -> Option<Soa> {
+        match &self.rr_parsed {
+            RecordData::Soa(soa) => Some(soa.clone()),
+            _ => None,
+        }
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.name(&self.rr_name);
+        writer.u16(self.rr_type);
+        writer.u16(self.rr_class);
+        writer.u32(self.rr_ttl);
+        let rdlength_at = writer.buf.len();
+        writer.u16(0);
+        if self.rr_parsed.compressible() {
+            self.rr_parsed.write(writer);
+        } else {
+            writer.bytes(&self.rr_data);
+        }
+        let rdlength = (writer.buf.len() - rdlength_at - 2) as u16;
+        writer.buf[rdlength_at..rdlength_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+
+    fn read(reader: &mut Reader) -> Result<Self, DnsError> {
+        let rr_name = reader.name()?;
+        let rr_type = reader.u16()?;
+        let rr_class = reader.u16()?;
+        let rr_ttl = reader.u32()?;
+        let rdlength_at = reader.pos;
+        let rdlength = reader.u16()? as usize;
+        let rdata_end = reader.pos + rdlength;
+        if rdata_end > reader.buf.len() {
+            return Err(parse_error(rdlength_at, "rdlength exceeds buffer"));
+        }
+        // The rdata is read through a reader that ends where the rdata
+        // does, so a record can't claim more bytes than its rdlength.
+        // Compression pointers only lead backwards, so they still resolve.
+        let mut rdata = Reader {
+            buf: &reader.buf[..rdata_end],
+            pos: reader.pos,
+        };
+        // In updates, class ANY or NONE with no rdata stands for a whole
+        // RRset (RFC 2136, section 2.4), whatever rdata the type needs.
+        let whole_rrset = rdlength == 0
+            && matches!(DnsQueryClass::from_u16(rr_class), Some(DnsQueryClass::AllClass | DnsQueryClass::NoClass));
+        let rr_parsed = if whole_rrset {
+            RecordData::Unknown {
+                rtype: rr_type,
+                data: Vec::new(),
+            }
+        } else {
+            RecordData::read(rr_type, &mut rdata).map_err(|e| match e {
+                DnsError::Parse { offset, .. } if offset >= rdata_end => parse_error(offset, "rdata runs past rdlength"),
+                e => e,
+            })?
+        };
+        // Nor can it claim more bytes than its fields use.
+        if rdata.pos != rdata_end {
+            return Err(parse_error(rdata.pos, "rdata ends before rdlength"));
+        }
+        let rr_data = match rr_parsed {
+            // Character-strings are read lossily, so their bytes are kept
+            // as they came rather than re-encoded.
+            RecordData::Txt(_)
+            | RecordData::Spf(_)
+            | RecordData::Hinfo(_)
+            | RecordData::Uri(_)
+            | RecordData::Unknown { .. } => {
+                reader.buf[reader.pos..rdata_end].to_vec()
+            }
+            _ => {
+                let mut data = Writer::uncompressed();
+                rr_parsed.write(&mut data);
+                data.into_bytes()
+            }
+        };
+        reader.pos = rdata_end;
+        Ok(ResourceRecord {
+            rr_name: rr_name.into_boxed_str(),
+            rr_type,
+            rr_class,
+            rr_ttl,
+            rr_data,
+            rr_parsed,
+        })
+    }
 }
 
-/// DnsMessageSection contains the data for both requests and responses.
-/// The length can be variable, but is restricted to at most 416 bytes.
-#[derive(Debug)]
-pub struct DnsMessageSection {
-    /// Queries and zone sections have their own format
-    queries: Box<Vec<QueryZone>>,
-    /// Answers, authority, and addditional information sections share
-    /// a common format.
-    answers: Box<Vec<ResourceRecord>>,
-    authority: Box<Vec<ResourceRecord>>,
-    additional: Box<Vec<ResourceRecord>>,
+const QR: u16 = 0x8000;
+const AA: u16 = 0x0400;
+const TC: u16 = 0x0200;
+const RD: u16 = 0x0100;
+const RA: u16 = 0x0080;
+const AD: u16 = 0x0020;
+const CD: u16 = 0x0010;
+
+/// The opcode of a dynamic update (RFC 2136).
+pub const OPCODE_UPDATE: u8 = 5;
+
+/// Flags is the second header field unpacked into its parts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Flags {
+    /// Set on responses, clear on queries.
+    pub qr: bool,
+    /// The kind of query; 0 is a standard query.
+    pub opcode: u8,
+    /// Authoritative answer: the responding server owns the zone.
+    pub aa: bool,
+    /// Truncated: the message did not fit the transport.
+    pub tc: bool,
+    /// Recursion desired.
+    pub rd: bool,
+    /// Recursion available.
+    pub ra: bool,
+    /// Authentic data: the resolver validated the answer with DNSSEC.
+    pub ad: bool,
+    /// Checking disabled: return data even if DNSSEC validation fails.
+    pub cd: bool,
+    pub rcode: u8,
 }
 
-impl DnsMessageSection {
-    fn new() -> Self {
-        let queries: Vec<QueryZone> = Vec::with_capacity(1);
-        let answers: Vec<ResourceRecord> = Vec::new();
-        let authority: Vec<ResourceRecord> = Vec::new();
-        let additional: Vec<ResourceRecord> = Vec::new();
-        DnsMessageSection{
-            queries: Box::new(queries),
-            answers: Box::new(answers),
-            authority: Box::new(authority),
-            additional: Box::new(additional),
+impl From<u16> for Flags {
+    fn from(flags: u16) -> Self {
+        Flags {
+            qr: flags & QR != 0,
+            opcode: ((flags >> 11) & 0xf) as u8,
+            aa: flags & AA != 0,
+            tc: flags & TC != 0,
+            rd: flags & RD != 0,
+            ra: flags & RA != 0,
+            ad: flags & AD != 0,
+            cd: flags & CD != 0,
+            rcode: (flags & 0x000f) as u8,
         }
     }
 }
 
-/// DnsMessage is the DNS message format for both requests and responses.
-/// See RFC-6195 for more information about the fields.
-#[derive(Debug)]
-pub struct DnsMessage {
-    /// Transaction ID is used by the client to match requests to responses
-    transaction_id: u16,
-    /// Flags are split into 10 fields
-    flags: u16,
-    /// The number of queries, generally 1 for a DNS request, 0 for response
-    query_count: u16,
-    /// The number of answers, generally 1 for a DNS response, 0 for request
-    answer_count: u16,
-    /// The number of authority messages
-    authority_count: u16,
-    /// The number of additional messages, used to reduce number of queries
-    additional_count: u16,
-    /// The data
-    records: DnsMessageSection,
+/// Lists the bits that are set, lowercase and in header order, the way
+/// dig's `;; flags:` line does.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = [
+            ("qr", self.qr),
+            ("aa", self.aa),
+            ("tc", self.tc),
+            ("rd", self.rd),
+            ("ra", self.ra),
+            ("ad", self.ad),
+            ("cd", self.cd),
+        ];
+        let set: Vec<&str> = bits.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+        write!(f, "{}", set.join(" "))
+    }
 }
 
-impl DnsMessage {
-    pub fn new(trans_id: u16) -> Self {
-        DnsMessage {
-            transaction_id: trans_id,
+/// SectionCounts is how many entries each section of a message holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SectionCounts {
+    pub questions: u16,
+    pub answers: u16,
+    pub authority: u16,
+    pub additional: u16,
+}
+
+/// DnsHeader is the fixed 12-byte header of a message, as it appears on
+/// the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DnsHeader {
+    pub id: u16,
+    /// The raw flags word, which `Flags::from` splits into its fields.
+    pub flags: u16,
+    pub counts: SectionCounts,
+}
+
+/// The EDNS option code for the nameserver identifier (RFC 5001).
+pub const EDNS_NSID: u16 = 3;
+
+/// The EDNS option code for the client subnet (RFC 7871).
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+
+/// The EDNS option code for cookies (RFC 7873).
+pub const EDNS_COOKIE: u16 = 10;
+
+/// The EDNS option code for padding (RFC 7830).
+pub const EDNS_PADDING: u16 = 12;
+
+/// The DNSSEC OK bit of the EDNS flags word (RFC 3225).
+pub const EDNS_DO: u16 = 0x8000;
+
+/// The extended rcode for an unsupported EDNS version (RFC 6891).
+pub const BADVERS: u16 = 16;
+
+/// The extended rcode for a missing or wrong server cookie (RFC 7873).
+pub const BADCOOKIE: u16 = 23;
+
+/// Edns is the contents of a message's OPT pseudo-record (RFC 6891),
+/// which extends the header with a larger UDP payload size, more flags
+/// and a list of options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns {
+    /// The largest UDP message the sender can receive.
+    pub udp_size: u16,
+    /// The upper 8 bits of the 12-bit extended rcode.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The EDNS flags word, of which only DO (0x8000) is defined.
+    pub flags: u16,
+    /// Options as (code, data) pairs, in the order they appear.
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Default for Edns {
+    /// EDNS version 0 advertising 1232 bytes, the size recommended for
+    /// avoiding IP fragmentation.
+    fn default() -> Self {
+        Edns {
+            udp_size: 1232,
+            extended_rcode: 0,
+            version: 0,
             flags: 0,
-            query_count: 0,
-            answer_count: 0,
-            authority_count: 0,
-            additional_count: 0,
-            records: DnsMessageSection::new(),
+            options: Vec::new(),
         }
     }
+}
 
-    fn set_query(&mut self, hostname: String, query: DnsQueryType, record: DnsRecordType) {
-        // Flip QR (query), 1st bit of flags, to 1
-        self.flags |= 0x8000;
-        // Flip RD (recursion desired), 8th bit of flags, to specified value
-        self.flags |= 0x80 * query.value();
-        self.query_count = 1;
+impl Edns {
+    /// Whether the DO bit is set, asking for DNSSEC records.
+    pub fn dnssec_ok(&self) -> bool {
+        self.flags & EDNS_DO != 0
+    }
+
+    /// The data of the first option with `code`, if there is one.
+    pub fn option(&self, code: u16) -> Option<&[u8]> {
+        self.options.iter().find(|(c, _)| *c == code).map(|(_, data)| data.as_slice())
+    }
+
+    /// The client subnet option, if there is a well-formed one.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.option(EDNS_CLIENT_SUBNET).and_then(|data| ClientSubnet::from_option_data(data).ok())
+    }
+
+    fn from_record(record: &ResourceRecord) -> Result<Self, DnsError> {
+        let mut reader = Reader::new(record.data());
+        let mut options = Vec::new();
+        while reader.pos < reader.buf.len() {
+            let code = reader.u16()?;
+            let len = reader.u16()? as usize;
+            options.push((code, reader.bytes(len)?.to_vec()));
+        }
+        let ttl = record.ttl();
+        Ok(Edns {
+            udp_size: record.class(),
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            flags: ttl as u16,
+            options,
+        })
+    }
+
+    fn to_record(&self) -> ResourceRecord {
+        let mut data = Writer::uncompressed();
+        for (code, option) in &self.options {
+            data.u16(*code);
+            data.u16(option.len() as u16);
+            data.bytes(option);
+        }
+        let ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16 | self.flags as u32;
+        ResourceRecord::new(String::new(), DnsRecordType::OPT.value(), self.udp_size, ttl, data.into_bytes())
     }
 }
 
-#[derive(Debug)]
-pub struct DnsSocket {
-    udp_sock: UdpSocket,
-    trans_id: u16,
+/// ClientSubnet is the EDNS client subnet option (RFC 7871), which tells
+/// a recursive resolver what network a query is on behalf of, so that
+/// geo-targeted answers can be picked for it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    /// How many leading bits of `address` are given.
+    pub source_prefix: u8,
+    /// How many leading bits the answer applies to, which the server
+    /// fills in. It's zero in queries.
+    pub scope_prefix: u8,
 }
 
-impl DnsSocket {
-    pub fn new<T: ToSocketAddrs>(server: T) -> Self {
-        let udp_sock = UdpSocket::bind("0.0.0.0:0").unwrap();
-        udp_sock.connect(server).unwrap();
-        DnsSocket {
-            udp_sock,
-            trans_id: 0,
-        }
+impl ClientSubnet {
+    /// The subnet of `address` given to `source_prefix` bits, with the
+    /// bits past the prefix cleared.
+    pub fn new(address: IpAddr, source_prefix: u8) -> Result<Self, DnsError> {
+        let address = match address {
+            IpAddr::V4(v4) if source_prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(source_prefix)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) if source_prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(source_prefix)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+            _ => return Err(DnsError::InvalidSubnet(format!("{}/{}", address, source_prefix))),
+        };
+        Ok(ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix: 0,
+        })
     }
 
-    pub fn query(
-        &mut self,
-        hostname: String,
-        query: DnsQueryType,
-        record: DnsRecordType,
-    ) -> Result<DnsMessage, Error> {
-        self.trans_id += 1;
-        let mut dns_message = DnsMessage::new(self.trans_id);
-        dns_message.set_query(hostname, query, record);
+    /// The option's wire format: family, both prefix lengths, and only as
+    /// many address bytes as the source prefix covers.
+    pub fn to_option_data(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(address) => (1u16, address.octets().to_vec()),
+            IpAddr::V6(address) => (2u16, address.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        let len = usize::from(self.source_prefix).div_ceil(8).min(octets.len());
+        data.extend_from_slice(&octets[..len]);
+        data
+    }
 
-        Ok(dns_message)
+    pub fn from_option_data(data: &[u8]) -> Result<Self, DnsError> {
+        let mut reader = Reader::new(data);
+        let family = reader.u16()?;
+        let source_prefix = reader.u8()?;
+        let scope_prefix = reader.u8()?;
+        let given = reader.bytes(data.len() - 4)?;
+        let address = match family {
+            1 if given.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..given.len()].copy_from_slice(given);
+                IpAddr::from(octets)
+            }
+            2 if given.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..given.len()].copy_from_slice(given);
+                IpAddr::from(octets)
+            }
+            _ => return Err(parse_error(0, "unsupported client subnet family")),
+        };
+        Ok(ClientSubnet {
+            address,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = DnsError;
+
+    /// Parses `address/prefix`, or a bare address as a /32 or /128.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DnsError::InvalidSubnet(s.to_string());
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let prefix = match (prefix, address) {
+            (Some(prefix), _) => prefix.parse().map_err(|_| invalid())?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        ClientSubnet::new(address, prefix)
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    /// `address/source/scope`, as dig shows it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.address, self.source_prefix, self.scope_prefix)
+    }
+}
+
+/// DnsMessageSection contains the data for both requests and responses.
+/// The length can be variable, but is restricted to at most 416 bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsMessageSection {
+    /// Queries and zone sections have their own format
+    queries: Vec<QueryZone>,
+    /// Answers, authority, and addditional information sections share
+    /// a common format.
+    answers: Vec<ResourceRecord>,
+    authority: Vec<ResourceRecord>,
+    additional: Vec<ResourceRecord>,
+}
+
+impl DnsMessageSection {
+    fn new() -> Self {
+        DnsMessageSection {
+            queries: Vec::with_capacity(1),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+}
+
+/// Whether two records are the same record, perhaps with different TTLs
+/// or name case.
+pub(crate) fn same_record(a: &ResourceRecord, b: &ResourceRecord) -> bool {
+    a.rr_type == b.rr_type && a.rr_class == b.rr_class && a.rr_data == b.rr_data && a.name().eq_ignore_ascii_case(b.name())
+}
+
+/// Drops repeats of a record, keeping the first with the lowest TTL seen.
+fn dedup_records(records: &mut Vec<ResourceRecord>) {
+    let mut kept: Vec<ResourceRecord> = Vec::with_capacity(records.len());
+    for record in records.drain(..) {
+        match kept.iter_mut().find(|k| same_record(k, &record)) {
+            Some(k) => k.rr_ttl = k.rr_ttl.min(record.rr_ttl),
+            None => kept.push(record),
+        }
+    }
+    *records = kept;
+}
+
+/// SizeReport breaks down the encoded size of a message, as
+/// `DnsMessage::size_report` finds it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeReport {
+    /// The size on the wire, with names compressed.
+    pub size: usize,
+    /// The size with every name spelled out in full.
+    pub uncompressed: usize,
+    /// The compressed size of the header, then of the question, answer,
+    /// authority and additional sections.
+    pub sections: [usize; 5],
+}
+
+impl SizeReport {
+    /// The bytes name compression saves.
+    pub fn saved(&self) -> usize {
+        self.uncompressed - self.size
+    }
+}
+
+/// DnsMessage is the DNS message format for both requests and responses.
+/// See RFC-6195 for more information about the fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsMessage {
+    /// Transaction ID is used by the client to match requests to responses
+    transaction_id: u16,
+    /// Flags are split into 10 fields
+    flags: u16,
+    /// The data. The header's section counts are the lengths of these.
+    records: DnsMessageSection,
+}
+
+impl DnsMessage {
+    pub fn new(trans_id: u16) -> Self {
+        DnsMessage {
+            transaction_id: trans_id,
+            flags: 0,
+            records: DnsMessageSection::new(),
+        }
+    }
+
+    /// Empties the message to the state `new(trans_id)` creates, but keeps
+    /// the sections' allocations, so one message can be reused for many
+    /// queries.
+    pub fn reset(&mut self, trans_id: u16) {
+        self.transaction_id = trans_id;
+        self.flags = 0;
+        let records = &mut self.records;
+        records.queries.clear();
+        records.answers.clear();
+        records.authority.clear();
+        records.additional.clear();
+    }
+
+    pub fn set_query(
+        &mut self,
+        hostname: String,
+        query: DnsQueryType,
+        record: DnsRecordType,
+    ) -> Result<(), DnsError> {
+        self.set_query_with_class(hostname, query, record, DnsQueryClass::InternetClass)
+    }
+
+    /// Like `set_query`, for a class other than IN. With class ANY, the
+    /// answers may be of any class, each record carrying its own.
+    pub fn set_query_with_class(
+        &mut self,
+        hostname: String,
+        query: DnsQueryType,
+        record: DnsRecordType,
+        class: DnsQueryClass,
+    ) -> Result<(), DnsError> {
+        let question = QueryZone::new(hostname, record, class)?;
+        // QR (query/response), 1st bit of flags, stays 0 for a query.
+        // Flip RD (recursion desired), 8th bit of flags, to specified value
+        self.flags |= RD * query.value();
+        self.add_question(question);
+        Ok(())
+    }
+
+    /// Appends another question. RFC 1035 allows several, but in practice
+    /// most servers answer only the first, or reject the query with
+    /// FORMERR, so this is mainly useful for testing servers.
+    pub fn add_question(&mut self, question: QueryZone) {
+        self.records.queries.push(question);
+    }
+
+    pub fn transaction_id(&self) -> u16 {
+        self.transaction_id
+    }
+
+    /// The response code, the low 4 bits of flags.
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    /// The full 12-bit response code: `rcode` extended with the upper
+    /// bits carried in the OPT record, if there is one.
+    pub fn extended_rcode(&self) -> u16 {
+        let upper = self.edns().map_or(0, |edns| u16::from(edns.extended_rcode));
+        upper << 4 | u16::from(self.rcode())
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags::from(self.flags)
+    }
+
+    /// The header this message is sent with.
+    pub fn header(&self) -> DnsHeader {
+        DnsHeader {
+            id: self.transaction_id,
+            flags: self.flags,
+            counts: SectionCounts {
+                questions: self.question_count(),
+                answers: self.answer_count(),
+                authority: self.authority_count(),
+                additional: self.additional_count(),
+            },
+        }
+    }
+
+    /// Sets or clears QR, which marks the message as a response.
+    pub fn set_response(&mut self, response: bool) {
+        self.set_flag(QR, response);
+    }
+
+    /// Sets or clears AA, for building responses from a zone's owner.
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.set_flag(AA, authoritative);
+    }
+
+    /// Sets or clears CD, asking a validating resolver for data even if
+    /// it fails DNSSEC validation.
+    pub fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.set_flag(CD, checking_disabled);
+    }
+
+    fn set_flag(&mut self, bit: u16, on: bool) {
+        if on {
+            self.flags |= bit;
+        } else {
+            self.flags &= !bit;
+        }
+    }
+
+    /// The message's EDNS information, if it has a well-formed OPT record.
+    pub fn edns(&self) -> Option<Edns> {
+        self.records
+            .additional
+            .iter()
+            .find(|record| record.record_type() == DnsRecordType::OPT.value())
+            .and_then(|record| Edns::from_record(record).ok())
+    }
+
+    /// The UDP payload size the sender advertised in its OPT record, the
+    /// most it can receive without falling back to TCP.
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        self.edns().map(|edns| edns.udp_size)
+    }
+
+    /// Adds an OPT record with `edns`, replacing any the message has.
+    pub fn set_edns(&mut self, edns: Edns) {
+        self.records
+            .additional
+            .retain(|record| record.record_type() != DnsRecordType::OPT.value());
+        self.records.additional.push(edns.to_record());
+    }
+
+    /// Sets the whole EDNS flags word, adding an OPT record first if there
+    /// is none. Bits other than DO are reserved (RFC 6891, section 6.1.4)
+    /// and servers must ignore them, but they may be set for testing how
+    /// servers cope.
+    pub fn set_edns_flags(&mut self, flags: u16) {
+        let mut edns = self.edns().unwrap_or_default();
+        edns.flags = flags;
+        self.set_edns(edns);
+    }
+
+    /// Adds an EDNS padding option that makes the message's length a
+    /// multiple of `block` bytes (RFC 7830), adding an OPT record first if
+    /// there is none. Padding hides the length of the name from whoever
+    /// watches an encrypted transport such as DoT or DoH; over plain UDP
+    /// the name itself is visible, so padding only wastes bytes.
+    pub fn pad_to(&mut self, block: usize) {
+        if block == 0 {
+            return;
+        }
+        let mut edns = self.edns().unwrap_or_default();
+        edns.options.retain(|(code, _)| *code != EDNS_PADDING);
+        self.set_edns(edns.clone());
+        // The padding option's own code and length take four bytes.
+        let len = self.to_bytes().len() + 4;
+        edns.options.push((EDNS_PADDING, vec![0; (block - len % block) % block]));
+        self.set_edns(edns);
+    }
+
+    /// Whether the name does not exist at all.
+    pub fn is_nxdomain(&self) -> bool {
+        self.rcode() == 3
+    }
+
+    /// Whether the name exists but has no records of the asked-for type:
+    /// NOERROR with no answers, but the zone's SOA in authority
+    /// (RFC 2308). A referral has no SOA, so it doesn't count.
+    pub fn is_nodata(&self) -> bool {
+        self.rcode() == 0 && self.records.answers.is_empty() && self.authority_soa().is_some()
+    }
+
+    /// The answers grouped by record type, in the order each type first
+    /// appears. An ANY query gets a mix of types back, which reads better
+    /// this way.
+    pub fn answers_by_type(&self) -> Vec<(u16, Vec<&ResourceRecord>)> {
+        let mut groups: Vec<(u16, Vec<&ResourceRecord>)> = Vec::new();
+        for record in &self.records.answers {
+            match groups.iter_mut().find(|(rtype, _)| *rtype == record.rr_type) {
+                Some((_, records)) => records.push(record),
+                None => groups.push((record.rr_type, vec![record])),
+            }
+        }
+        groups
+    }
+
+    /// Tidies the message for display and comparison: repeated records are
+    /// dropped from every section, and the answers are grouped into RRsets
+    /// by name and type, in the order each RRset first appears.
+    pub fn canonicalize(&mut self) {
+        dedup_records(&mut self.records.answers);
+        dedup_records(&mut self.records.authority);
+        dedup_records(&mut self.records.additional);
+        let mut rrsets: Vec<Vec<ResourceRecord>> = Vec::new();
+        for record in self.records.answers.drain(..) {
+            let rrset = rrsets
+                .iter_mut()
+                .find(|set| set[0].rr_type == record.rr_type && set[0].name().eq_ignore_ascii_case(record.name()));
+            match rrset {
+                Some(set) => set.push(record),
+                None => rrsets.push(vec![record]),
+            }
+        }
+        self.records.answers = rrsets.into_iter().flatten().collect();
+    }
+
+    /// Whether this is the minimal answer RFC 8482 lets servers give to an
+    /// ANY query instead of everything they have: a lone HINFO record.
+    pub fn is_minimal_any(&self) -> bool {
+        matches!(self.records.answers.as_slice(), [record] if record.rr_type == DnsRecordType::HINFO.value())
+    }
+
+    /// The name `qname` ends up at after following the CNAME and DNAME
+    /// records in the answer section, which is the owner of the records
+    /// actually asked for. DNAMEs are applied by synthesizing the CNAME
+    /// they imply, whether or not the server included it.
+    pub fn canonical_name(&self, qname: &str) -> String {
+        let mut name = qname.trim_end_matches('.').to_string();
+        // Every step uses up a record, so this stops looping chains.
+        for _ in 0..self.records.answers.len() {
+            let next = self.records.answers.iter().find_map(|record| match &record.rr_parsed {
+                RecordData::Cname(target) if record.name().eq_ignore_ascii_case(&name) => Some(target.clone()),
+                RecordData::Dname(target) => synthesize_dname(record.name(), target, &name),
+                _ => None,
+            });
+            match next {
+                Some(next) => name = next,
+                None => break,
+            }
+        }
+        name
+    }
+
+    /// How long the answer as a whole may be cached: the smallest TTL of
+    /// the answer records, or `None` if there are none.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.records.answers.iter().map(|record| record.rr_ttl).min()
+    }
+
+    /// How long an NXDOMAIN or NODATA answer may be cached: the lesser of
+    /// the authority SOA's own TTL and its minimum field (RFC 2308).
+    pub fn negative_ttl(&self) -> Option<u32> {
+        if !self.is_nxdomain() && !self.is_nodata() {
+            return None;
+        }
+        self.records
+            .authority
+            .iter()
+            .find_map(|record| record.soa().map(|soa| record.ttl().min(soa.minimum)))
+    }
+
+    fn authority_soa(&self) -> Option<Soa> {
+        self.records.authority.iter().find_map(|record| record.soa())
+    }
+
+    /// The first question, which for almost every message is the only one.
+    pub fn question(&self) -> Option<&QueryZone> {
+        self.records.queries.first()
+    }
+
+    pub fn questions(&self) -> &[QueryZone] {
+        &self.records.queries
+    }
+
+    /// The number of questions, as actually present in the message.
+    pub fn question_count(&self) -> u16 {
+        self.records.queries.len() as u16
+    }
+
+    /// The number of answer records, as actually present in the message.
+    pub fn answer_count(&self) -> u16 {
+        self.records.answers.len() as u16
+    }
+
+    /// The number of authority records, as actually present in the message.
+    pub fn authority_count(&self) -> u16 {
+        self.records.authority.len() as u16
+    }
+
+    /// The number of additional records, as actually present in the message.
+    pub fn additional_count(&self) -> u16 {
+        self.records.additional.len() as u16
+    }
+
+    pub fn answers(&self) -> &[ResourceRecord] {
+        &self.records.answers
+    }
+
+    pub fn authority(&self) -> &[ResourceRecord] {
+        &self.records.authority
+    }
+
+    pub fn additional(&self) -> &[ResourceRecord] {
+        &self.records.additional
+    }
+
+    /// The answers as `(name, TTL, data)`, which is what most callers want
+    /// from them.
+    pub fn records(&self) -> impl Iterator<Item = (&str, u32, &RecordData)> {
+        self.records.answers.iter().map(|r| (r.name(), r.rr_ttl, &r.rr_parsed))
+    }
+
+    pub fn add_answer(&mut self, record: ResourceRecord) {
+        self.records.answers.push(record);
+    }
+
+    pub fn add_authority(&mut self, record: ResourceRecord) {
+        self.records.authority.push(record);
+    }
+
+    pub fn add_additional(&mut self, record: ResourceRecord) {
+        self.records.additional.push(record);
+    }
+
+    /// Starts a dynamic update of `zone` (RFC 2136). Updates reuse the
+    /// sections of a query under other names: the question names the
+    /// zone, the answers are prerequisites, and the authority section
+    /// holds the changes, which the `update_*` methods add.
+    pub fn update(trans_id: u16, zone: String) -> Result<Self, DnsError> {
+        let mut message = DnsMessage::new(trans_id);
+        message.flags = u16::from(OPCODE_UPDATE) << 11;
+        message.add_question(QueryZone::new(zone, DnsRecordType::SOA, DnsQueryClass::InternetClass)?);
+        Ok(message)
+    }
+
+    /// Adds `record` to its RRset. It keeps its own class and TTL, which
+    /// should be the zone's class and the TTL to store.
+    pub fn update_add(&mut self, record: ResourceRecord) {
+        self.add_authority(record);
+    }
+
+    /// Deletes the whole `record_type` RRset at `name`. This is written
+    /// as class ANY with TTL 0 and empty rdata; type ANY deletes every
+    /// RRset at the name.
+    pub fn update_delete_rrset(&mut self, name: String, record_type: DnsRecordType) {
+        let class = DnsQueryClass::AllClass.value();
+        self.add_authority(ResourceRecord::new(name, record_type.value(), class, 0, Vec::new()));
+    }
+
+    /// Deletes just the record matching `record`'s name, type and rdata,
+    /// leaving the rest of its RRset. This is written as class NONE with
+    /// TTL 0.
+    pub fn update_delete_record(&mut self, record: &ResourceRecord) {
+        let class = DnsQueryClass::NoClass.value();
+        let data = record.data().to_vec();
+        self.add_authority(ResourceRecord::new(record.name().to_string(), record.record_type(), class, 0, data));
+    }
+
+    /// Serializes the message into its wire format, compressing names
+    /// that repeat a name or suffix written earlier in the message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        self.write(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Writes the message, returning where the header and each section
+    /// end.
+    fn write(&self, writer: &mut Writer) -> [usize; 5] {
+        let mut ends = [0; 5];
+        for field in &[
+            self.transaction_id,
+            self.flags,
+            self.question_count(),
+            self.answer_count(),
+            self.authority_count(),
+            self.additional_count(),
+        ] {
+            writer.u16(*field);
+        }
+        ends[0] = writer.buf.len();
+        for query in self.records.queries.iter() {
+            query.write(writer);
+        }
+        ends[1] = writer.buf.len();
+        let sections = [&self.records.answers, &self.records.authority, &self.records.additional];
+        for (i, records) in sections.iter().enumerate() {
+            for record in records.iter() {
+                record.write(writer);
+            }
+            ends[i + 2] = writer.buf.len();
+        }
+        ends
+    }
+
+    /// How large the message is once encoded, and how much of that name
+    /// compression saves.
+    pub fn size_report(&self) -> SizeReport {
+        let mut compressed = Writer::new();
+        let ends = self.write(&mut compressed);
+        let mut uncompressed = Writer::uncompressed();
+        self.write(&mut uncompressed);
+        let mut sections = [0; 5];
+        for i in 0..5 {
+            sections[i] = ends[i] - if i == 0 { 0 } else { ends[i - 1] };
+        }
+        SizeReport {
+            size: ends[4],
+            uncompressed: uncompressed.buf.len(),
+            sections,
+        }
+    }
+
+    /// Like `to_bytes`, but fails rather than returning a message larger
+    /// than `limit`: `MAX_UDP_SIZE` for plain UDP, the advertised buffer
+    /// size with EDNS, or `MAX_TCP_SIZE` over TCP.
+    pub fn to_bytes_checked(&self, limit: usize) -> Result<Vec<u8>, DnsError> {
+        let bytes = self.to_bytes();
+        if bytes.len() > limit {
+            return Err(DnsError::MessageTooLarge {
+                size: bytes.len(),
+                limit,
+            });
+        }
+        Ok(bytes)
+    }
+
+    /// Parses a message from its wire format.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DnsError> {
+        let mut reader = Reader::new(buf);
+        let mut message = DnsMessage::new(reader.u16()?);
+        message.flags = reader.u16()?;
+        let query_count = reader.u16()?;
+        let answer_count = reader.u16()?;
+        let authority_count = reader.u16()?;
+        let additional_count = reader.u16()?;
+        trace!(
+            "parsing message id {} flags {:#06x}: {} questions, {} answers, {} authority, {} additional",
+            message.transaction_id,
+            message.flags,
+            query_count,
+            answer_count,
+            authority_count,
+            additional_count,
+        );
+
+        for _ in 0..query_count {
+            message.records.queries.push(QueryZone::read(&mut reader)?);
+        }
+        for _ in 0..answer_count {
+            message.records.answers.push(ResourceRecord::read(&mut reader)?);
+        }
+        for _ in 0..authority_count {
+            message.records.authority.push(ResourceRecord::read(&mut reader)?);
+        }
+        for _ in 0..additional_count {
+            message.records.additional.push(ResourceRecord::read(&mut reader)?);
+        }
+        Ok(message)
+    }
+}
+
+/// The target of the CNAME that a DNAME from `owner` to `target` implies
+/// for `qname`: `qname` with the `owner` suffix swapped for `target`
+/// (RFC 6672, section 2.2). There is none unless `qname` is below
+/// `owner`, or when the result would be too long to be a name.
+pub fn synthesize_dname(owner: &str, target: &str, qname: &str) -> Option<String> {
+    let owner = owner.trim_end_matches('.');
+    let qname = qname.trim_end_matches('.');
+    let split = qname.len().checked_sub(owner.len() + 1)?;
+    let (prefix, suffix) = (qname.get(..split)?, qname.get(split..)?);
+    if prefix.is_empty() || !suffix.starts_with('.') || !suffix[1..].eq_ignore_ascii_case(owner) {
+        return None;
+    }
+    let target = target.trim_end_matches('.');
+    let synthesized = if target.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{}.{}", prefix, target)
+    };
+    validate_name(&synthesized).ok().map(|_| synthesized)
+}
+
+/// Checks that a hostname can be encoded as a sequence of labels.
+fn validate_name(name: &str) -> Result<(), DnsError> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    if trimmed.is_empty() {
+        return Ok(());
+    }
+    if trimmed.len() > 253 || trimmed.split('.').any(|l| l.is_empty() || l.len() > 63) {
+        return Err(DnsError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Compression pointers hold a 14-bit offset, so only names starting in
+/// the first 16K of a message can be pointed to.
+const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+/// Writer builds a message in wire format.
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+    /// Where each name, or suffix of a name, already written starts,
+    /// keyed by its lowercased form. `None` when not compressing.
+    names: Option<HashMap<String, u16>>,
+}
+
+impl Writer {
+    /// A writer that compresses repeated names.
+    pub(crate) fn new() -> Self {
+        Writer {
+            buf: Vec::with_capacity(MAX_UDP_SIZE),
+            names: Some(HashMap::new()),
+        }
+    }
+
+    /// A writer that spells out every name in full.
+    pub(crate) fn uncompressed() -> Self {
+        Writer {
+            buf: Vec::new(),
+            names: None,
+        }
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Writes a character-string, cut off at the 255 bytes its length
+    /// prefix can count.
+    pub(crate) fn character_string(&mut self, string: &str) {
+        let bytes = &string.as_bytes()[..string.len().min(255)];
+        self.u8(bytes.len() as u8);
+        self.bytes(bytes);
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Writes a name as length-prefixed labels terminated by the root
+    /// label, or ends it early with a pointer to a suffix written before.
+    pub(crate) fn name(&mut self, name: &str) {
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        for i in 0..labels.len() {
+            if let Some(names) = self.names.as_mut() {
+                let suffix = labels[i..].join(".").to_ascii_lowercase();
+                if let Some(offset) = names.get(&suffix) {
+                    let pointer = 0xc000 | *offset;
+                    self.u16(pointer);
+                    return;
+                }
+                if self.buf.len() <= MAX_POINTER_OFFSET {
+                    names.insert(suffix, self.buf.len() as u16);
+                }
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(labels[i].as_bytes());
+        }
+        self.buf.push(0);
+    }
+}
+
+/// Reader walks a received message while checking every read against
+/// the end of the buffer.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+/// A `DnsError::Parse` for a problem found at `offset`.
+pub(crate) fn parse_error(offset: usize, reason: &'static str) -> DnsError {
+    DnsError::Parse { offset, reason }
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], DnsError> {
+        let end = self.pos.saturating_add(len);
+        let slice = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(|| parse_error(self.pos, "field runs past the end of the message"))?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    /// The bytes left before the end of the buffer.
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, DnsError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, DnsError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DnsError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a length-prefixed character-string (RFC 1035, section 3.3).
+    pub(crate) fn character_string(&mut self) -> Result<String, DnsError> {
+        let len = self.u8()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    /// Reads a possibly compressed name, leaving the reader just past
+    /// the name's encoding at the current position.
+    pub(crate) fn name(&mut self) -> Result<String, DnsError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut resume_at = None;
+        let mut jumps = 0;
+        loop {
+            let len = self.u8()?;
+            match len & 0xc0 {
+                0x00 if len == 0 => break,
+                0x00 => {
+                    let label = self.bytes(len as usize)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                }
+                0xc0 => {
+                    let at = self.pos - 1;
+                    let offset = (((len & 0x3f) as usize) << 8) | self.u8()? as usize;
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS {
+                        return Err(parse_error(at, "too many compression pointers"));
+                    }
+                    if resume_at.is_none() {
+                        resume_at = Some(self.pos);
+                    }
+                    self.pos = offset;
+                }
+                _ => return Err(parse_error(self.pos - 1, "reserved label type")),
+            }
+        }
+        if let Some(pos) = resume_at {
+            self.pos = pos;
+        }
+        Ok(labels.join("."))
+    }
+}
+
+/// Parses a message from its wire format, without any network I/O, for
+/// inspecting packets captured elsewhere. This is the stable name for
+/// `DnsMessage::from_bytes`.
+pub fn parse_message(bytes: &[u8]) -> Result<DnsMessage, DnsError> {
+    DnsMessage::from_bytes(bytes)
+}
+
+/// Serializes a message for sending over a transport of the caller's
+/// own. Unlike `DnsMessage::to_bytes`, it checks that every name can be
+/// encoded and that the message fits in a TCP message, the largest a
+/// DNS message can be.
+pub fn encode_message(message: &DnsMessage) -> Result<Vec<u8>, DnsError> {
+    let sections = &message.records;
+    let names = sections.queries.iter().map(|q| q.name()).chain(
+        sections
+            .answers
+            .iter()
+            .chain(sections.authority.iter())
+            .chain(sections.additional.iter())
+            .map(|r| r.name()),
+    );
+    for name in names {
+        validate_name(name)?;
+    }
+    message.to_bytes_checked(MAX_TCP_SIZE)
+}
+
+/// Checks that `response` answers the question asked in `request`, as a
+/// guard against spoofed responses that only guessed the id. Servers may
+/// leave the question out of error responses, so only a successful
+/// response is required to echo it.
+pub(crate) fn check_question(request: &DnsMessage, response: DnsMessage) -> Result<DnsMessage, DnsError> {
+    let asked = request.question().unwrap();
+    match response.question() {
+        Some(echoed) if echoed.matches(asked) => Ok(response),
+        None if response.rcode() != 0 => Ok(response),
+        _ => Err(DnsError::QuestionMismatch),
+    }
+}
+
+/// Picks an unpredictable value from the standard library's randomly
+/// keyed hasher.
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish()
+}
+
+/// Picks an unpredictable id, so that ids are hard to guess for spoofers.
+pub(crate) fn random_u16() -> u16 {
+    random_u64() as u16
+}
+
+/// NonAuthoritative says what to do with answers that lack the AA bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonAuthoritative {
+    #[default]
+    Accept,
+    /// Log a warning, but use the answer.
+    Warn,
+    /// Fail with `DnsError::NotAuthoritative` (`--aa-only`).
+    Reject,
+}
+
+/// The largest response `request` can get. The server may send as much as
+/// EDNS said we can take, or 512 bytes without it. Receive buffers have
+/// room for one byte more, since recv drops whatever doesn't fit: a
+/// datagram that fills the buffer was cut short.
+fn response_limit(request: &DnsMessage) -> usize {
+    request
+        .edns()
+        .map_or(MAX_UDP_SIZE, |edns| usize::from(edns.udp_size).max(MAX_UDP_SIZE))
+}
+
+/// Turns a BADVERS answer to `request` into `DnsError::BadVersion`.
+fn check_version(request: &DnsMessage, response: &DnsMessage) -> Result<(), DnsError> {
+    if response.extended_rcode() != BADVERS {
+        return Ok(());
+    }
+    Err(DnsError::BadVersion {
+        requested: request.edns().map_or(0, |edns| edns.version),
+        supported: response.edns().map_or(0, |edns| edns.version),
+    })
+}
+
+/// Turns a BADCOOKIE answer into `DnsError::BadCookie`.
+fn check_cookie(response: &DnsMessage) -> Result<(), DnsError> {
+    match response.extended_rcode() {
+        BADCOOKIE => Err(DnsError::BadCookie),
+        _ => Ok(()),
+    }
+}
+
+/// QueryOptions are settings applied to every query a socket sends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryOptions {
+    /// Set the CD bit (`+cd`).
+    pub checking_disabled: bool,
+    /// Pad queries to a multiple of this many bytes (`+padding`). See
+    /// `DnsMessage::pad_to`.
+    pub padding: Option<u16>,
+    /// Send this EDNS flags word (`+ednsflags`). See
+    /// `DnsMessage::set_edns_flags`.
+    pub edns_flags: Option<u16>,
+    /// How to treat answers without the AA bit, for when the server is
+    /// meant to be authoritative for the name.
+    pub non_authoritative: NonAuthoritative,
+    /// Send an OPT record with this EDNS version (`--edns-version`).
+    pub edns_version: Option<u8>,
+    /// On BADVERS, ask again at the version the server supports
+    /// (`+ednsnegotiation`) rather than failing.
+    pub edns_negotiation: bool,
+    /// Send the client subnet option with this subnet (`--subnet`).
+    pub client_subnet: Option<ClientSubnet>,
+    /// Send a client cookie (`+cookie`), along with the server cookie
+    /// once the server has given one.
+    pub cookie: bool,
+    /// The class to ask for (`--class`), IN unless changed.
+    pub class: DnsQueryClass,
+}
+
+/// Whether a socket error is a read timing out, which is `WouldBlock` on
+/// unix and `TimedOut` on Windows.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// The longest a single attempt waits under `RetryPolicy::default`,
+/// however far the backoff has grown.
+pub const MAX_RETRY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Backoff is how the timeout grows from one attempt at a query to the
+/// next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Backoff {
+    /// Every attempt waits as long as the first.
+    #[default]
+    Constant,
+    /// Each attempt waits the first one's timeout longer than the last.
+    Linear,
+    /// Each attempt waits twice as long as the last.
+    Exponential,
+}
+
+/// RetryPolicy is how many times a `DnsSocket` sends a query that goes
+/// unanswered, and how long it waits each time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// How many times to send the query in all; 1 never retries.
+    pub attempts: u32,
+    pub backoff: Backoff,
+    /// The most one attempt waits, which bounds the total time.
+    pub max_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Backoff::default(),
+            max_timeout: MAX_RETRY_TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long attempt number `attempt`, counting from 0, waits when the
+    /// first one waits `base`. It's never less than `base`.
+    pub fn timeout(&self, base: Duration, attempt: u32) -> Duration {
+        let timeout = match self.backoff {
+            Backoff::Constant => base,
+            Backoff::Linear => base.saturating_mul(attempt.saturating_add(1)),
+            Backoff::Exponential => base.saturating_mul(2u32.saturating_pow(attempt)),
+        };
+        timeout.min(self.max_timeout).max(base)
+    }
+
+    /// How long every attempt together waits when the first waits `base`.
+    pub fn total(&self, base: Duration) -> Duration {
+        (0..self.attempts.max(1)).map(|attempt| self.timeout(base, attempt)).sum()
+    }
+}
+
+/// QueryStats describes how a query went on the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryStats {
+    /// The nameserver that answered.
+    pub server: SocketAddr,
+    /// When the query was sent.
+    pub sent_at: SystemTime,
+    /// How long the answer took to arrive.
+    pub elapsed: Duration,
+    /// The size of the response, in bytes.
+    pub received: usize,
+    /// Every server tried, in order, ending with the one that answered.
+    pub attempts: Vec<Attempt>,
+}
+
+/// Attempt is one server's part in a query: whether it answered, and how
+/// long it was waited on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attempt {
+    pub server: SocketAddr,
+    /// Why the server failed, or `None` if it answered.
+    pub error: Option<String>,
+    pub elapsed: Duration,
+}
+
+/// BindOptions controls how a `DnsSocket` binds its local end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BindOptions {
+    /// The local address, by default an ephemeral port on all interfaces.
+    pub local_addr: SocketAddr,
+    /// Sets SO_REUSEADDR, and SO_REUSEPORT where there is one, so that
+    /// other sockets may bind the same port. Listeners on the multicast
+    /// ports used by mDNS (5353) and LLMNR (5355) need this to coexist.
+    pub reuse_address: bool,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        BindOptions {
+            local_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            reuse_address: false,
+        }
+    }
+}
+
+impl BindOptions {
+    /// Options for reaching `server`: an ephemeral port on the unspecified
+    /// address of its family, since an IPv4 socket can't reach an IPv6
+    /// server or the other way around.
+    pub fn for_server(server: SocketAddr) -> Self {
+        let local_addr = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        BindOptions {
+            local_addr,
+            ..BindOptions::default()
+        }
+    }
+
+    /// Binds a UDP socket as configured.
+    pub fn bind(&self) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(self.local_addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if self.reuse_address {
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&self.local_addr.into())?;
+        Ok(socket.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct DnsSocket {
+    udp_sock: UdpSocket,
+    server: SocketAddr,
+    trans_id: u16,
+    /// When set, every query uses this id instead of the next one in
+    /// sequence, which makes the packets on the wire reproducible.
+    fixed_trans_id: Option<u16>,
+    /// The stats of the last query that was answered.
+    last_stats: Option<QueryStats>,
+    /// The exact bytes of the last response that was accepted.
+    last_response: Vec<u8>,
+    /// The bytes of the query that response answered.
+    last_request: Vec<u8>,
+    options: QueryOptions,
+    /// How long the first attempt at a query waits, or `None` to wait
+    /// for as long as it takes.
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    /// When queries must be done by, retries and fallbacks included.
+    deadline: Option<Instant>,
+    /// Set once a query with EDNS went unanswered where one without it
+    /// got through, after which queries go without it.
+    edns_incapable: bool,
+    /// The client half of the cookie option, fixed for the socket's
+    /// lifetime as RFC 7873 expects for one server.
+    client_cookie: [u8; 8],
+    /// The server cookie from the last answer that had one for
+    /// `client_cookie`, sent back with later queries.
+    server_cookie: Vec<u8>,
+    /// Whether to keep every exchange in `exchanges`, for a capture log.
+    recording: bool,
+    exchanges: Vec<CaptureEntry>,
+}
+
+impl DnsSocket {
+    /// Creates a socket connected to `server`, bound to the wildcard
+    /// address of the server's family.
+    pub fn new<T: ToSocketAddrs>(server: T) -> io::Result<Self> {
+        DnsSocket::with_source_port(server, None)
+    }
+
+    /// Like `new`, but bound to `source_port` if one is given instead of
+    /// an ephemeral port. A random source port is much of what keeps
+    /// off-path attackers from spoofing answers, so this is only meant
+    /// for testing.
+    pub fn with_source_port<T: ToSocketAddrs>(server: T, source_port: Option<u16>) -> io::Result<Self> {
+        let server = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address for the server"))?;
+        let mut bind = BindOptions::for_server(server);
+        if let Some(port) = source_port {
+            warn!("binding source port {} for {}, which makes spoofed answers easier to get accepted", port, server);
+            bind.local_addr.set_port(port);
+        }
+        DnsSocket::with_bind_options(server, &bind)
+    }
+
+    /// Creates a socket bound as `bind` says rather than to an ephemeral
+    /// port.
+    pub fn with_bind_options<T: ToSocketAddrs>(server: T, bind: &BindOptions) -> io::Result<Self> {
+        let udp_sock = bind.bind()?;
+        udp_sock.connect(server)?;
+        udp_sock.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+        DnsSocket::from_socket(udp_sock)
+    }
+
+    /// Wraps a socket the caller has already set up, which must be
+    /// connected to the nameserver. Its options are left as they are, so
+    /// without a read timeout a query waits for as long as it takes.
+    pub fn from_socket(udp_sock: UdpSocket) -> io::Result<Self> {
+        let server = udp_sock.peer_addr()?;
+        let timeout = udp_sock.read_timeout()?;
+        Ok(DnsSocket {
+            udp_sock,
+            server,
+            trans_id: random_u16(),
+            fixed_trans_id: None,
+            last_stats: None,
+            last_response: Vec::new(),
+            last_request: Vec::new(),
+            options: QueryOptions::default(),
+            timeout,
+            retry: RetryPolicy::default(),
+            deadline: None,
+            edns_incapable: false,
+            client_cookie: random_u64().to_be_bytes(),
+            server_cookie: Vec::new(),
+            recording: false,
+            exchanges: Vec::new(),
+        })
+    }
+
+    /// Creates a socket whose queries all carry `trans_id`. This is meant
+    /// for tests that need byte-for-byte reproducible packets; everything
+    /// else should use `new` and its randomized ids.
+    pub fn with_transaction_id<T: ToSocketAddrs>(server: T, trans_id: u16) -> io::Result<Self> {
+        let mut socket = DnsSocket::new(server)?;
+        socket.set_transaction_id(Some(trans_id));
+        Ok(socket)
+    }
+
+    /// Pins the id of every later query to `trans_id`, or goes back to
+    /// randomized ids on `None`.
+    pub fn set_transaction_id(&mut self, trans_id: Option<u16>) {
+        self.fixed_trans_id = trans_id;
+    }
+
+    /// The nameserver this socket is connected to.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// The local address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp_sock.local_addr()
+    }
+
+    /// Sets the options of later queries. A server found to drop EDNS
+    /// gets another chance at the new ones.
+    pub fn set_options(&mut self, options: QueryOptions) {
+        self.options = options;
+        self.edns_incapable = false;
+    }
+
+    /// Sets how long each query waits for its answer, `DEFAULT_TIMEOUT`
+    /// unless changed. A zero timeout is rounded up to a millisecond, as
+    /// sockets take zero to mean no timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let timeout = timeout.max(Duration::from_millis(1));
+        self.timeout = Some(timeout);
+        self.udp_sock.set_read_timeout(Some(timeout))
+    }
+
+    /// Sets how often a query that times out is sent again, and how the
+    /// timeout grows between attempts. By default it isn't.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Makes every later query, with its retries and fallbacks, give up
+    /// at `deadline` with `DnsError::Timeout`, or lifts that on `None`.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// The most a query waits for answers under the timeout and retry
+    /// policy, not counting fallbacks, or `None` without a timeout.
+    pub fn time_allowed(&self) -> Option<Duration> {
+        self.timeout.map(|base| self.retry.total(base))
+    }
+
+    /// `timeout` cut short by the deadline, if there is one, and a
+    /// `DnsError::Timeout` once that has passed.
+    fn read_timeout(&self, timeout: Option<Duration>) -> Result<Option<Duration>, DnsError> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(timeout),
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(DnsError::Timeout);
+        }
+        Ok(Some(timeout.map_or(remaining, |timeout| timeout.min(remaining))))
+    }
+
+    /// Whether the server seems to drop queries with EDNS, so that they
+    /// are now sent without it.
+    pub fn edns_incapable(&self) -> bool {
+        self.edns_incapable
+    }
+
+    /// Whether queries get an OPT record with these options.
+    fn sends_edns(&self) -> bool {
+        !self.edns_incapable
+            && (self.options.edns_version.is_some()
+                || self.options.edns_flags.is_some()
+                || self.options.client_subnet.is_some()
+                || self.options.cookie
+                || self.pads())
+    }
+
+    fn pads(&self) -> bool {
+        self.options.padding.is_some_and(|block| block > 0)
+    }
+
+    /// The options a query without EDNS leaves out, by their dig names.
+    fn edns_options_dropped(&self) -> Vec<&'static str> {
+        let options = &self.options;
+        [
+            (options.edns_version.is_some(), "--edns-version"),
+            (options.edns_flags.is_some(), "+ednsflags"),
+            (options.client_subnet.is_some(), "--subnet"),
+            (options.cookie, "+cookie"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    /// Keeps every response received from now on, with the request it
+    /// answers, until `take_exchanges` collects them. Answers that fail
+    /// to parse or check out are kept too.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    /// The exchanges kept since the last call, oldest first.
+    pub fn take_exchanges(&mut self) -> Vec<CaptureEntry> {
+        std::mem::take(&mut self.exchanges)
+    }
+
+    fn record(&mut self, sent_at: SystemTime, request: &[u8], response: &[u8]) {
+        if self.recording {
+            self.exchanges.push(CaptureEntry::new(self.server, sent_at, request, response));
+        }
+    }
+
+    /// The server cookie the socket sends back, empty until a server has
+    /// given one.
+    pub fn server_cookie(&self) -> &[u8] {
+        &self.server_cookie
+    }
+
+    /// Keeps the server cookie of `response` if it has one for our
+    /// client cookie. RFC 7873 allows 8 to 32 bytes of it.
+    fn store_server_cookie(&mut self, response: &DnsMessage) {
+        let cookie = response.edns().and_then(|edns| edns.option(EDNS_COOKIE).map(<[u8]>::to_vec));
+        if let Some(cookie) = cookie {
+            if (16..=40).contains(&cookie.len()) && cookie[..8] == self.client_cookie {
+                self.server_cookie = cookie[8..].to_vec();
+            }
+        }
+    }
+
+    /// The stats of the last query that got an answer, if any.
+    pub fn last_stats(&self) -> Option<&QueryStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// The raw bytes of the last answer, as received. Empty until a query
+    /// has been answered.
+    pub fn last_response(&self) -> &[u8] {
+        &self.last_response
+    }
+
+    /// The bytes of the query `last_response` answered.
+    pub fn last_request(&self) -> &[u8] {
+        &self.last_request
+    }
+
+    /// Builds the next query for `hostname` with this socket's options,
+    /// returning it with its encoding.
+    fn build_query(
+        &mut self,
+        hostname: String,
+        query: DnsQueryType,
+        record: DnsRecordType,
+        edns_version: Option<u8>,
+    ) -> Result<(DnsMessage, Vec<u8>), DnsError> {
+        let trans_id = self.next_transaction_id();
+        let mut dns_message = DnsMessage::new(trans_id);
+        dns_message.set_query_with_class(hostname, query, record, self.options.class)?;
+        dns_message.set_checking_disabled(self.options.checking_disabled);
+        if self.sends_edns() {
+            if let Some(version) = edns_version {
+                dns_message.set_edns(Edns {
+                    version,
+                    ..Edns::default()
+                });
+            }
+            if let Some(subnet) = self.options.client_subnet {
+                let mut edns = dns_message.edns().unwrap_or_default();
+                edns.options.push((EDNS_CLIENT_SUBNET, subnet.to_option_data()));
+                dns_message.set_edns(edns);
+            }
+            if self.options.cookie {
+                let mut edns = dns_message.edns().unwrap_or_default();
+                edns.options.push((EDNS_COOKIE, [&self.client_cookie[..], &self.server_cookie].concat()));
+                dns_message.set_edns(edns);
+            }
+            if let Some(flags) = self.options.edns_flags {
+                dns_message.set_edns_flags(flags);
+            }
+            if let Some(block) = self.options.padding {
+                dns_message.pad_to(block.into());
+            }
+        }
+        let bytes = dns_message.to_bytes_checked(MAX_UDP_SIZE)?;
+        Ok((dns_message, bytes))
+    }
+
+    pub fn query(
+        &mut self,
+        hostname: String,
+        query: DnsQueryType,
+        record: DnsRecordType,
+    ) -> Result<DnsMessage, DnsError> {
+        let sent_edns = self.sends_edns();
+        let result = self.exchange(hostname.clone(), query, record, self.options.edns_version);
+        self.recover(&hostname, query, record, sent_edns, result)
+    }
+
+    /// Makes the retries that a failed first exchange for `record` calls
+    /// for: at the EDNS version the server supports, with the cookie it
+    /// sent, or without EDNS after a timeout. `sent_edns` is whether the
+    /// first exchange had an OPT record.
+    fn recover(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        record: DnsRecordType,
+        sent_edns: bool,
+        result: Result<DnsMessage, DnsError>,
+    ) -> Result<DnsMessage, DnsError> {
+        let version = self.options.edns_version;
+        let result = match result {
+            Err(DnsError::BadVersion { requested, supported }) if self.options.edns_negotiation => {
+                debug!("{} does not support EDNS version {}, retrying at {}", self.server, requested, supported);
+                self.exchange(hostname.to_string(), query, record, Some(supported))
+            }
+            // The answer carried the server cookie to use, so one retry
+            // with it should get through.
+            Err(DnsError::BadCookie) if self.options.cookie => {
+                debug!("{} rejected our cookie, retrying with the one it sent", self.server);
+                self.exchange(hostname.to_string(), query, record, version)
+            }
+            result => result,
+        };
+        // Some old servers and firewalls drop anything with an OPT record,
+        // so a timeout may only mean that; resolvers such as unbound then
+        // try once more without it, if there is time left for that. Padded
+        // queries never go without it, as padding is there for privacy.
+        match result {
+            Err(DnsError::Io(e)) if sent_edns && is_timeout(&e) && !self.pads() && self.read_timeout(None).is_ok() => {
+                // Another query of the same batch may have found this out.
+                let known = self.edns_incapable;
+                self.edns_incapable = true;
+                let result = self.exchange(hostname.to_string(), query, record, None);
+                if result.is_err() {
+                    self.edns_incapable = known;
+                } else if !known {
+                    warn!("{} only answers queries without EDNS", self.server);
+                    for option in self.edns_options_dropped() {
+                        warn!("queries to {} now go without {}", self.server, option);
+                    }
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// Sends one query and waits for its answer.
+    fn exchange(
+        &mut self,
+        hostname: String,
+        query: DnsQueryType,
+        record: DnsRecordType,
+        edns_version: Option<u8>,
+    ) -> Result<DnsMessage, DnsError> {
+        let (dns_message, bytes) = self.build_query(hostname, query, record, edns_version)?;
+        let trans_id = dns_message.transaction_id();
+        trace!("sending {} bytes with id {} to {}", bytes.len(), trans_id, self.server);
+        let sent_at = SystemTime::now();
+        let started = Instant::now();
+        // Earlier retries may have left a longer timeout behind.
+        self.udp_sock.set_read_timeout(self.read_timeout(self.timeout)?)?;
+        self.udp_sock.send(&bytes).map_err(|e| self.io_error(e))?;
+
+        let limit = response_limit(&dns_message);
+        let mut buf = vec![0u8; limit + 1];
+        let mut attempt = 0;
+        loop {
+            let len = match (self.udp_sock.recv(&mut buf), self.timeout) {
+                (Ok(len), _) => len,
+                (Err(e), Some(base)) if is_timeout(&e) && attempt + 1 < self.retry.attempts => {
+                    attempt += 1;
+                    let timeout = self.read_timeout(Some(self.retry.timeout(base, attempt)))?;
+                    debug!("no answer from {}, sending attempt {} with a {:?} timeout", self.server, attempt + 1, timeout);
+                    self.udp_sock.set_read_timeout(timeout)?;
+                    self.udp_sock.send(&bytes).map_err(|e| self.io_error(e))?;
+                    continue;
+                }
+                (Err(e), _) => return Err(self.io_error(e)),
+            };
+            trace!("received {} bytes from {}", len, self.server);
+            // Anything with a different id is a stale or spoofed answer,
+            // and is dropped unparsed, so that a malformed one can't end
+            // the query.
+            if len < 2 || buf[..2] != trans_id.to_be_bytes() {
+                debug!("ignoring a response that is not for id {}", trans_id);
+                continue;
+            }
+            self.record(sent_at, &bytes, &buf[..len]);
+            if len > limit {
+                return Err(DnsError::OversizedResponse { limit });
+            }
+            let response = DnsMessage::from_bytes(&buf[..len])?;
+            let elapsed = started.elapsed();
+            self.last_stats = Some(QueryStats {
+                server: self.server,
+                sent_at,
+                elapsed,
+                received: len,
+                attempts: vec![Attempt {
+                    server: self.server,
+                    error: None,
+                    elapsed,
+                }],
+            });
+            self.last_response = buf[..len].to_vec();
+            self.last_request = bytes;
+            let response = check_question(&dns_message, response)?;
+            self.store_server_cookie(&response);
+            check_version(&dns_message, &response)?;
+            check_cookie(&response)?;
+            return self.check_authoritative(response);
+        }
+    }
+
+    /// Sends queries for two types of `hostname` back to back, before
+    /// waiting for either answer, the way glibc sends A and AAAA lookups
+    /// unless `single-request` is set. The answers are returned in the
+    /// order of `records`, however they arrive, and if either query
+    /// fails, so does the pair.
+    pub fn query_pair(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        records: [DnsRecordType; 2],
+    ) -> Result<[DnsMessage; 2], DnsError> {
+        let mut answers = self.query_many(hostname, query, &records)?.into_iter();
+        Ok([answers.next().unwrap()?, answers.next().unwrap()?])
+    }
+
+    /// Like `query_pair`, for any number of types, with each type's answer
+    /// or error in the order of `records`. Unanswered queries are sent
+    /// again under the retry policy, and failed ones get the same retries
+    /// as in `query`, one at a time. It fails as a whole only when the
+    /// queries cannot be sent or none of them gets an answer.
+    pub fn query_many(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        records: &[DnsRecordType],
+    ) -> Result<Vec<Result<DnsMessage, DnsError>>, DnsError> {
+        let version = self.options.edns_version;
+        let requests = records
+            .iter()
+            .map(|&record| self.build_query(hostname.to_string(), query, record, version))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sent_edns = self.sends_edns();
+        self.udp_sock.set_read_timeout(self.read_timeout(self.timeout)?)?;
+        let sent_at = SystemTime::now();
+        let started = Instant::now();
+        for (dns_message, bytes) in &requests {
+            trace!("sending {} bytes with id {} to {}", bytes.len(), dns_message.transaction_id(), self.server);
+            self.udp_sock.send(bytes).map_err(|e| self.io_error(e))?;
+        }
+
+        let limit = requests.iter().map(|(dns_message, _)| response_limit(dns_message)).max().unwrap_or(0);
+        let mut buf = vec![0u8; limit + 1];
+        let mut answers: Vec<Option<Result<DnsMessage, DnsError>>> = records.iter().map(|_| None).collect();
+        let mut attempt = 0;
+        while answers.iter().any(Option::is_none) {
+            let len = match self.udp_sock.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    let retry = match self.timeout {
+                        Some(base) if is_timeout(&e) && attempt + 1 < self.retry.attempts => {
+                            self.read_timeout(Some(self.retry.timeout(base, attempt + 1))).ok()
+                        }
+                        _ => None,
+                    };
+                    let unanswered: Vec<usize> = (0..requests.len()).filter(|&i| answers[i].is_none()).collect();
+                    if let Some(timeout) = retry {
+                        attempt += 1;
+                        let server = self.server;
+                        debug!(
+                            "{} queries to {} unanswered, sending attempt {}",
+                            unanswered.len(),
+                            server,
+                            attempt + 1
+                        );
+                        self.udp_sock.set_read_timeout(timeout)?;
+                        for &i in &unanswered {
+                            self.udp_sock.send(&requests[i].1).map_err(|e| self.io_error(e))?;
+                        }
+                        continue;
+                    }
+                    let (count, server) = (unanswered.len(), self.server);
+                    debug!("{} of {} queries to {} went unanswered: {}", count, records.len(), server, e);
+                    for i in unanswered {
+                        answers[i] = Some(Err(self.io_error(io::Error::new(e.kind(), e.to_string()))));
+                    }
+                    break;
+                }
+            };
+            trace!("received {} bytes from {}", len, self.server);
+            let has_id = |i: usize| len >= 2 && buf[..2] == requests[i].0.transaction_id().to_be_bytes();
+            let waiting: Vec<usize> = (0..requests.len()).filter(|&i| answers[i].is_none() && has_id(i)).collect();
+            if waiting.is_empty() {
+                debug!("ignoring a response that answers none of the queries");
+                continue;
+            }
+            let response = if len > limit {
+                Err(DnsError::OversizedResponse { limit })
+            } else {
+                DnsMessage::from_bytes(&buf[..len])
+            };
+            // A pinned id is shared by every query, so the question picks
+            // out which of them this answers.
+            let index = waiting
+                .iter()
+                .copied()
+                .find(|&i| match &response {
+                    Ok(response) => response.question().is_some_and(|q| q.matches(requests[i].0.question().unwrap())),
+                    Err(_) => false,
+                })
+                .unwrap_or(waiting[0]);
+            self.record(sent_at, &requests[index].1, &buf[..len]);
+            let answer = response.and_then(|response| self.check_answer(&requests[index].0, response));
+            if answer.is_ok() {
+                let elapsed = started.elapsed();
+                self.last_stats = Some(QueryStats {
+                    server: self.server,
+                    sent_at,
+                    elapsed,
+                    received: len,
+                    attempts: vec![Attempt {
+                        server: self.server,
+                        error: None,
+                        elapsed,
+                    }],
+                });
+                self.last_response = buf[..len].to_vec();
+                self.last_request = requests[index].1.clone();
+            }
+            answers[index] = Some(answer);
+        }
+
+        let timed_out = |answer: &Result<DnsMessage, DnsError>| matches!(answer, Err(DnsError::Io(e)) if is_timeout(e));
+        let mut results = Vec::with_capacity(records.len());
+        let mut unreachable = false;
+        for (&record, answer) in records.iter().zip(answers) {
+            let answer = answer.unwrap();
+            // Once a query has gone unanswered without EDNS too, the rest
+            // would only wait as long again.
+            if unreachable && timed_out(&answer) {
+                results.push(answer);
+                continue;
+            }
+            let was_timeout = timed_out(&answer);
+            let result = self.recover(hostname, query, record, sent_edns, answer);
+            unreachable |= was_timeout && timed_out(&result);
+            results.push(result);
+        }
+        if results.iter().all(Result::is_err) {
+            return Err(results.into_iter().next().unwrap().unwrap_err());
+        }
+        Ok(results)
+    }
+
+    /// Applies the same checks to `response` to `request` as `exchange`
+    /// does, for `query_many`.
+    fn check_answer(&mut self, request: &DnsMessage, response: DnsMessage) -> Result<DnsMessage, DnsError> {
+        let response = check_question(request, response)?;
+        self.store_server_cookie(&response);
+        check_version(request, &response)?;
+        check_cookie(&response)?;
+        self.check_authoritative(response)
+    }
+
+    /// Sends `request` exactly as given and parses what comes back, for
+    /// reproducing what a server does with a particular packet. None of
+    /// the query options apply, and the answer is only matched to the
+    /// request by id, so it need not be a well formed query.
+    pub fn query_raw(&mut self, request: &[u8]) -> Result<DnsMessage, DnsError> {
+        trace!("sending {} raw bytes to {}", request.len(), self.server);
+        let sent_at = SystemTime::now();
+        let started = Instant::now();
+        self.udp_sock.set_read_timeout(self.read_timeout(self.timeout)?)?;
+        self.udp_sock.send(request).map_err(|e| self.io_error(e))?;
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let len = self.udp_sock.recv(&mut buf).map_err(|e| self.io_error(e))?;
+            trace!("received {} bytes from {}", len, self.server);
+            if request.len() >= 2 && len >= 2 && buf[..2] != request[..2] {
+                debug!("ignoring a response with another id than the raw query");
+                continue;
+            }
+            self.record(sent_at, request, &buf[..len]);
+            let response = DnsMessage::from_bytes(&buf[..len])?;
+            let elapsed = started.elapsed();
+            self.last_stats = Some(QueryStats {
+                server: self.server,
+                sent_at,
+                elapsed,
+                received: len,
+                attempts: vec![Attempt {
+                    server: self.server,
+                    error: None,
+                    elapsed,
+                }],
+            });
+            self.last_response = buf[..len].to_vec();
+            self.last_request = request.to_vec();
+            return Ok(response);
+        }
+    }
+
+    /// Replaces the socket with a newly bound one, on a new source port,
+    /// as glibc's `single-request-reopen` does between the A and AAAA
+    /// lookups for middleboxes that mix up two queries from one port.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let udp_sock = BindOptions::for_server(self.server).bind()?;
+        udp_sock.connect(self.server)?;
+        udp_sock.set_read_timeout(self.timeout)?;
+        self.udp_sock = udp_sock;
+        Ok(())
+    }
+
+    /// Wraps a socket error, telling a refused port apart. As the socket
+    /// is connected, an ICMP port unreachable in reply to a query fails
+    /// the next send or recv at once, rather than leaving it to time out.
+    fn io_error(&self, e: io::Error) -> DnsError {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => DnsError::Unreachable(self.server),
+            _ => DnsError::Io(e),
+        }
+    }
+
+    /// Applies `QueryOptions::non_authoritative` to a response.
+    fn check_authoritative(&self, response: DnsMessage) -> Result<DnsMessage, DnsError> {
+        if response.flags().aa {
+            return Ok(response);
+        }
+        match self.options.non_authoritative {
+            NonAuthoritative::Accept => Ok(response),
+            NonAuthoritative::Warn => {
+                warn!("{} answered non-authoritatively", self.server);
+                Ok(response)
+            }
+            NonAuthoritative::Reject => Err(DnsError::NotAuthoritative(self.server)),
+        }
+    }
+
+    /// Hands out the id for the next query. Ids start at a random value
+    /// and wrap around after 65535, so an id is not reused until 65535
+    /// later queries have gone out. A pinned id is handed out every time,
+    /// even to queries that `query_many` has in flight together.
+    fn next_transaction_id(&mut self) -> u16 {
+        if let Some(trans_id) = self.fixed_trans_id {
+            return trans_id;
+        }
+        self.trans_id = self.trans_id.wrapping_add(1);
+        self.trans_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_server, name, query_id, question, soa_rdata, Packet};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_it_counts_parsed_sections() {
+        let bytes = Packet::response(7)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .answer("example.com", 1, 60, vec![192, 0, 2, 2])
+            .authority("example.com", 2, 60, name("ns.example.com"))
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(message.question_count(), 1);
+        assert_eq!(message.answer_count(), 2);
+        assert_eq!(message.authority_count(), 1);
+        assert_eq!(message.additional_count(), 0);
+    }
+
+    #[test]
+    fn test_it_reads_back_the_question() {
+        let bytes = Packet::response(7).question("www.example.com", 28).build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        let question = message.question().unwrap();
+        assert_eq!(question.name(), "www.example.com");
+        assert_eq!(question.record_type(), Some(DnsRecordType::AAAA));
+        assert_eq!(question.class(), Some(DnsQueryClass::InternetClass));
+
+        let bytes = Packet::response(7).build();
+        assert!(DnsMessage::from_bytes(&bytes).unwrap().question().is_none());
+    }
+
+    #[test]
+    fn test_it_accepts_an_echoed_question_in_any_case() {
+        let server = mock_server(|query| {
+            Some(Packet::response(query_id(query)).question("ExAmPlE.CoM", 1).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        assert!(socket
+            .query("example.com.".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_it_rejects_a_mismatched_question() {
+        let server = mock_server(|query| {
+            let (_, qtype) = question(query);
+            let name = if qtype == 1 { "evil.example" } else { "example.com" };
+            Some(Packet::response(query_id(query)).question(name, 1).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let err = socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap_err();
+        assert!(matches!(err, DnsError::QuestionMismatch));
+        // The right name with the wrong type is no better.
+        let err = socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::MX)
+            .unwrap_err();
+        assert!(matches!(err, DnsError::QuestionMismatch));
+    }
+
+    /// Builds a response with one answer, then overwrites its rdlength.
+    fn with_rdlength(rtype: u16, rdata: Vec<u8>, rdlength: u16) -> Vec<u8> {
+        let rdata_len = rdata.len();
+        let mut bytes = Packet::response(7)
+            .question("example.com", rtype)
+            .answer("example.com", rtype, 60, rdata)
+            .build();
+        let at = bytes.len() - rdata_len - 2;
+        bytes[at..at + 2].copy_from_slice(&rdlength.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_it_rejects_rdlength_shorter_than_rdata() {
+        let mut mx = vec![0, 10];
+        mx.extend(name("mail.example.com"));
+        let bytes = with_rdlength(15, mx.clone(), 3);
+        let rdata_at = bytes.len() - mx.len();
+        // The exchange's first label is cut off by the rdlength.
+        match DnsMessage::from_bytes(&bytes) {
+            Err(DnsError::Parse { offset, reason }) => {
+                assert_eq!(offset, rdata_at + 3);
+                assert_eq!(reason, "rdata runs past rdlength");
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_it_rejects_rdlength_longer_than_rdata() {
+        let mut ns = name("ns.example.com");
+        ns.extend_from_slice(&[0, 0]);
+        let len = ns.len() as u16;
+        let bytes = with_rdlength(2, ns, len);
+        assert!(matches!(DnsMessage::from_bytes(&bytes), Err(DnsError::Parse { .. })));
+
+        let ns = name("ns.example.com");
+        let len = ns.len() as u16;
+        assert!(DnsMessage::from_bytes(&with_rdlength(2, ns, len)).is_ok());
+    }
+
+    #[test]
+    fn test_it_sorts_mx_by_preference_and_shuffles_ties() {
+        let mx = |preference: u16, exchange: &str| {
+            let mut rdata = preference.to_be_bytes().to_vec();
+            rdata.extend(name(exchange));
+            ResourceRecord::new("example.com".to_string(), 15, 1, 300, rdata)
+        };
+        let records = [
+            mx(20, "b.example.com"),
+            mx(10, "primary.example.com"),
+            mx(20, "a.example.com"),
+            ResourceRecord::new("example.com".to_string(), 1, 1, 300, vec![192, 0, 2, 1]),
+        ];
+
+        let mut orders = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let sorted = sorted_mx(&records);
+            assert_eq!(sorted.len(), 3);
+            assert_eq!(sorted[0].exchange, "primary.example.com");
+            orders.insert(sorted[1].exchange.clone());
+        }
+        assert_eq!(orders.len(), 2);
+    }
+
+    #[test]
+    fn test_it_rejects_messages_too_large_for_the_transport() {
+        let mut message = DnsMessage::new(1);
+        for i in 0..40 {
+            let name = format!("host{}.example.com", i);
+            message.add_additional(ResourceRecord::new(name, 16, 1, 300, vec![20; 21]));
+        }
+        let size = message.to_bytes().len();
+        assert!(matches!(
+            message.to_bytes_checked(MAX_UDP_SIZE),
+            Err(DnsError::MessageTooLarge { limit: MAX_UDP_SIZE, size: s }) if s == size
+        ));
+        assert_eq!(message.to_bytes_checked(MAX_TCP_SIZE).unwrap().len(), size);
+    }
+
+    #[test]
+    fn test_it_sets_and_reads_the_aa_bit() {
+        let mut message = DnsMessage::new(1);
+        assert!(!message.flags().aa);
+        message.set_authoritative(true);
+        let bytes = message.to_bytes();
+        assert_eq!(&bytes[2..4], &[0x04, 0x00]);
+        assert!(DnsMessage::from_bytes(&bytes).unwrap().flags().aa);
+        message.set_authoritative(false);
+        assert!(!message.flags().aa);
+
+        let flags = DnsMessage::from_bytes(&Packet::response(1).build()).unwrap().flags();
+        assert!(flags.qr && flags.rd && flags.ra && !flags.aa && !flags.tc);
+    }
+
+    #[test]
+    fn test_it_sends_the_cd_bit_when_asked() {
+        let (tx, rx) = mpsc::channel();
+        let server = mock_server(move |query| {
+            tx.send(u16::from_be_bytes([query[2], query[3]])).unwrap();
+            let (qname, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_options(QueryOptions {
+            checking_disabled: true,
+            ..QueryOptions::default()
+        });
+        socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        assert_eq!(rx.recv().unwrap(), 0x0110);
+    }
+
+    #[test]
+    fn test_it_reads_the_ad_bit() {
+        let bytes = Packet::response(1).flags(0x81a0).question("example.com", 1).build();
+        let flags = DnsMessage::from_bytes(&bytes).unwrap().flags();
+        assert!(flags.ad);
+        assert!(!flags.cd);
+        assert_eq!(flags.to_string(), "qr rd ra ad");
+    }
+
+    #[test]
+    fn test_it_backs_off_between_attempts() {
+        let base = Duration::from_millis(100);
+        let mut retry = RetryPolicy {
+            attempts: 4,
+            backoff: Backoff::Exponential,
+            max_timeout: Duration::from_millis(500),
+        };
+        let timeouts: Vec<_> = (0..4).map(|attempt| retry.timeout(base, attempt).as_millis()).collect();
+        assert_eq!(timeouts, vec![100, 200, 400, 500]);
+        retry.backoff = Backoff::Linear;
+        let timeouts: Vec<_> = (0..4).map(|attempt| retry.timeout(base, attempt).as_millis()).collect();
+        assert_eq!(timeouts, vec![100, 200, 300, 400]);
+
+        // A server that never answers sees each attempt arrive after the
+        // last one's timeout.
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |_| {
+            sent.send(Instant::now()).unwrap();
+            None
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(base).unwrap();
+        socket.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            backoff: Backoff::Exponential,
+            ..RetryPolicy::default()
+        });
+        let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::Io(ref e)) if is_timeout(e)));
+        let arrivals: Vec<Instant> = received.try_iter().collect();
+        assert_eq!(arrivals.len(), 3);
+        let (first, second) = (arrivals[1] - arrivals[0], arrivals[2] - arrivals[1]);
+        assert!(first >= base && first < base * 2, "{:?}", first);
+        assert!(second >= base * 2, "{:?}", second);
+    }
+
+    #[test]
+    fn test_it_encodes_a_delete_rrset_update() {
+        let mut update = DnsMessage::update(0x0101, "example.com".to_string()).unwrap();
+        update.update_delete_rrset("www.example.com".to_string(), DnsRecordType::A);
+        let bytes = update.to_bytes();
+
+        // Opcode 5, one zone, no prerequisites, one update.
+        assert_eq!(&bytes[..12], &[1, 1, 0x28, 0, 0, 1, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(&bytes[12..29], &[&name("example.com")[..], &[0, 6, 0, 1]].concat()[..]);
+        // The name compresses to "www" and a pointer to the zone; then
+        // type A, class ANY, TTL 0 and no rdata.
+        assert_eq!(&bytes[29..], &[3, b'w', b'w', b'w', 0xc0, 12, 0, 1, 0, 255, 0, 0, 0, 0, 0, 0]);
+
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.flags().opcode, OPCODE_UPDATE);
+        assert_eq!(class_name(parsed.authority()[0].class()), "ANY");
+    }
+
+    #[test]
+    fn test_it_encodes_the_client_subnet() {
+        let subnet: ClientSubnet = "192.0.2.77/20".parse().unwrap();
+        assert_eq!(subnet.address, "192.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(subnet.to_option_data(), vec![0, 1, 20, 0, 192, 0, 0]);
+        assert_eq!(ClientSubnet::from_option_data(&subnet.to_option_data()).unwrap(), subnet);
+        let subnet: ClientSubnet = "2001:db8::1".parse().unwrap();
+        assert_eq!(subnet.source_prefix, 128);
+        assert!("192.0.2.0/33".parse::<ClientSubnet>().is_err());
+    }
+
+    #[test]
+    fn test_it_reads_the_advertised_udp_size() {
+        let bytes = Packet::response(3).question("example.com", 1).opt(1232, 0, vec![]).build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(response.edns_udp_size(), Some(1232));
+        let bytes = Packet::response(3).question("example.com", 1).build();
+        assert_eq!(DnsMessage::from_bytes(&bytes).unwrap().edns_udp_size(), None);
+    }
+
+    #[test]
+    fn test_it_reports_badvers() {
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |query| {
+            let version = DnsMessage::from_bytes(query).unwrap().edns().map_or(0, |edns| edns.version);
+            sent.send(version).unwrap();
+            let (qname, qtype) = question(query);
+            let mut response = Packet::response(query_id(query)).question(&qname, qtype);
+            if version > 0 {
+                // BADVERS is 16: 1 in the upper bits of the OPT TTL, and
+                // NOERROR in the header.
+                response = response.opt(1232, 0x0100_0000, vec![]);
+            }
+            Some(response.build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let mut options = QueryOptions {
+            edns_version: Some(1),
+            ..QueryOptions::default()
+        };
+        socket.set_options(options);
+        let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::BadVersion { requested: 1, supported: 0 })));
+        assert_eq!(socket.last_response()[3] & 0x0f, 0);
+        assert_eq!(received.recv().unwrap(), 1);
+
+        options.edns_negotiation = true;
+        socket.set_options(options);
+        let response = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert_eq!(response.unwrap().extended_rcode(), 0);
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_it_retries_with_the_server_cookie_after_badcookie() {
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |query| {
+            let edns = DnsMessage::from_bytes(query).unwrap().edns().unwrap();
+            let cookie = edns.option(EDNS_COOKIE).unwrap().to_vec();
+            sent.send(cookie.clone()).unwrap();
+            let (qname, qtype) = question(query);
+            let mut response = Packet::response(query_id(query)).question(&qname, qtype);
+            let server_cookie = [&cookie[..8], b"servercookie"].concat();
+            let mut option = vec![0, EDNS_COOKIE as u8, 0, server_cookie.len() as u8];
+            option.extend_from_slice(&server_cookie);
+            if cookie.len() == 8 {
+                // BADCOOKIE is 23: 1 in the upper bits of the OPT TTL,
+                // and 7 (YXRRSET) in the header.
+                response = response.rcode(7).opt(1232, 0x0100_0000, option);
+            } else {
+                response = response.answer(&qname, 1, 60, vec![192, 0, 2, 1]).opt(1232, 0, option);
+            }
+            Some(response.build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_options(QueryOptions {
+            cookie: true,
+            ..QueryOptions::default()
+        });
+        let response = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert_eq!(response.unwrap().answers().len(), 1);
+        assert_eq!(socket.server_cookie(), b"servercookie");
+
+        let cookies: Vec<Vec<u8>> = received.try_iter().collect();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0], socket.client_cookie);
+        assert_eq!(cookies[1], [&socket.client_cookie[..], b"servercookie"].concat());
+    }
+
+    #[test]
+    fn test_it_pads_to_the_block_size() {
+        for hostname in &["a.io", "example.com", "a-much-longer-name.subdomain.example.com"] {
+            for block in &[128, 468] {
+                let mut message = DnsMessage::new(1);
+                message
+                    .set_query(hostname.to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+                    .unwrap();
+                message.pad_to(*block);
+                message.pad_to(*block);
+                let bytes = message.to_bytes();
+                assert_eq!(bytes.len() % block, 0);
+
+                let edns = DnsMessage::from_bytes(&bytes).unwrap().edns().unwrap();
+                assert_eq!(edns.udp_size, 1232);
+                assert_eq!(edns.options.len(), 1);
+                assert_eq!(edns.options[0].0, EDNS_PADDING);
+            }
+        }
+    }
+
+    #[test]
+    fn test_it_applies_the_non_authoritative_setting() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let mut query = |non_authoritative| {
+            socket.set_options(QueryOptions {
+                non_authoritative,
+                ..QueryOptions::default()
+            });
+            socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+        };
+        assert!(query(NonAuthoritative::Accept).is_ok());
+        assert!(query(NonAuthoritative::Warn).is_ok());
+        assert!(matches!(query(NonAuthoritative::Reject), Err(DnsError::NotAuthoritative(s)) if s == server));
+    }
+
+    #[test]
+    fn test_it_parses_captured_packets() {
+        // `dig google.com`, and its answer, as captured off the wire.
+        let query = [
+            0xab, 0xcd, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, b'g', b'o', b'o', b'g',
+            b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let response = [
+            0xab, 0xcd, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x06, b'g', b'o', b'o', b'g',
+            b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01,
+            0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 0x8e, 0xfa, 0x4c, 0xce,
+        ];
+
+        let query = parse_message(&query).unwrap();
+        assert_eq!(query.transaction_id(), 0xabcd);
+        assert!(!query.flags().qr && query.flags().rd && query.flags().ad);
+        assert_eq!(query.question().unwrap().name(), "google.com");
+        assert_eq!(query.edns().unwrap().udp_size, 1232);
+
+        let response = parse_message(&response).unwrap();
+        assert!(response.flags().qr);
+        assert_eq!(response.answers()[0].name(), "google.com");
+        assert_eq!(response.answers()[0].ttl(), 300);
+        assert_eq!(response.answers()[0].ip_address(), Some("142.250.76.206".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_it_parses_questions_of_unknown_types_and_classes() {
+        // An HTTPS (65) query, and one in an unassigned class.
+        let bytes = Packet::response(1)
+            .question("example.com", 65)
+            .question_in_class("example.com", 1, 42)
+            .build();
+        let message = parse_message(&bytes).unwrap();
+        let questions = message.questions();
+        assert_eq!((questions[0].qtype(), questions[0].record_type()), (65, None));
+        assert_eq!((questions[1].qclass(), questions[1].class()), (42, None));
+        assert_eq!(parse_message(&encode_message(&message).unwrap()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_it_round_trips_encoded_messages() {
+        let mut message = DnsMessage::new(0x4242);
+        message
+            .set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::MX)
+            .unwrap();
+        let mut mx = vec![0, 10];
+        mx.extend(name("mail.example.com"));
+        message.add_answer(ResourceRecord::new("example.com".to_string(), 15, 1, 300, mx));
+        message.add_authority(ResourceRecord::new("example.com".to_string(), 2, 1, 300, name("ns.example.com")));
+        message.add_additional(ResourceRecord::new("ns.example.com".to_string(), 1, 1, 300, vec![192, 0, 2, 53]));
+        message.set_edns(Edns::default());
+
+        let bytes = encode_message(&message).unwrap();
+        assert_eq!(parse_message(&bytes).unwrap(), message);
+
+        let label = "a".repeat(64);
+        message.add_answer(ResourceRecord::new(format!("{}.example.com", label), 1, 1, 300, vec![192, 0, 2, 1]));
+        assert!(matches!(encode_message(&message), Err(DnsError::InvalidName(_))));
+    }
+
+    #[test]
+    fn test_it_binds_the_requested_source_port() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let socket = DnsSocket::with_source_port("127.0.0.1:53", Some(port)).unwrap();
+        assert_eq!(socket.udp_sock.local_addr().unwrap().port(), port);
+        let socket = DnsSocket::with_source_port("127.0.0.1:53", None).unwrap();
+        assert_ne!(socket.udp_sock.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn test_it_sends_custom_edns_flags() {
+        let mut message = DnsMessage::new(1);
+        message.set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        message.set_edns_flags(EDNS_DO | 0x0001);
+        let bytes = message.to_bytes();
+        // The OPT record closes the message: its TTL, with the flags in
+        // the low half, then an empty rdlength.
+        assert_eq!(&bytes[bytes.len() - 6..], &[0, 0, 0x80, 0x01, 0, 0]);
+        let edns = DnsMessage::from_bytes(&bytes).unwrap().edns().unwrap();
+        assert_eq!(edns.flags, 0x8001);
+        assert!(edns.dnssec_ok());
+    }
+
+    #[test]
+    fn test_it_reports_compression_savings() {
+        let bytes = Packet::response(1)
+            .question("www.example.com", 5)
+            .answer("www.example.com", 5, 60, name("web.example.com"))
+            .answer("web.example.com", 1, 60, vec![192, 0, 2, 1])
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        let report = message.size_report();
+        assert_eq!(report.size, message.to_bytes().len());
+        assert_eq!(report.sections.iter().sum::<usize>(), report.size);
+        assert_eq!(report.sections[0], 12);
+        assert_eq!(report.sections[1], 21);
+        // The packet builder never compresses, so it made the long form.
+        assert_eq!(report.uncompressed, bytes.len());
+        assert!(report.saved() > 0);
+        assert_eq!(report.sections[4], 0);
+    }
+
+    #[test]
+    fn test_it_finds_the_minimum_ttl() {
+        let bytes = Packet::response(1)
+            .question("www.example.com", 1)
+            .answer("www.example.com", 5, 3600, name("example.com"))
+            .answer("example.com", 1, 120, vec![192, 0, 2, 1])
+            .answer("example.com", 1, 300, vec![192, 0, 2, 2])
+            .authority("example.com", 2, 60, name("ns.example.com"))
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(message.min_ttl(), Some(120));
+        assert_eq!(DnsMessage::new(1).min_ttl(), None);
+    }
+
+    #[test]
+    fn test_it_recognizes_nodata() {
+        let bytes = Packet::response(1)
+            .question("example.com", 28)
+            .authority("example.com", 6, 900, soa_rdata("ns.example.com", 1))
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(message.is_nodata());
+        assert!(!message.is_nxdomain());
+        // soa_rdata uses a minimum of 300, below the record's TTL.
+        assert_eq!(message.negative_ttl(), Some(300));
+
+        let referral = Packet::response(1)
+            .question("example.com", 28)
+            .authority("example.com", 2, 900, name("ns.example.com"))
+            .build();
+        let message = DnsMessage::from_bytes(&referral).unwrap();
+        assert!(!message.is_nodata());
+        assert_eq!(message.negative_ttl(), None);
+    }
+
+    #[test]
+    fn test_it_parses_rdata_by_type() {
+        let mut mx = vec![0, 10];
+        mx.extend(name("mail.example.com"));
+        let mut srv = vec![0, 1, 0, 5, 0x13, 0xc4];
+        srv.extend(name("sip.example.com"));
+        let bytes = Packet::response(1)
+            .question("example.com", 255)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .answer("example.com", 28, 60, [0x20, 0x01, 0x0d, 0xb8].iter().chain(&[0; 12]).copied().collect())
+            .answer("example.com", 2, 60, name("ns.example.com"))
+            .answer("www.example.com", 5, 60, name("example.com"))
+            .answer("example.com", 6, 60, soa_rdata("ns.example.com", 7))
+            .answer("1.2.0.192.in-addr.arpa", 12, 60, name("example.com"))
+            .answer("example.com", 15, 60, mx)
+            .answer("example.com", 16, 60, b"\x05hello\x05world".to_vec())
+            .answer("_sip._udp.example.com", 33, 60, srv)
+            .answer("example.com", 65280, 60, vec![1, 2, 3])
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let rdata: Vec<&RecordData> = response.answers().iter().map(|r| r.rdata()).collect();
+
+        assert_eq!(rdata[0], &RecordData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(rdata[1], &RecordData::Aaaa("2001:db8::".parse().unwrap()));
+        assert_eq!(rdata[2], &RecordData::Ns("ns.example.com".to_string()));
+        assert_eq!(rdata[3], &RecordData::Cname("example.com".to_string()));
+        assert!(matches!(rdata[4], RecordData::Soa(soa) if soa.serial == 7));
+        assert_eq!(rdata[5], &RecordData::Ptr("example.com".to_string()));
+        assert!(matches!(rdata[6], RecordData::Mx(mx) if mx.exchange == "mail.example.com"));
+        assert_eq!(rdata[7], &RecordData::Txt(vec!["hello".to_string(), "world".to_string()]));
+        assert!(matches!(rdata[8], RecordData::Srv(srv) if srv.port == 5060 && srv.target == "sip.example.com"));
+        assert_eq!(rdata[9], &RecordData::Unknown { rtype: 65280, data: vec![1, 2, 3] });
+    }
+
+    #[test]
+    fn test_it_serializes_every_question() {
+        let mut message = DnsMessage::new(1);
+        message
+            .set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        let second = QueryZone::new("example.org".to_string(), DnsRecordType::MX, DnsQueryClass::InternetClass);
+        message.add_question(second.unwrap());
+
+        let parsed = DnsMessage::from_bytes(&message.to_bytes()).unwrap();
+        assert_eq!(parsed.question_count(), 2);
+        let names: Vec<&str> = parsed.questions().iter().map(|q| q.name()).collect();
+        assert_eq!(names, ["example.com", "example.org"]);
+        assert_eq!(parsed.questions()[1].record_type(), Some(DnsRecordType::MX));
+    }
+
+    #[test]
+    fn test_it_compresses_shared_suffixes() {
+        let mut message = DnsMessage::new(1);
+        message.add_answer(ResourceRecord::new("a.example.com".to_string(), 1, 1, 60, vec![192, 0, 2, 1]));
+        message.add_answer(ResourceRecord::new("b.Example.com".to_string(), 2, 1, 60, name("ns.example.com")));
+        let bytes = message.to_bytes();
+
+        // The first name is written in full right after the header.
+        assert_eq!(&bytes[12..27], b"\x01a\x07example\x03com\x00");
+        // The second name points back to `example.com` at offset 14, and
+        // so does the NS target in its rdata.
+        let second = 27 + 10 + 4;
+        assert_eq!(&bytes[second..second + 4], &[1, b'b', 0xc0, 14]);
+        assert_eq!(&bytes[second + 14..], &[2, b'n', b's', 0xc0, 14]);
+
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        // Names compare case-insensitively, so the pointer may change case.
+        assert_eq!(parsed.answers()[1].name(), "b.example.com");
+        assert_eq!(parsed.answers()[1].target_name().unwrap(), "ns.example.com");
+    }
+
+    #[test]
+    fn test_it_survives_transaction_id_wraparound() {
+        let server = mock_server(|query| {
+            Some(Packet::response(query_id(query)).question("example.com", 1).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let first_id = socket.trans_id.wrapping_add(1);
+        let mut last_id = socket.trans_id;
+        for _ in 0..70000 {
+            let response = socket
+                .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+                .unwrap();
+            assert_eq!(response.transaction_id(), last_id.wrapping_add(1));
+            last_id = response.transaction_id();
+        }
+        assert_eq!(last_id, first_id.wrapping_add((70000 - 1) as u16));
+    }
+
+    #[test]
+    fn test_it_uses_a_fixed_transaction_id() {
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |query| {
+            sent.send(query.to_vec()).unwrap();
+            Some(Packet::response(query_id(query)).question("example.com", 1).build())
+        });
+        let mut socket = DnsSocket::with_transaction_id(server, 0xbeef).unwrap();
+        for _ in 0..2 {
+            let response = socket
+                .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+                .unwrap();
+            assert_eq!(response.transaction_id(), 0xbeef);
+            assert_eq!(&received.recv().unwrap()[..2], &[0xbe, 0xef]);
+        }
+    }
+
+    #[test]
+    fn test_it_pairs_queries_that_share_a_pinned_id() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut queries = Vec::new();
+            let mut buf = [0u8; 512];
+            for _ in 0..2 {
+                let (len, peer) = server.recv_from(&mut buf).unwrap();
+                queries.push((buf[..len].to_vec(), peer));
+            }
+            // The AAAA answer comes back first.
+            for (query, peer) in queries.iter().rev() {
+                let (qname, qtype) = question(query);
+                let reply = Packet::response(query_id(query)).question(&qname, qtype).build();
+                server.send_to(&reply, peer).unwrap();
+            }
+        });
+        let mut socket = DnsSocket::with_transaction_id(addr, 0xbeef).unwrap();
+        let [a, aaaa] = socket
+            .query_pair("example.com", DnsQueryType::Recursive, [DnsRecordType::A, DnsRecordType::AAAA])
+            .unwrap();
+        assert_eq!(a.question().unwrap().record_type(), Some(DnsRecordType::A));
+        assert_eq!(aaaa.question().unwrap().record_type(), Some(DnsRecordType::AAAA));
+    }
+
+    /// Answers with an empty response echoing the question, for pairs.
+    fn echo(query: &[u8]) -> Option<Vec<u8>> {
+        let (qname, qtype) = question(query);
+        Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+    }
+
+    #[test]
+    fn test_it_resends_unanswered_queries_of_a_pair() {
+        let (sent, received) = mpsc::channel();
+        let aaaa_seen = std::sync::atomic::AtomicBool::new(false);
+        let server = mock_server(move |query| {
+            let qtype = question(query).1;
+            sent.send(qtype).unwrap();
+            // The first AAAA query is lost.
+            if qtype == 28 && !aaaa_seen.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return None;
+            }
+            echo(query)
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(Duration::from_millis(200)).unwrap();
+        socket.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            ..RetryPolicy::default()
+        });
+        socket
+            .query_pair("example.com", DnsQueryType::Recursive, [DnsRecordType::A, DnsRecordType::AAAA])
+            .unwrap();
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), vec![1, 28, 28]);
+    }
+
+    #[test]
+    fn test_it_falls_back_without_edns_for_a_pair() {
+        let server = mock_server(|query| if query[11] != 0 { None } else { echo(query) });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(Duration::from_millis(200)).unwrap();
+        socket.set_options(QueryOptions {
+            edns_version: Some(0),
+            ..QueryOptions::default()
+        });
+        socket
+            .query_pair("example.com", DnsQueryType::Recursive, [DnsRecordType::A, DnsRecordType::AAAA])
+            .unwrap();
+        assert!(socket.edns_incapable());
+    }
+
+    #[test]
+    fn test_it_retries_a_pair_with_the_server_cookie() {
+        let server = mock_server(|query| {
+            let edns = DnsMessage::from_bytes(query).unwrap().edns().unwrap();
+            let cookie = edns.option(EDNS_COOKIE).unwrap().to_vec();
+            let (qname, qtype) = question(query);
+            let server_cookie = [&cookie[..8], b"servercookie"].concat();
+            let mut option = vec![0, EDNS_COOKIE as u8, 0, server_cookie.len() as u8];
+            option.extend_from_slice(&server_cookie);
+            let response = Packet::response(query_id(query)).question(&qname, qtype);
+            // BADCOOKIE until the server cookie comes back.
+            let response = if cookie.len() == 8 {
+                response.rcode(7).opt(1232, 0x0100_0000, option)
+            } else {
+                response.opt(1232, 0, option)
+            };
+            Some(response.build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_options(QueryOptions {
+            cookie: true,
+            ..QueryOptions::default()
+        });
+        let [a, aaaa] = socket
+            .query_pair("example.com", DnsQueryType::Recursive, [DnsRecordType::A, DnsRecordType::AAAA])
+            .unwrap();
+        assert_eq!((a.extended_rcode(), aaaa.extended_rcode()), (0, 0));
+    }
+
+    #[test]
+    fn test_it_ignores_malformed_responses_for_other_ids() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let id = query_id(&buf[..len]);
+            // Garbage under another id arrives before the real answer.
+            server.send_to(&[!buf[0], buf[1], 0xff], peer).unwrap();
+            let reply = Packet::response(id).question("example.com", 1).build();
+            server.send_to(&reply, peer).unwrap();
+        });
+        let mut socket = DnsSocket::new(addr).unwrap();
+        socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_it_shares_ports_when_reusing_addresses() {
+        let mut bind = BindOptions {
+            local_addr: "127.0.0.31:0".parse().unwrap(),
+            reuse_address: true,
+        };
+        let first = bind.bind().unwrap();
+        // Any free port will do, so the test doesn't depend on 5355 being
+        // free of a local LLMNR responder.
+        bind.local_addr = first.local_addr().unwrap();
+        let second = DnsSocket::with_bind_options("127.0.0.1:53", &bind).unwrap();
+        assert_eq!(second.local_addr().unwrap(), bind.local_addr);
+
+        bind.reuse_address = false;
+        assert!(bind.bind().is_err());
+    }
+
+    #[test]
+    fn test_it_binds_to_the_servers_family() {
+        let socket = DnsSocket::new("[::1]:53").unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv6());
+        let socket = DnsSocket::new("127.0.0.1:53").unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv4());
+    }
+
+    #[test]
+    fn test_it_resets_messages_for_reuse() {
+        let bytes = Packet::response(1)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .build();
+        let mut message = DnsMessage::from_bytes(&bytes).unwrap();
+        message.reset(2);
+        assert_eq!(message, DnsMessage::new(2));
+        assert!(message.records.answers.capacity() > 0);
+
+        message.set_query("example.org".to_string(), DnsQueryType::Recursive, DnsRecordType::AAAA).unwrap();
+        let mut fresh = DnsMessage::new(2);
+        fresh.set_query("example.org".to_string(), DnsQueryType::Recursive, DnsRecordType::AAAA).unwrap();
+        assert_eq!(message.to_bytes(), fresh.to_bytes());
+    }
+
+    #[test]
+    fn test_it_queries_over_an_injected_socket() {
+        let server = mock_server(|query| {
+            let (name, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&name, qtype).build())
+        });
+        let udp_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(DnsSocket::from_socket(udp_sock.try_clone().unwrap()).is_err());
+
+        udp_sock.connect(server).unwrap();
+        udp_sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let local = udp_sock.local_addr().unwrap();
+        let mut socket = DnsSocket::from_socket(udp_sock).unwrap();
+        assert_eq!(socket.server(), server);
+        assert_eq!(socket.local_addr().unwrap(), local);
+        let response = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.rcode(), 0);
+    }
+
+    #[test]
+    fn test_it_groups_mixed_any_answers() {
+        let mut mx = vec![0, 10];
+        mx.extend(name("mail.example.com"));
+        let bytes = Packet::response(1)
+            .question("example.com", 255)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .answer("example.com", 15, 60, mx)
+            .answer("example.com", 16, 60, b"\x05hello".to_vec())
+            .answer("example.com", 1, 60, vec![192, 0, 2, 2])
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let groups = response.answers_by_type();
+        let types: Vec<u16> = groups.iter().map(|(rtype, _)| *rtype).collect();
+        assert_eq!(types, vec![1, 15, 16]);
+        assert_eq!(groups[0].1.len(), 2);
+        assert!(matches!(groups[1].1[0].rdata(), RecordData::Mx(mx) if mx.exchange == "mail.example.com"));
+        assert_eq!(groups[2].1[0].rdata(), &RecordData::Txt(vec!["hello".to_string()]));
+        assert!(!response.is_minimal_any());
+
+        let bytes = Packet::response(1)
+            .question("example.com", 255)
+            .answer("example.com", 13, 3789, b"\x07RFC8482\x00".to_vec())
+            .build();
+        assert!(DnsMessage::from_bytes(&bytes).unwrap().is_minimal_any());
+    }
+
+    #[test]
+    fn test_it_displays_soa_fields_in_order() {
+        let soa = Soa {
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 2021041301,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+        assert_eq!(
+            soa.to_string(),
+            "ns1.example.com. hostmaster.example.com. 2021041301 7200 3600 1209600 300"
+        );
+    }
+
+    #[test]
+    fn test_it_detects_responses_larger_than_the_buffer() {
+        let server = mock_server(|query| {
+            let (name, qtype) = question(query);
+            let mut packet = Packet::response(query_id(query)).question(&name, qtype);
+            for i in 0..40 {
+                packet = packet.answer(&name, 1, 60, vec![192, 0, 2, i]);
+            }
+            Some(packet.build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::OversizedResponse { limit: 512 })));
+
+        // Padding adds EDNS, which advertises room for the whole response.
+        socket.set_options(QueryOptions {
+            padding: Some(128),
+            ..QueryOptions::default()
+        });
+        let response = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.answers().len(), 40);
+    }
+
+    #[test]
+    fn test_it_exposes_the_raw_header() {
+        let bytes = Packet::response(0xabcd)
+            .flags(0x8580)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .authority("example.com", 2, 60, name("ns.example.com"))
+            .additional("ns.example.com", 1, 60, vec![192, 0, 2, 53])
+            .additional("ns.example.com", 28, 60, vec![0; 16])
+            .build();
+        let header = DnsMessage::from_bytes(&bytes).unwrap().header();
+        let field = |i: usize| u16::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        assert_eq!(header.id, field(0));
+        assert_eq!(header.flags, field(1));
+        assert_eq!(
+            header.counts,
+            SectionCounts {
+                questions: field(2),
+                answers: field(3),
+                authority: field(4),
+                additional: field(5),
+            }
+        );
+        assert_eq!(header.counts.additional, 2);
+    }
+
+    #[test]
+    fn test_it_decodes_spf_records_as_strings() {
+        let bytes = Packet::response(1)
+            .question("example.com", 99)
+            .answer("example.com", 99, 300, b"\x09v=spf1 mx\x05 -all".to_vec())
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            response.answers()[0].rdata(),
+            &RecordData::Spf(vec!["v=spf1 mx".to_string(), " -all".to_string()])
+        );
+        assert_eq!(DnsRecordType::from_str("SPF").unwrap(), DnsRecordType::SPF);
+    }
+
+    #[test]
+    fn test_it_falls_back_to_queries_without_edns() {
+        let server = mock_server(|query| {
+            // Drop anything with an additional record, i.e. an OPT.
+            if query[11] != 0 {
+                return None;
+            }
+            Some(
+                Packet::response(query_id(query))
+                    .question("example.com", 1)
+                    .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+                    .build(),
+            )
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(Duration::from_millis(200)).unwrap();
+        socket.set_options(QueryOptions {
+            edns_version: Some(0),
+            ..QueryOptions::default()
+        });
+        let response = socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.1".parse().unwrap()));
+        assert!(socket.edns_incapable());
+        assert_eq!(socket.last_request()[11], 0);
+
+        // Later queries go without EDNS straight away.
+        let started = Instant::now();
+        socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        // New options give EDNS another try.
+        socket.set_options(QueryOptions {
+            edns_version: Some(0),
+            ..QueryOptions::default()
+        });
+        assert!(!socket.edns_incapable());
+    }
+
+    #[test]
+    fn test_it_never_drops_padding_to_fall_back_without_edns() {
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |query| {
+            sent.send(DnsMessage::from_bytes(query).unwrap().edns()).unwrap();
+            None
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(Duration::from_millis(100)).unwrap();
+        socket.set_options(QueryOptions {
+            padding: Some(128),
+            ..QueryOptions::default()
+        });
+        for _ in 0..2 {
+            let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+            assert!(result.is_err());
+        }
+        assert!(!socket.edns_incapable());
+        let sent: Vec<Option<Edns>> = received.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|edns| edns.as_ref().and_then(|edns| edns.option(EDNS_PADDING)).is_some()));
+    }
+
+    #[test]
+    fn test_it_falls_back_without_edns_only_before_the_deadline() {
+        let server = mock_server(|_| None);
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_timeout(Duration::from_millis(200)).unwrap();
+        socket.set_options(QueryOptions {
+            edns_version: Some(0),
+            ..QueryOptions::default()
+        });
+        let started = Instant::now();
+        socket.set_deadline(Some(started + Duration::from_millis(300)));
+        let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(result.is_err());
+        // The fallback gets only what is left of the 300ms, not another
+        // 200ms of its own.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(380), "{:?}", elapsed);
+        assert!(!socket.edns_incapable());
+
+        let result = socket.query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::Timeout)));
+    }
+
+    #[test]
+    fn test_it_decodes_uri_records() {
+        let mut rdata = vec![0, 10, 0, 1];
+        rdata.extend_from_slice(b"ftp://ftp1.example.com/public");
+        let bytes = Packet::response(1)
+            .question("_ftp._tcp.example.com", 256)
+            .answer("_ftp._tcp.example.com", 256, 3600, rdata.clone())
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        let record = &message.answers()[0];
+        let uri = Uri {
+            priority: 10,
+            weight: 1,
+            target: "ftp://ftp1.example.com/public".to_string(),
+        };
+        assert_eq!(record.rdata(), &RecordData::Uri(uri));
+        assert_eq!(record.data(), rdata.as_slice());
+        assert_eq!(
+            crate::output::format_record(record),
+            "_ftp._tcp.example.com.\t3600\tIN\tURI\t10 1 \"ftp://ftp1.example.com/public\""
+        );
+        assert_eq!("uri".parse::<DnsRecordType>().unwrap(), DnsRecordType::URI);
+    }
+
+    #[test]
+    fn test_it_sends_raw_queries_verbatim() {
+        let mut request = DnsMessage::new(0x4242);
+        request.set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        let request = request.to_bytes();
+        let (sent, received) = mpsc::channel();
+        let server = mock_server(move |query| {
+            sent.send(query.to_vec()).unwrap();
+            Some(
+                Packet::response(query_id(query))
+                    .question("example.com", 1)
+                    .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+                    .build(),
+            )
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        let response = socket.query_raw(&request).unwrap();
+        assert_eq!(received.recv().unwrap(), request);
+        assert_eq!(socket.last_request(), request.as_slice());
+        assert_eq!(response.transaction_id(), 0x4242);
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_it_iterates_answer_records() {
+        let bytes = Packet::response(1)
+            .question("www.example.com", 1)
+            .answer("www.example.com", 5, 300, name("example.com"))
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .build();
+        let message = DnsMessage::from_bytes(&bytes).unwrap();
+        let records: Vec<(&str, u32, &RecordData)> = message.records().collect();
+        assert_eq!(
+            records,
+            vec![
+                ("www.example.com", 300, &RecordData::Cname("example.com".to_string())),
+                ("example.com", 60, &RecordData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_it_canonicalizes_answers() {
+        let bytes = Packet::response(1)
+            .question("example.com", 1)
+            .answer("example.com", 1, 300, vec![192, 0, 2, 1])
+            .answer("www.example.com", 1, 300, vec![192, 0, 2, 9])
+            .answer("EXAMPLE.com", 1, 60, vec![192, 0, 2, 1])
+            .answer("example.com", 1, 300, vec![192, 0, 2, 2])
+            .build();
+        let mut message = DnsMessage::from_bytes(&bytes).unwrap();
+        message.canonicalize();
+        let answers: Vec<(&str, Option<IpAddr>, u32)> =
+            message.answers().iter().map(|r| (r.name(), r.ip_address(), r.ttl())).collect();
+        assert_eq!(
+            answers,
+            vec![
+                ("example.com", Some("192.0.2.1".parse().unwrap()), 60),
+                ("example.com", Some("192.0.2.2".parse().unwrap()), 300),
+                ("www.example.com", Some("192.0.2.9".parse().unwrap()), 300),
+            ]
+        );
+        assert_eq!(message.answer_count(), 3);
+    }
+
+    #[test]
+    fn test_it_queries_across_classes() {
+        let server = mock_server(|query| {
+            let at = query.len() - 2;
+            assert_eq!(&query[at..], &[0, 255]);
+            Some(
+                Packet::response(query_id(query))
+                    .question_in_class("version.bind", 16, 255)
+                    .answer_in_class("version.bind", 16, 1, 60, b"\x02in".to_vec())
+                    .answer_in_class("version.bind", 16, 3, 0, b"\x069.18.1".to_vec())
+                    .build(),
+            )
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket.set_options(QueryOptions {
+            class: DnsQueryClass::AllClass,
+            ..QueryOptions::default()
+        });
+        let response = socket
+            .query("version.bind".to_string(), DnsQueryType::Recursive, DnsRecordType::TXT)
+            .unwrap();
+        let classes: Vec<u16> = response.answers().iter().map(|r| r.class()).collect();
+        assert_eq!(classes, vec![1, 3]);
+        let texts: Vec<&RecordData> = response.answers().iter().map(|r| r.rdata()).collect();
+        assert_eq!(texts[0], &RecordData::Txt(vec!["in".to_string()]));
+        assert_eq!(texts[1], &RecordData::Txt(vec!["9.18.1".to_string()]));
+        let formatted = crate::output::format_record(&response.answers()[1]);
+        assert_eq!(formatted, "version.bind.\t0\tCH\tTXT\t\"9.18.1\"");
+        assert_eq!("ch".parse::<DnsQueryClass>().unwrap(), DnsQueryClass::ChaosClass);
+    }
+
+    #[test]
+    fn test_it_decodes_null_records_as_raw_bytes() {
+        let payload = vec![0x00, 0xff, 0xc0, 0x0c, 0x80];
+        let bytes = Packet::response(1)
+            .question("t.example.com", 10)
+            .answer("t.example.com", 10, 0, payload.clone())
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let record = &response.answers()[0];
+        assert_eq!(record.rdata(), &RecordData::Null(payload.clone()));
+        assert_eq!(record.data(), &payload[..]);
+        assert_eq!(crate::output::format_record(record), "t.example.com.\t0\tIN\tNULL\t\\# 5 00ffc00c80");
+    }
+
+    #[test]
+    fn test_it_decodes_loc() {
+        let mut rdata = vec![0, 0x33, 0x16, 0x13];
+        for field in &[0x8917_2dd0u32, 0x70be_15f0, 0x0098_8d20] {
+            rdata.extend_from_slice(&field.to_be_bytes());
+        }
+        let bytes = Packet::response(1)
+            .question("cambridge-net.kei.com", 29)
+            .answer("cambridge-net.kei.com", 29, 3600, rdata)
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let loc = match response.answers()[0].rdata() {
+            RecordData::Loc(loc) => loc.clone(),
+            other => panic!("not a LOC: {:?}", other),
+        };
+        assert_eq!(loc.size, 0x33);
+        assert_eq!(loc.to_string(), "42 21 54.000 N 71 6 18.000 W -24.00m 30m 10000m 10m");
+
+        // Other versions can't be interpreted, but don't fail the message.
+        let bytes = Packet::response(1)
+            .question("example.com", 29)
+            .answer("example.com", 29, 3600, vec![1, 2, 3])
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(matches!(response.answers()[0].rdata(), RecordData::Unknown { rtype: 29, .. }));
+    }
+
+    #[test]
+    fn test_it_follows_dname_redirections() {
+        assert_eq!(
+            synthesize_dname("example.com", "example.net", "a.www.EXAMPLE.com.").as_deref(),
+            Some("a.www.example.net")
+        );
+        // The owner itself isn't redirected, nor are names merely
+        // ending in the same characters.
+        assert_eq!(synthesize_dname("example.com", "example.net", "example.com"), None);
+        assert_eq!(synthesize_dname("example.com", "example.net", "notexample.com"), None);
+
+        let bytes = Packet::response(1)
+            .question("www.example.com", 1)
+            .answer("example.com", 39, 300, name("example.net"))
+            .answer("www.example.net", 5, 300, name("web.example.org"))
+            .answer("web.example.org", 1, 300, vec![192, 0, 2, 80])
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(response.answers()[0].rdata(), &RecordData::Dname("example.net".to_string()));
+        assert_eq!(response.canonical_name("www.example.com"), "web.example.org");
+        assert_eq!(response.canonical_name("example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_it_decodes_sshfp() {
+        let mut rdata = vec![4, 2];
+        rdata.extend_from_slice(&[0x12, 0x34, 0xab, 0xcd, 0x00, 0xff]);
+        let bytes = Packet::response(1)
+            .question("host.example.com", 44)
+            .answer("host.example.com", 44, 3600, rdata)
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let sshfp = Sshfp {
+            algorithm: 4,
+            fingerprint_type: 2,
+            fingerprint: vec![0x12, 0x34, 0xab, 0xcd, 0x00, 0xff],
+        };
+        assert_eq!(response.answers()[0].rdata(), &RecordData::Sshfp(sshfp.clone()));
+        assert_eq!(sshfp.to_string(), "4 2 1234abcd00ff");
+    }
+
+    #[test]
+    fn test_it_decodes_hinfo() {
+        let bytes = Packet::response(1)
+            .question("host.example.com", 13)
+            .answer("host.example.com", 13, 3600, b"\x06PDP-11\x04UNIX".to_vec())
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let hinfo = Hinfo {
+            cpu: "PDP-11".to_string(),
+            os: "UNIX".to_string(),
+        };
+        assert_eq!(response.answers()[0].rdata(), &RecordData::Hinfo(hinfo.clone()));
+        assert_eq!(hinfo.to_string(), "\"PDP-11\" \"UNIX\"");
+        assert_eq!(DnsRecordType::from_str("HINFO").unwrap(), DnsRecordType::HINFO);
+    }
+
+    mod round_trip {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+
+        /// Names are lowercase so that compression, which may replace a
+        /// suffix with an earlier spelling of it, can't change them.
+        fn domain() -> impl Strategy<Value = String> {
+            vec("[a-z0-9]{1,12}", 1..4).prop_map(|labels| labels.join("."))
+        }
+
+        fn rdata() -> impl Strategy<Value = RecordData> {
+            prop_oneof![
+                any::<[u8; 4]>().prop_map(|b| RecordData::A(Ipv4Addr::from(b))),
+                any::<[u8; 16]>().prop_map(|b| RecordData::Aaaa(Ipv6Addr::from(b))),
+                (any::<u16>(), domain()).prop_map(|(preference, exchange)| RecordData::Mx(Mx { preference, exchange })),
+                vec("[ -~]{0,40}", 1..4).prop_map(RecordData::Txt),
+                (domain(), domain(), any::<[u32; 5]>()).prop_map(|(mname, rname, n)| RecordData::Soa(Soa {
+                    mname,
+                    rname,
+                    serial: n[0],
+                    refresh: n[1],
+                    retry: n[2],
+                    expire: n[3],
+                    minimum: n[4],
+                })),
+                (any::<[u16; 3]>(), domain()).prop_map(|(n, target)| RecordData::Srv(Srv {
+                    priority: n[0],
+                    weight: n[1],
+                    port: n[2],
+                    target,
+                })),
+            ]
+        }
+
+        fn record() -> impl Strategy<Value = ResourceRecord> {
+            (domain(), rdata(), any::<u32>()).prop_map(|(name, data, ttl)| {
+                let rtype = match data {
+                    RecordData::A(_) => DnsRecordType::A,
+                    RecordData::Aaaa(_) => DnsRecordType::AAAA,
+                    RecordData::Mx(_) => DnsRecordType::MX,
+                    RecordData::Txt(_) => DnsRecordType::TXT,
+                    RecordData::Soa(_) => DnsRecordType::SOA,
+                    _ => DnsRecordType::SRV,
+                };
+                let mut writer = Writer::uncompressed();
+                data.write(&mut writer);
+                ResourceRecord::new(name, rtype as u16, 1, ttl, writer.into_bytes())
+            })
+        }
+
+        fn message() -> impl Strategy<Value = DnsMessage> {
+            (
+                any::<u16>(),
+                any::<u16>(),
+                domain(),
+                vec(record(), 0..5),
+                vec(record(), 0..3),
+                vec(record(), 0..3),
+            )
+                .prop_map(|(id, flags, qname, answers, authority, additional)| {
+                    let mut message = DnsMessage::new(id);
+                    message.flags = flags;
+                    message.add_question(
+                        QueryZone::new(qname, DnsRecordType::A, DnsQueryClass::InternetClass).unwrap(),
+                    );
+                    answers.into_iter().for_each(|r| message.add_answer(r));
+                    authority.into_iter().for_each(|r| message.add_authority(r));
+                    additional.into_iter().for_each(|r| message.add_additional(r));
+                    message
+                })
+        }
+
+        /// Runs `test` over generated messages from a seed that's printed
+        /// on failure. Setting DIG_PROPTEST_SEED to it replays the run.
+        fn run(test: impl Fn(DnsMessage) -> Result<(), TestCaseError>) {
+            let seed = match std::env::var("DIG_PROPTEST_SEED") {
+                Ok(seed) => seed.parse().expect("DIG_PROPTEST_SEED must be a u64"),
+                Err(_) => random_u64(),
+            };
+            let mut bytes = [0u8; 32];
+            bytes[..8].copy_from_slice(&seed.to_be_bytes());
+            let rng = TestRng::from_seed(RngAlgorithm::ChaCha, &bytes);
+            let mut runner = TestRunner::new_with_rng(Config::default(), rng);
+            if let Err(e) = runner.run(&message(), test) {
+                panic!("{} (reproduce with DIG_PROPTEST_SEED={})", e, seed);
+            }
+        }
+
+        #[test]
+        fn test_it_round_trips_generated_messages() {
+            run(|message| {
+                let bytes = message.to_bytes();
+                let parsed = parse_message(&bytes).unwrap();
+                prop_assert_eq!(&parsed, &message);
+                prop_assert_eq!(parsed.to_bytes(), bytes);
+                Ok(())
+            });
+        }
     }
 }
@@ -0,0 +1,43 @@
+//! Hex encoding of raw packets, for `--raw-query` and capture logs.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// Encodes `bytes` as lowercase hex digits.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes pairs of hex digits, in either case, into bytes.
+pub fn decode(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex: {}", hex));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Serializes bytes as a hex string, for `#[serde(with = "crate::hex")]`.
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+/// Reads bytes back from what `serialize` wrote.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    decode(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_round_trips_bytes() {
+        assert_eq!(encode(&[0x12, 0xab, 0x00]), "12ab00");
+        assert_eq!(decode("12AB00").unwrap(), vec![0x12, 0xab, 0x00]);
+        assert!(decode("123").is_err());
+        assert!(decode("zz").is_err());
+    }
+}
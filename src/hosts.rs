@@ -0,0 +1,114 @@
+//! The static host table in `/etc/hosts`, which resolv.conf's `lookup`
+//! line can put ahead of DNS.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::dns::{DnsMessage, DnsQueryType, DnsRecordType, ResourceRecord};
+
+/// Where the host table is read from unless told otherwise.
+pub const HOSTS_FILE: &str = "/etc/hosts";
+
+/// Hosts maps names to the addresses the host table gives them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hosts {
+    /// (lowercased name, address) pairs, in the order of the file.
+    entries: Vec<(String, IpAddr)>,
+}
+
+impl Hosts {
+    /// Parses hosts(5) lines: an address, then its canonical name and any
+    /// aliases. Lines whose address does not parse are skipped, as the C
+    /// library does.
+    pub fn parse(contents: &str) -> Self {
+        let mut entries = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let address = match fields.next().map(str::parse::<IpAddr>) {
+                Some(Ok(address)) => address,
+                _ => continue,
+            };
+            for name in fields {
+                entries.push((normalize(name), address));
+            }
+        }
+        Hosts { entries }
+    }
+
+    /// Reads and parses the host table at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        std::fs::read_to_string(path).map(|contents| Hosts::parse(&contents))
+    }
+
+    /// The addresses listed for `name`, in file order. Names match
+    /// without regard to case or a trailing dot.
+    pub fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        let name = normalize(name);
+        self.entries
+            .iter()
+            .filter(|(entry, _)| *entry == name)
+            .map(|(_, address)| *address)
+            .collect()
+    }
+
+    /// A response answering `name`'s A or AAAA records from the table, as
+    /// if a server had sent it, or `None` if the table has no address of
+    /// that family for it, or for any other type.
+    pub fn answer(&self, name: &str, record: DnsRecordType) -> Option<DnsMessage> {
+        let rdata: Vec<Vec<u8>> = self
+            .lookup(name)
+            .into_iter()
+            .filter_map(|address| match (address, record) {
+                (IpAddr::V4(address), DnsRecordType::A) => Some(address.octets().to_vec()),
+                (IpAddr::V6(address), DnsRecordType::AAAA) => Some(address.octets().to_vec()),
+                _ => None,
+            })
+            .collect();
+        if rdata.is_empty() {
+            return None;
+        }
+        let mut message = DnsMessage::new(0);
+        message.set_query(name.to_string(), DnsQueryType::Recursive, record).ok()?;
+        message.set_response(true);
+        for data in rdata {
+            // A TTL of 0, as nothing should cache what the table says.
+            message.add_answer(ResourceRecord::new(name.to_string(), record.value(), 1, 0, data));
+        }
+        Some(message)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_parses_names_and_aliases() {
+        let hosts = Hosts::load("test/hosts").unwrap();
+        assert_eq!(hosts.lookup("fileserver.example.com"), vec!["192.0.2.10".parse::<IpAddr>().unwrap()]);
+        assert_eq!(hosts.lookup("FILESERVER."), vec!["192.0.2.10".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            hosts.lookup("dual.example.com"),
+            vec!["192.0.2.20".parse::<IpAddr>().unwrap(), "2001:db8::20".parse().unwrap()]
+        );
+        assert!(hosts.lookup("commented.example.com").is_empty());
+        assert!(hosts.lookup("nowhere.example.com").is_empty());
+    }
+
+    #[test]
+    fn test_it_answers_by_address_family() {
+        let hosts = Hosts::load("test/hosts").unwrap();
+        let answer = hosts.answer("dual.example.com", DnsRecordType::AAAA).unwrap();
+        assert!(answer.flags().qr);
+        assert_eq!(answer.answers().len(), 1);
+        assert_eq!(answer.answers()[0].ip_address(), Some("2001:db8::20".parse().unwrap()));
+        assert!(hosts.answer("fileserver", DnsRecordType::AAAA).is_none());
+        assert!(hosts.answer("fileserver", DnsRecordType::MX).is_none());
+    }
+}
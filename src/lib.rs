@@ -0,0 +1,16 @@
+pub mod capture;
+pub mod config;
+pub mod diff;
+pub mod hex;
+pub mod dns;
+pub mod hosts;
+pub mod llmnr;
+pub mod logger;
+pub mod message_ref;
+pub mod output;
+pub mod pool;
+pub mod punycode;
+pub mod resolver;
+
+#[cfg(test)]
+mod test_util;
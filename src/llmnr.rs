@@ -0,0 +1,147 @@
+//! Link-Local Multicast Name Resolution (RFC 4795), which resolves
+//! single-label names by asking every host on the local link.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::{debug, trace};
+
+use crate::dns::{check_question, random_u16, BindOptions, DnsError, DnsMessage, DnsQueryType, DnsRecordType, MAX_UDP_SIZE};
+
+/// The IPv4 group and port LLMNR queries are sent to.
+pub const LLMNR_GROUP: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 252), 5355));
+
+/// How long to collect responses for, LLMNR_TIMEOUT in RFC 4795.
+pub const LLMNR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Builds an LLMNR query for `name`, which must be a single label. The
+/// LLMNR header reuses the RD bit as T (tentative) and AA as C
+/// (conflict), and a query sets neither, so unlike a DNS query every
+/// flag is clear.
+pub fn llmnr_query(trans_id: u16, name: &str, record: DnsRecordType) -> Result<DnsMessage, DnsError> {
+    let label = name.strip_suffix('.').unwrap_or(name);
+    if label.is_empty() || label.contains('.') {
+        return Err(DnsError::InvalidName(format!("{} is not a single-label name", name)));
+    }
+    let mut message = DnsMessage::new(trans_id);
+    message.set_query(label.to_string(), DnsQueryType::Iterative, record)?;
+    Ok(message)
+}
+
+/// LlmnrClient sends LLMNR queries to a multicast group and collects the
+/// unicast responses of every host that answers.
+#[derive(Debug)]
+pub struct LlmnrClient {
+    socket: UdpSocket,
+    group: SocketAddr,
+    timeout: Duration,
+}
+
+impl LlmnrClient {
+    pub fn new() -> io::Result<Self> {
+        LlmnrClient::with_group(LLMNR_GROUP)
+    }
+
+    /// Creates a client that sends to `group` instead of `LLMNR_GROUP`.
+    pub fn with_group(group: SocketAddr) -> io::Result<Self> {
+        let socket = BindOptions::for_server(group).bind()?;
+        if group.is_ipv4() {
+            // Queries must not leave the link.
+            socket.set_multicast_ttl_v4(1)?;
+        }
+        Ok(LlmnrClient {
+            socket,
+            group,
+            timeout: LLMNR_TIMEOUT,
+        })
+    }
+
+    pub fn group(&self) -> SocketAddr {
+        self.group
+    }
+
+    /// Sets how long `query` waits for responses.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Asks the link for `name` and returns every response that arrived
+    /// before the timeout, along with who sent it. No response at all is
+    /// not an error, as it just means no host claims the name.
+    pub fn query(&self, name: &str, record: DnsRecordType) -> Result<Vec<(SocketAddr, DnsMessage)>, DnsError> {
+        let trans_id = random_u16();
+        let request = llmnr_query(trans_id, name, record)?;
+        let bytes = request.to_bytes_checked(MAX_UDP_SIZE)?;
+        trace!("sending {} bytes with id {} to {}", bytes.len(), trans_id, self.group);
+        self.socket.send_to(&bytes, self.group)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut responses = Vec::new();
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            };
+            trace!("received {} bytes from {}", len, from);
+            match DnsMessage::from_bytes(&buf[..len]) {
+                Ok(response) if response.transaction_id() == trans_id && response.flags().qr => {
+                    match check_question(&request, response) {
+                        Ok(response) => responses.push((from, response)),
+                        Err(e) => debug!("ignoring response from {}: {}", from, e),
+                    }
+                }
+                Ok(_) => debug!("ignoring unrelated packet from {}", from),
+                Err(e) => debug!("ignoring malformed packet from {}: {}", from, e),
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_server, question, query_id, Packet};
+
+    #[test]
+    fn test_it_only_asks_for_single_labels() {
+        assert_eq!(LLMNR_GROUP, "224.0.0.252:5355".parse().unwrap());
+        assert_eq!(LlmnrClient::new().unwrap().group(), LLMNR_GROUP);
+
+        let query = llmnr_query(7, "printer.", DnsRecordType::A).unwrap();
+        assert_eq!(query.question().unwrap().name(), "printer");
+        assert_eq!(query.to_bytes()[2..4], [0, 0]);
+        assert!(matches!(
+            llmnr_query(7, "printer.example.com", DnsRecordType::A),
+            Err(DnsError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn test_it_collects_unicast_responses() {
+        let responder = mock_server(|query| {
+            let (name, qtype) = question(query);
+            Some(
+                Packet::response(query_id(query))
+                    .flags(0x8000)
+                    .question(&name, qtype)
+                    .answer(&name, 1, 30, vec![192, 168, 1, 20])
+                    .build(),
+            )
+        });
+        let mut client = LlmnrClient::with_group(responder).unwrap();
+        client.set_timeout(Duration::from_millis(200));
+        let responses = client.query("printer", DnsRecordType::A).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0, responder);
+        assert_eq!(responses[0].1.answers()[0].ip_address(), Some("192.168.1.20".parse().unwrap()));
+    }
+}
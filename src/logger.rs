@@ -0,0 +1,98 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// StderrLogger prints log records to stderr as dig-style comments, so
+/// they stay out of the way of the answers on stdout.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(";; {}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Maps the number of `-v` flags to a log level: none keeps the default
+/// warnings only, one adds retries and failovers, and two or more add
+/// every packet sent, received and parsed.
+pub fn level_for(verbosity: u64) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Installs the stderr logger at the level for `verbosity`.
+pub fn init(verbosity: u64) {
+    // Another logger may already be installed, e.g. by tests.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level_for(verbosity));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQueryType, DnsRecordType, DnsSocket};
+    use crate::test_util::{mock_server, query_id, Packet};
+    use log::Level;
+    use std::sync::Mutex;
+
+    /// CapturingLogger keeps every record so tests can inspect them.
+    struct CapturingLogger {
+        records: Mutex<Vec<(Level, String)>>,
+    }
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= log::max_level()
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.records.lock().unwrap().push((record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: CapturingLogger = CapturingLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_verbose_produces_trace_records() {
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(level_for(2));
+
+        let server = mock_server(|query| {
+            Some(Packet::response(query_id(query)).question("example.com", 1).build())
+        });
+        let mut socket = DnsSocket::new(server).unwrap();
+        socket
+            .query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+
+        let records = CAPTURE.records.lock().unwrap();
+        let expected = format!("to {}", server);
+        assert!(records.iter().any(|(l, m)| *l == Level::Trace && m.starts_with("sending") && m.ends_with(&expected)));
+        assert!(records.iter().any(|(l, m)| *l == Level::Trace && m.starts_with("received")));
+        assert!(records.iter().any(|(l, m)| *l == Level::Trace && m.starts_with("parsing")));
+    }
+
+    #[test]
+    fn test_verbosity_levels() {
+        assert_eq!(level_for(0), LevelFilter::Warn);
+        assert_eq!(level_for(1), LevelFilter::Debug);
+        assert_eq!(level_for(3), LevelFilter::Trace);
+    }
+}
@@ -1,16 +1,188 @@
-mod config;
-mod dns;
-
-use config::AppConfig;
+use dig_rs::capture::{self, Replay};
+use dig_rs::config::{self, AppConfig, BatchEntry};
+use dig_rs::dns::{DnsError, DEFAULT_PORT};
+use dig_rs::llmnr::LlmnrClient;
+use dig_rs::logger;
+use dig_rs::output::{self, Style};
+use dig_rs::resolver::Resolver;
 use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+
+type Entries = Box<dyn Iterator<Item = Result<BatchEntry, DnsError>>>;
+
+/// The names to look up: those in the batch file, the one given, or
+/// otherwise each line piped to stdin as it arrives.
+fn entries(config: &AppConfig) -> Result<Entries, Box<dyn Error>> {
+    if config.stdin_names() {
+        return Ok(Box::new(config::batch_entries(io::stdin().lock(), config.record_type())));
+    }
+    let entries = match config.batch_file() {
+        Some(path) => config::parse_batch_file(path, config.record_type())?,
+        None => vec![BatchEntry {
+            hostname: config.hostname().ok_or("no hostname to look up")?.to_string(),
+            record_type: config.record_type(),
+        }],
+    };
+    Ok(Box::new(entries.into_iter().map(Ok)))
+}
+
+/// Answers every lookup from a capture log, without touching the network.
+fn replay(config: &AppConfig, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut replay = Replay::open(Path::new(path))?;
+    let stdout = io::stdout();
+    let style = Style {
+        color: config.color().enabled(stdout.is_terminal()),
+        idn: config.idn_display(),
+    };
+    let mut out = stdout.lock();
+    for entry in entries(config)? {
+        let entry = entry?;
+        let response = replay.query(&entry.hostname, entry.record_type)?;
+        if let Some(path) = config.raw_file() {
+            std::fs::write(path, replay.last_response())?;
+        }
+        output::write_lookup(
+            &mut out,
+            config.output_format(),
+            &entry.hostname,
+            entry.record_type,
+            &response,
+            replay.last_stats(),
+            style,
+        )?;
+    }
+    Ok(())
+}
+
+/// Appends every exchange the resolver kept to the `--capture` log,
+/// those of failed lookups included.
+fn save_capture(config: &AppConfig, resolver: &mut Resolver) -> io::Result<()> {
+    if let Some(path) = config.capture_file() {
+        for entry in resolver.take_exchanges() {
+            capture::append(Path::new(path), &entry)?;
+        }
+    }
+    Ok(())
+}
 
 fn query(config: AppConfig) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = config.replay_file() {
+        return replay(&config, path);
+    }
+    let mut resolver = Resolver::from_settings(&config.resolver_settings())?;
+    resolver.set_recording(config.capture_file().is_some());
+    let stdout = io::stdout();
+    let style = Style {
+        color: config.color().enabled(stdout.is_terminal()),
+        idn: config.idn_display(),
+    };
+    let mut out = stdout.lock();
+
+    if let Some(request) = config.raw_query() {
+        let response = resolver.query_raw(request);
+        save_capture(&config, &mut resolver)?;
+        let response = response?;
+        if let Some(path) = config.raw_file() {
+            std::fs::write(path, resolver.last_response())?;
+        }
+        let (hostname, record_type) = match response.question() {
+            Some(question) => (question.name().to_string(), question.record_type().unwrap_or(config.record_type())),
+            None => (String::new(), config.record_type()),
+        };
+        output::write_lookup(
+            &mut out,
+            config.output_format(),
+            &hostname,
+            record_type,
+            &response,
+            resolver.last_stats(),
+            style,
+        )?;
+        return Ok(());
+    }
+
+    if config.nssearch() {
+        let zone = config.hostname().unwrap();
+        let serials = resolver.nssearch(zone, DEFAULT_PORT);
+        save_capture(&config, &mut resolver)?;
+        let serials = serials?;
+        output::write_nssearch(&mut out, zone, &serials)?;
+        return Ok(());
+    }
+
+    if config.check_delegation() {
+        let check = resolver.check_delegation(config.hostname().unwrap(), DEFAULT_PORT);
+        save_capture(&config, &mut resolver)?;
+        let check = check?;
+        output::write_delegation_check(&mut out, &check)?;
+        return Ok(());
+    }
+
+    if config.serial() {
+        let serial = resolver.soa_serial(config.hostname().unwrap());
+        save_capture(&config, &mut resolver)?;
+        let serial = serial?;
+        writeln!(out, "{}", serial)?;
+        return Ok(());
+    }
+
+    if config.llmnr() {
+        let hostname = config.hostname().unwrap();
+        let responses = LlmnrClient::new()?.query(hostname, config.record_type())?;
+        output::write_llmnr(&mut out, config.output_format(), hostname, config.record_type(), &responses, style)?;
+        return Ok(());
+    }
+
+    if config.compare() {
+        let hostname = config.hostname().unwrap();
+        let comparison = resolver.compare(hostname, config.query_type(), config.record_type());
+        save_capture(&config, &mut resolver)?;
+        output::write_comparison(&mut out, &comparison)?;
+        return Ok(());
+    }
+
+    for entry in entries(&config)? {
+        let entry = entry?;
+        if config.all_servers() {
+            let responses = resolver.query_all(&entry.hostname, config.query_type(), entry.record_type);
+            save_capture(&config, &mut resolver)?;
+            output::write_all_servers(
+                &mut out,
+                config.output_format(),
+                &entry.hostname,
+                entry.record_type,
+                &responses,
+                style,
+            )?;
+            continue;
+        }
+        let response = resolver.lookup(&entry.hostname, config.query_type(), entry.record_type);
+        save_capture(&config, &mut resolver)?;
+        let response = response?;
+        if let Some(path) = config.raw_file() {
+            std::fs::write(path, resolver.last_response())?;
+        }
+        output::write_lookup(
+            &mut out,
+            config.output_format(),
+            &entry.hostname,
+            entry.record_type,
+            &response,
+            resolver.last_stats(),
+            style,
+        )?;
+        if config.size_report() {
+            output::write_size_report(&mut out, &response.size_report())?;
+        }
+    }
     Ok(())
 }
 
 fn main() {
     let config = AppConfig::from(&mut std::env::args_os());
-    
+    logger::init(config.verbosity());
+
     if let Err(e) = query(config) {
         eprintln!("Error performing DNS query: {}", e);
     }
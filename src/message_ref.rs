@@ -0,0 +1,405 @@
+use std::borrow::Cow;
+
+use crate::dns::{parse_error, DnsError, Reader, MAX_POINTER_JUMPS};
+
+/// The fixed size of the message header.
+const HEADER_LEN: usize = 12;
+
+/// Skips over a possibly compressed name without decoding it.
+fn skip_name(reader: &mut Reader) -> Result<(), DnsError> {
+    loop {
+        let len = reader.u8()?;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Ok(()),
+            0x00 => {
+                reader.bytes(len as usize)?;
+            }
+            0xc0 => {
+                // A pointer always ends the name where it appears.
+                reader.u8()?;
+                return Ok(());
+            }
+            _ => return Err(parse_error(reader.pos - 1, "reserved label type")),
+        }
+    }
+}
+
+/// NameRef is a name read in place from a message buffer. Its labels are
+/// borrowed straight from the buffer, following compression pointers as
+/// needed.
+#[derive(Clone, Copy, Debug)]
+pub struct NameRef<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NameRef<'a> {
+    /// An iterator over the labels of the name, borrowed from the buffer.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            buf: self.buf,
+            pos: self.offset,
+            jumps: 0,
+            done: false,
+        }
+    }
+
+    /// The dotted form of the name. The wire format stores labels with
+    /// length prefixes rather than dots, so this only borrows for names of
+    /// at most one label, and has to assemble an owned string otherwise.
+    pub fn to_cow(&self) -> Result<Cow<'a, str>, DnsError> {
+        let mut labels = self.labels();
+        let first = match labels.next() {
+            Some(label) => label?,
+            None => return Ok(Cow::Borrowed("")),
+        };
+        let mut name = match labels.next() {
+            Some(label) => format!("{}.{}", first, label?),
+            None => return Ok(first),
+        };
+        for label in labels {
+            name.push('.');
+            name.push_str(&label?);
+        }
+        Ok(Cow::Owned(name))
+    }
+}
+
+/// Labels walks the labels of a `NameRef`.
+#[derive(Debug)]
+pub struct Labels<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    jumps: usize,
+    done: bool,
+}
+
+impl<'a> Labels<'a> {
+    fn step(&mut self) -> Result<Option<Cow<'a, str>>, DnsError> {
+        loop {
+            let mut reader = Reader::new(self.buf);
+            reader.pos = self.pos;
+            let len = reader.u8()?;
+            match len & 0xc0 {
+                0x00 if len == 0 => return Ok(None),
+                0x00 => {
+                    let label = reader.bytes(len as usize)?;
+                    self.pos = reader.pos;
+                    return Ok(Some(String::from_utf8_lossy(label)));
+                }
+                0xc0 => {
+                    self.jumps += 1;
+                    if self.jumps > MAX_POINTER_JUMPS {
+                        return Err(parse_error(self.pos, "too many compression pointers"));
+                    }
+                    self.pos = (((len & 0x3f) as usize) << 8) | reader.u8()? as usize;
+                }
+                _ => return Err(parse_error(self.pos, "reserved label type")),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = Result<Cow<'a, str>, DnsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.step() {
+            Ok(Some(label)) => Some(Ok(label)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// QueryZoneRef is a question read in place from a message buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryZoneRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// ResourceRecordRef is a record read in place from a message buffer.
+/// Unlike `ResourceRecord`, its rdata is exactly the bytes on the wire,
+/// so names inside it may still be compressed.
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceRecordRef<'a> {
+    pub name: NameRef<'a>,
+    pub rr_type: u16,
+    pub rr_class: u16,
+    pub ttl: u32,
+    pub data: &'a [u8],
+}
+
+/// DnsMessageRef is a view of a received message that parses lazily and
+/// borrows from the buffer instead of copying it, for callers handling
+/// high volumes of responses. Only the header is checked up front; the
+/// sections are parsed as they are iterated. `DnsMessage` is simpler to
+/// use when that does not matter.
+#[derive(Clone, Copy, Debug)]
+pub struct DnsMessageRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> DnsMessageRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DnsError> {
+        if buf.len() < HEADER_LEN {
+            return Err(parse_error(buf.len(), "message shorter than its header"));
+        }
+        Ok(DnsMessageRef { buf })
+    }
+
+    fn header_field(&self, index: usize) -> u16 {
+        u16::from_be_bytes([self.buf[index * 2], self.buf[index * 2 + 1]])
+    }
+
+    pub fn transaction_id(&self) -> u16 {
+        self.header_field(0)
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.header_field(1)
+    }
+
+    /// The response code, the low 4 bits of flags.
+    pub fn rcode(&self) -> u8 {
+        (self.flags() & 0x000f) as u8
+    }
+
+    pub fn query_count(&self) -> u16 {
+        self.header_field(2)
+    }
+
+    pub fn answer_count(&self) -> u16 {
+        self.header_field(3)
+    }
+
+    pub fn authority_count(&self) -> u16 {
+        self.header_field(4)
+    }
+
+    pub fn additional_count(&self) -> u16 {
+        self.header_field(5)
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            buf: self.buf,
+            pos: HEADER_LEN,
+            remaining: self.query_count(),
+            failed: false,
+        }
+    }
+
+    /// Iterates the answer section. Each call walks past the questions
+    /// again, which is cheap since nothing is decoded along the way.
+    pub fn answers(&self) -> Records<'a> {
+        self.records_after(0, self.answer_count())
+    }
+
+    pub fn authority(&self) -> Records<'a> {
+        self.records_after(self.answer_count(), self.authority_count())
+    }
+
+    pub fn additional(&self) -> Records<'a> {
+        let skip = self.answer_count().saturating_add(self.authority_count());
+        self.records_after(skip, self.additional_count())
+    }
+
+    /// The records of the section that starts after `skip` records. If
+    /// anything before the section is malformed, the section yields that
+    /// error.
+    fn records_after(&self, skip: u16, count: u16) -> Records<'a> {
+        let mut questions = self.questions();
+        let mut error = questions.find_map(|q| q.err());
+        let mut skipped = Records {
+            buf: self.buf,
+            pos: questions.pos,
+            remaining: skip,
+            failed: false,
+            error: None,
+        };
+        if error.is_none() {
+            error = skipped.find_map(|r| r.err());
+        }
+        Records {
+            buf: self.buf,
+            pos: skipped.pos,
+            remaining: count,
+            failed: false,
+            error,
+        }
+    }
+}
+
+/// Questions walks the question section of a `DnsMessageRef`.
+#[derive(Debug)]
+pub struct Questions<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    remaining: u16,
+    failed: bool,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QueryZoneRef<'a>, DnsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+        let mut reader = Reader::new(self.buf);
+        reader.pos = self.pos;
+        let name = NameRef {
+            buf: self.buf,
+            offset: self.pos,
+        };
+        let question = skip_name(&mut reader).and_then(|_| {
+            Ok(QueryZoneRef {
+                name,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            })
+        });
+        match question {
+            Ok(question) => {
+                self.pos = reader.pos;
+                self.remaining -= 1;
+                Some(Ok(question))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Records walks one record section of a `DnsMessageRef`.
+#[derive(Debug)]
+pub struct Records<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    remaining: u16,
+    failed: bool,
+    /// An error from an earlier section, reported in place of records.
+    error: Option<DnsError>,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<ResourceRecordRef<'a>, DnsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+        let mut reader = Reader::new(self.buf);
+        reader.pos = self.pos;
+        let name = NameRef {
+            buf: self.buf,
+            offset: self.pos,
+        };
+        let record = skip_name(&mut reader).and_then(|_| {
+            let rr_type = reader.u16()?;
+            let rr_class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let rdlength = reader.u16()? as usize;
+            Ok(ResourceRecordRef {
+                name,
+                rr_type,
+                rr_class,
+                ttl,
+                data: reader.bytes(rdlength)?,
+            })
+        });
+        match record {
+            Ok(record) => {
+                self.pos = reader.pos;
+                self.remaining -= 1;
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DnsMessage;
+    use crate::test_util::Packet;
+
+    /// A response whose answer names are compression pointers back to the
+    /// question, as real servers send them.
+    fn compressed_response() -> Vec<u8> {
+        let mut bytes = Packet::response(0x1234).question("www.example.com", 1).build();
+        bytes[7] = 2;
+        for address in &[[192, 0, 2, 1], [192, 0, 2, 2]] {
+            bytes.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+            bytes.extend_from_slice(address);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_it_matches_the_owned_parser() {
+        let bytes = compressed_response();
+        let owned = DnsMessage::from_bytes(&bytes).unwrap();
+        let borrowed = DnsMessageRef::parse(&bytes).unwrap();
+
+        assert_eq!(borrowed.transaction_id(), owned.transaction_id());
+        assert_eq!(borrowed.rcode(), owned.rcode());
+        let question = borrowed.questions().next().unwrap().unwrap();
+        assert_eq!(question.name.to_cow().unwrap(), "www.example.com");
+        assert_eq!(question.qtype, 1);
+
+        let answers: Vec<ResourceRecordRef> = borrowed.answers().collect::<Result<_, _>>().unwrap();
+        assert_eq!(answers.len(), owned.answers().len());
+        for (borrowed, owned) in answers.iter().zip(owned.answers()) {
+            assert_eq!(borrowed.name.to_cow().unwrap(), owned.name());
+            assert_eq!(borrowed.rr_type, owned.record_type());
+            assert_eq!(borrowed.rr_class, owned.class());
+            assert_eq!(borrowed.ttl, owned.ttl());
+            assert_eq!(borrowed.data, owned.data());
+        }
+        assert_eq!(borrowed.authority().count(), 0);
+    }
+
+    #[test]
+    fn test_it_borrows_labels() {
+        let bytes = compressed_response();
+        let borrowed = DnsMessageRef::parse(&bytes).unwrap();
+        let answer = borrowed.answers().nth(1).unwrap().unwrap();
+        let labels: Vec<Cow<str>> = answer.name.labels().collect::<Result<_, _>>().unwrap();
+        assert_eq!(labels, vec!["www", "example", "com"]);
+        assert!(labels.iter().all(|label| matches!(label, Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn test_it_reports_truncated_sections() {
+        let bytes = compressed_response();
+        let truncated = &bytes[..bytes.len() - 2];
+        let borrowed = DnsMessageRef::parse(truncated).unwrap();
+        let answers: Vec<_> = borrowed.answers().collect();
+        assert_eq!(answers.len(), 2);
+        assert!(answers[1].is_err());
+        assert!(borrowed.additional().next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,695 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diff::diff_messages;
+use crate::dns::{
+    class_name, record_type_name, ClientSubnet, DnsMessage, DnsRecordType, Edns, QueryStats, RecordData, ResourceRecord,
+    SizeReport, EDNS_CLIENT_SUBNET, EDNS_COOKIE, EDNS_DO, EDNS_NSID,
+};
+use crate::punycode;
+use crate::resolver::{Comparison, DelegationCheck, ServerResponse, ZoneSerial};
+
+/// OutputFormat selects how lookup results are written.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable, one block per looked-up name.
+    Default,
+    /// One JSON object per looked-up name, one per line.
+    Ndjson,
+    /// Answer records only, in RFC 1035 master file syntax.
+    Zonefile,
+}
+
+/// ColorChoice is the `--color` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color output written to a terminal (`is_tty`) or not.
+    pub fn enabled(self, is_tty: bool) -> bool {
+        match self {
+            ColorChoice::Auto => is_tty && std::env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Style is how the default output format renders things for people,
+/// as opposed to the machine-readable formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    /// Color the output (`--color`).
+    pub color: bool,
+    /// Show A-labels as the Unicode they encode (`--idn-display`).
+    pub idn: bool,
+}
+
+const BOLD: &str = "1";
+const RED: &str = "31";
+const GREEN: &str = "32";
+const CYAN: &str = "36";
+
+/// Wraps `text` in an ANSI escape selecting `sgr`, if `color` is on.
+fn paint(color: bool, sgr: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", sgr, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Renders a response code by its mnemonic.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        _ => format!("RCODE{}", rcode),
+    }
+}
+
+/// Renders a name fully qualified, with its trailing dot.
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// Like `fqdn`, with A-labels decoded if `idn` is on.
+fn display_name(name: &str, idn: bool) -> String {
+    if idn {
+        fqdn(&punycode::to_unicode(name))
+    } else {
+        fqdn(name)
+    }
+}
+
+/// Renders character-strings in master file syntax: each one quoted,
+/// with quotes and backslashes escaped and anything outside printable
+/// ASCII as `\DDD`.
+fn quote_strings(strings: &[String]) -> String {
+    let quote = |string: &String| {
+        let mut quoted = String::from("\"");
+        for &b in string.as_bytes() {
+            match b {
+                b'"' | b'\\' => {
+                    quoted.push('\\');
+                    quoted.push(b as char);
+                }
+                0x20..=0x7e => quoted.push(b as char),
+                _ => quoted.push_str(&format!("\\{:03}", b)),
+            }
+        }
+        quoted.push('"');
+        quoted
+    };
+    strings.iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+/// Renders rdata in master file syntax for the types we understand, and
+/// everything else in the RFC 3597 `\# len hex` form. Every variant of
+/// `RecordData` has its own arm, so a new one can't be added without
+/// deciding how it's shown.
+fn format_rdata(record: &ResourceRecord, idn: bool) -> String {
+    match record.rdata() {
+        RecordData::A(address) => address.to_string(),
+        RecordData::Aaaa(address) => address.to_string(),
+        RecordData::Ns(target) | RecordData::Cname(target) | RecordData::Ptr(target) | RecordData::Dname(target) => {
+            display_name(target, idn)
+        }
+        RecordData::Mx(mx) => format!("{} {}", mx.preference, display_name(&mx.exchange, idn)),
+        RecordData::Soa(soa) => soa.to_string(),
+        RecordData::Hinfo(hinfo) => hinfo.to_string(),
+        RecordData::Loc(loc) => loc.to_string(),
+        RecordData::Sshfp(sshfp) => sshfp.to_string(),
+        RecordData::Uri(uri) => uri.to_string(),
+        RecordData::Txt(strings) | RecordData::Spf(strings) => quote_strings(strings),
+        RecordData::Srv(srv) => {
+            format!("{} {} {} {}", srv.priority, srv.weight, srv.port, display_name(&srv.target, idn))
+        }
+        // NULL rdata has no master file syntax of its own.
+        RecordData::Null(_) | RecordData::Unknown { .. } => {
+            let data = record.data();
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("\\# {} {}", data.len(), hex)
+        }
+    }
+}
+
+/// Writes a single record as `name TTL class type rdata`, with names as
+/// they are on the wire.
+pub fn format_record(record: &ResourceRecord) -> String {
+    format_record_styled(record, Style::default())
+}
+
+fn format_record_styled(record: &ResourceRecord, style: Style) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        display_name(record.name(), style.idn),
+        record.ttl(),
+        class_name(record.class()),
+        paint(style.color, CYAN, &record_type_name(record.record_type())),
+        format_rdata(record, style.idn),
+    )
+}
+
+/// Renders a time as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Converts days since the epoch to a proleptic Gregorian date, per
+    // Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Writes dig's summary of how a query went.
+pub fn write_footer<W: Write>(out: &mut W, stats: &QueryStats) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, ";; Query time: {} msec", stats.elapsed.as_millis())?;
+    writeln!(out, ";; SERVER: {}#{}({})", stats.server.ip(), stats.server.port(), stats.server.ip())?;
+    writeln!(out, ";; WHEN: {}", format_utc(stats.sent_at))?;
+    writeln!(out, ";; MSG SIZE  rcvd: {}", stats.received)
+}
+
+/// Writes the answers for one lookup, headed by the name that was asked
+/// and followed by the footer when `stats` are known. With color, the
+/// header is bold, record types are highlighted, and the status is green
+/// for NOERROR and red otherwise.
+pub fn write_response<W: Write>(
+    out: &mut W,
+    hostname: &str,
+    record_type: DnsRecordType,
+    response: &DnsMessage,
+    stats: Option<&QueryStats>,
+    style: Style,
+) -> io::Result<()> {
+    let rcode = response.rcode();
+    let status = paint(style.color, if rcode == 0 { GREEN } else { RED }, &rcode_name(rcode));
+    let header = paint(style.color, BOLD, &format!(";; {} {}", hostname, record_type));
+    writeln!(out, "{} status: {}, id: {}", header, status, response.transaction_id())?;
+    writeln!(out, ";; flags: {}", response.flags())?;
+    if let Some(edns) = response.edns() {
+        write_opt_pseudosection(out, &edns)?;
+    }
+    if record_type == DnsRecordType::ANY {
+        for (_, records) in response.answers_by_type() {
+            for record in records {
+                writeln!(out, "{}", format_record_styled(record, style))?;
+            }
+        }
+        if response.is_minimal_any() {
+            writeln!(out, ";; the server gave a minimal answer to ANY (RFC 8482), query types one by one instead")?;
+        }
+    } else {
+        for record in response.answers() {
+            writeln!(out, "{}", format_record_styled(record, style))?;
+        }
+    }
+    if response.is_nodata() {
+        let ttl = response.negative_ttl().unwrap_or(0);
+        writeln!(out, ";; no {} records for {} (negative TTL {})", record_type, hostname, ttl)?;
+    }
+    match stats {
+        Some(stats) => write_footer(out, stats),
+        None => Ok(()),
+    }
+}
+
+/// Writes the OPT record the way dig does, as a pseudosection of its own
+/// rather than an additional record.
+fn write_opt_pseudosection<W: Write>(out: &mut W, edns: &Edns) -> io::Result<()> {
+    writeln!(out, ";; OPT PSEUDOSECTION:")?;
+    let flags = if edns.dnssec_ok() { " do" } else { "" };
+    // Reserved bits are shown apart, as dig's "must be zero" field.
+    let mbz = match edns.flags & !EDNS_DO {
+        0 => String::new(),
+        bits => format!("; MBZ: {:#06x}", bits),
+    };
+    writeln!(out, "; EDNS: version: {}, flags:{}{}; udp: {}", edns.version, flags, mbz, edns.udp_size)?;
+    for (code, data) in &edns.options {
+        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+        match *code {
+            EDNS_NSID => {
+                let text: String = data
+                    .iter()
+                    .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                    .collect();
+                writeln!(out, "; NSID: {} (\"{}\")", hex, text)?;
+            }
+            // The scope shows how much of the subnet the answer is
+            // specific to, and zero if it applies everywhere.
+            EDNS_CLIENT_SUBNET => match ClientSubnet::from_option_data(data) {
+                Ok(subnet) => writeln!(out, "; CLIENT-SUBNET: {}", subnet)?,
+                Err(_) => writeln!(out, "; CLIENT-SUBNET: {}", hex)?,
+            },
+            EDNS_COOKIE => writeln!(out, "; COOKIE: {}", hex)?,
+            code => writeln!(out, "; OPT={}: {}", code, hex)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the answer records for one lookup as master file entries, with
+/// no comments, so the output can be loaded as a zone.
+pub fn write_zonefile<W: Write>(out: &mut W, response: &DnsMessage) -> io::Result<()> {
+    for record in response.answers() {
+        writeln!(out, "{}", format_record(record))?;
+    }
+    Ok(())
+}
+
+/// Writes one `nameserver (address): serial N` line per authoritative
+/// server found by an nssearch, so differing serials stand out.
+pub fn write_nssearch<W: Write>(out: &mut W, zone: &str, serials: &[ZoneSerial]) -> io::Result<()> {
+    if serials.is_empty() {
+        writeln!(out, ";; no nameservers found for {}", zone)?;
+    }
+    for serial in serials {
+        let address = match serial.address {
+            Some(address) => address.to_string(),
+            None => "unresolved".to_string(),
+        };
+        match &serial.soa {
+            Ok(soa) => writeln!(out, "{} ({}): serial {}", serial.nameserver, address, soa.serial)?,
+            Err(e) => writeln!(out, "{} ({}): {}", serial.nameserver, address, e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes a message's size report: the encoded size, what compression
+/// saved, and how the bytes divide between the sections.
+pub fn write_size_report<W: Write>(out: &mut W, report: &SizeReport) -> io::Result<()> {
+    let percent = (report.saved() * 100).checked_div(report.uncompressed).unwrap_or(0);
+    writeln!(
+        out,
+        ";; MSG SIZE: {} bytes, {} uncompressed, compression saved {} ({}%)",
+        report.size,
+        report.uncompressed,
+        report.saved(),
+        percent
+    )?;
+    let [header, question, answer, authority, additional] = report.sections;
+    writeln!(
+        out,
+        ";; header {}, question {}, answer {}, authority {}, additional {}",
+        header, question, answer, authority, additional
+    )
+}
+
+/// Writes both NS sets of a delegation check, then each problem found,
+/// or that there were none.
+pub fn write_delegation_check<W: Write>(out: &mut W, check: &DelegationCheck) -> io::Result<()> {
+    writeln!(out, ";; {} at its parent: {}", check.zone, check.parent.join(" "))?;
+    writeln!(out, ";; {} at its nameservers: {}", check.zone, check.child.join(" "))?;
+    if check.problems.is_empty() {
+        writeln!(out, ";; delegation OK")?;
+    }
+    for problem in &check.problems {
+        writeln!(out, "! {}", problem)?;
+    }
+    Ok(())
+}
+
+/// Writes every server's answers, marking records that not all servers
+/// returned with a leading `!`, followed by a verdict line.
+pub fn write_comparison<W: Write>(out: &mut W, comparison: &Comparison) -> io::Result<()> {
+    for server_response in &comparison.responses {
+        match &server_response.response {
+            Ok(response) => {
+                writeln!(out, ";; SERVER: {} status: {}", server_response.server, rcode_name(response.rcode()))?;
+                for record in response.answers() {
+                    let marker = if comparison.differs(record) { "!" } else { " " };
+                    writeln!(out, "{} {}", marker, format_record(record))?;
+                }
+            }
+            Err(e) => writeln!(out, ";; SERVER: {} failed: {}", server_response.server, e)?,
+        }
+    }
+    if comparison.agree() {
+        return writeln!(out, ";; all servers agree");
+    }
+    writeln!(out, ";; servers disagree")?;
+    // Spell out how each server differs from the first that answered.
+    let mut answered = comparison
+        .responses
+        .iter()
+        .filter_map(|r| r.response.as_ref().ok().map(|response| (r.server, response)));
+    if let Some((first, baseline)) = answered.next() {
+        for (server, response) in answered {
+            writeln!(out, ";; {} vs {}:", first, server)?;
+            for difference in diff_messages(baseline, response) {
+                writeln!(out, ";;   {}", difference)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes each host's LLMNR response in `format`, preceded by who sent
+/// it, or a note that no host answered.
+pub fn write_llmnr<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    hostname: &str,
+    record_type: DnsRecordType,
+    responses: &[(SocketAddr, DnsMessage)],
+    style: Style,
+) -> io::Result<()> {
+    if responses.is_empty() {
+        writeln!(out, ";; no LLMNR responses for {}", hostname)?;
+    }
+    for (from, response) in responses {
+        writeln!(out, ";; LLMNR response from {}", from)?;
+        write_lookup(out, format, hostname, record_type, response, None, style)?;
+    }
+    Ok(())
+}
+
+/// Writes every server's answer as its own block in `format`, each headed
+/// by `;; SERVER: address`. A server that failed gets a line saying how.
+pub fn write_all_servers<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    hostname: &str,
+    record_type: DnsRecordType,
+    responses: &[ServerResponse],
+    style: Style,
+) -> io::Result<()> {
+    for (i, server_response) in responses.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, ";; SERVER: {}", server_response.server)?;
+        match &server_response.response {
+            Ok(response) => write_lookup(
+                out,
+                format,
+                hostname,
+                record_type,
+                response,
+                server_response.stats.as_ref(),
+                style,
+            )?,
+            Err(e) => writeln!(out, ";; failed: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// JsonRecord is the serialized form of one answer record.
+#[derive(Debug, Serialize)]
+struct JsonRecord<'a> {
+    name: &'a str,
+    ttl: u32,
+    class: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    data: String,
+}
+
+/// JsonLookup is the serialized form of one looked-up name.
+#[derive(Debug, Serialize)]
+struct JsonLookup<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    record_type: DnsRecordType,
+    status: String,
+    id: u16,
+    answers: Vec<JsonRecord<'a>>,
+}
+
+/// Writes the answers for one lookup as a single line of JSON, flushing
+/// so that consumers see each result as soon as it is resolved.
+pub fn write_ndjson<W: Write>(
+    out: &mut W,
+    hostname: &str,
+    record_type: DnsRecordType,
+    response: &DnsMessage,
+) -> io::Result<()> {
+    let lookup = JsonLookup {
+        name: hostname,
+        record_type,
+        status: rcode_name(response.rcode()),
+        id: response.transaction_id(),
+        answers: response
+            .answers()
+            .iter()
+            .map(|record| JsonRecord {
+                name: record.name(),
+                ttl: record.ttl(),
+                class: class_name(record.class()),
+                record_type: record_type_name(record.record_type()),
+                data: format_rdata(record, false),
+            })
+            .collect(),
+    };
+    serde_json::to_writer(&mut *out, &lookup)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Writes the answers for one lookup in the selected format. Only the
+/// default format has a footer or is ever styled.
+pub fn write_lookup<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    hostname: &str,
+    record_type: DnsRecordType,
+    response: &DnsMessage,
+    stats: Option<&QueryStats>,
+    style: Style,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Default => write_response(out, hostname, record_type, response, stats, style),
+        OutputFormat::Ndjson => write_ndjson(out, hostname, record_type, response),
+        OutputFormat::Zonefile => write_zonefile(out, response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{name, soa_rdata, Packet};
+
+    #[test]
+    fn test_it_writes_one_ndjson_line_per_name() {
+        let names = ["google.com", "example.com", "rust-lang.org"];
+        let mut out = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let bytes = Packet::response(i as u16)
+                .question(name, 1)
+                .answer(name, 1, 300, vec![10, 0, 0, i as u8])
+                .build();
+            let response = DnsMessage::from_bytes(&bytes).unwrap();
+            write_ndjson(&mut out, name, DnsRecordType::A, &response).unwrap();
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), names.len());
+        for (line, name) in lines.iter().zip(names.iter()) {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["name"], *name);
+            assert_eq!(value["type"], "A");
+            assert_eq!(value["answers"][0]["type"], "A");
+        }
+        assert!(lines[2].contains("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_it_colors_only_when_enabled() {
+        let bytes = Packet::response(1)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .rcode(3)
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let lookup = |color| {
+            let mut out = Vec::new();
+            let style = Style { color, ..Style::default() };
+            write_lookup(&mut out, OutputFormat::Default, "example.com", DnsRecordType::A, &response, None, style)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(!ColorChoice::Never.enabled(true));
+        let plain = lookup(ColorChoice::Never.enabled(true));
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.starts_with(";; example.com A status: NXDOMAIN, id: 1\n"));
+        assert!(lookup(true).contains("\x1b[31mNXDOMAIN\x1b[0m"));
+        assert!(plain.contains(";; flags: qr rd ra\n"));
+    }
+
+    #[test]
+    fn test_it_shows_a_labels_as_unicode_when_asked() {
+        let bytes = Packet::response(1)
+            .question("xn--caf-dma.example", 5)
+            .answer("xn--caf-dma.example", 5, 60, name("www.xn--caf-dma.example"))
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let record = &response.answers()[0];
+        let idn = Style { idn: true, ..Style::default() };
+        assert_eq!(format_record_styled(record, idn), "café.example.\t60\tIN\tCNAME\twww.café.example.");
+        assert_eq!(format_record(record), "xn--caf-dma.example.\t60\tIN\tCNAME\twww.xn--caf-dma.example.");
+    }
+
+    #[test]
+    fn test_it_reports_nodata() {
+        let bytes = Packet::response(1)
+            .question("example.com", 28)
+            .authority("example.com", 6, 900, soa_rdata("ns.example.com", 1))
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let mut out = Vec::new();
+        write_response(&mut out, "example.com", DnsRecordType::AAAA, &response, None, Style::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ";; example.com AAAA status: NOERROR, id: 1\n;; flags: qr rd ra\n;; no AAAA records for example.com (negative TTL 300)\n"
+        );
+    }
+
+    #[test]
+    fn test_it_notes_minimal_any_answers() {
+        let bytes = Packet::response(1)
+            .question("example.com", 255)
+            .answer("example.com", 13, 3789, b"\x07RFC8482\x00".to_vec())
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let mut out = Vec::new();
+        write_response(&mut out, "example.com", DnsRecordType::ANY, &response, None, Style::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("minimal answer to ANY (RFC 8482)"), "{}", out);
+    }
+
+    #[test]
+    fn test_it_writes_a_block_per_server() {
+        let bytes = Packet::response(1)
+            .question("example.com", 1)
+            .answer("example.com", 1, 60, vec![192, 0, 2, 1])
+            .build();
+        let responses = vec![
+            ServerResponse {
+                server: "192.0.2.53:53".parse().unwrap(),
+                response: DnsMessage::from_bytes(&bytes),
+                stats: None,
+            },
+            ServerResponse {
+                server: "198.51.100.53:53".parse().unwrap(),
+                response: Err(crate::dns::DnsError::Timeout),
+                stats: None,
+            },
+        ];
+        let mut out = Vec::new();
+        write_all_servers(&mut out, OutputFormat::Zonefile, "example.com", DnsRecordType::A, &responses, Style::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ";; SERVER: 192.0.2.53:53\nexample.com.\t60\tIN\tA\t192.0.2.1\n\n;; SERVER: 198.51.100.53:53\n;; failed: lookup timed out\n"
+        );
+    }
+
+    #[test]
+    fn test_it_writes_the_opt_pseudosection() {
+        let mut rdata = vec![0, 3, 0, 5];
+        rdata.extend_from_slice(b"ns1-a");
+        rdata.extend_from_slice(&[0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let bytes = Packet::response(1).question("example.com", 1).opt(4096, 0x8000, rdata).build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+
+        let mut out = Vec::new();
+        write_response(&mut out, "example.com", DnsRecordType::A, &response, None, Style::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            ";; OPT PSEUDOSECTION:\n; EDNS: version: 0, flags: do; udp: 4096\n\
+             ; NSID: 6e73312d61 (\"ns1-a\")\n; COOKIE: 0102030405060708\n"
+        ));
+    }
+
+    #[test]
+    fn test_it_writes_the_client_subnet_scope() {
+        let rdata = vec![0, 8, 0, 7, 0, 1, 24, 20, 192, 0, 2];
+        let bytes = Packet::response(1).question("example.com", 1).opt(1232, 0, rdata).build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(response.edns().unwrap().client_subnet().unwrap().scope_prefix, 20);
+
+        let mut out = Vec::new();
+        write_response(&mut out, "example.com", DnsRecordType::A, &response, None, Style::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("; CLIENT-SUBNET: 192.0.2.0/24/20\n"));
+    }
+
+    #[test]
+    fn test_it_writes_the_footer() {
+        let bytes = Packet::response(1).question("example.com", 1).build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        let stats = QueryStats {
+            server: "192.0.2.53:53".parse().unwrap(),
+            sent_at: UNIX_EPOCH + std::time::Duration::from_secs(1618317296),
+            elapsed: std::time::Duration::from_millis(23),
+            received: bytes.len(),
+            attempts: Vec::new(),
+        };
+
+        let mut out = Vec::new();
+        write_response(&mut out, "example.com", DnsRecordType::A, &response, Some(&stats), Style::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(";; Query time: 23 msec\n"));
+        assert!(out.contains(";; SERVER: 192.0.2.53#53(192.0.2.53)\n"));
+        assert!(out.contains(";; WHEN: 2021-04-13 12:34:56 UTC\n"));
+        assert!(out.ends_with(&format!(";; MSG SIZE  rcvd: {}\n", bytes.len())));
+    }
+
+    #[test]
+    fn test_it_writes_zonefile_records() {
+        let mut mx = vec![0, 10];
+        mx.extend(name("mail.example.com"));
+        let bytes = Packet::response(1)
+            .question("example.com", 255)
+            .answer("example.com", 1, 3600, vec![93, 184, 216, 34])
+            .answer("example.com", 15, 300, mx)
+            .answer("example.com", 16, 300, b"\x0bv=spf1 -all\x08say \"hi\"\x02\x01\\".to_vec())
+            .answer("_sip._udp.example.com", 33, 60, [vec![0, 10, 0, 5, 0x13, 0xc4], name("sip.example.com")].concat())
+            .build();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+
+        let mut out = Vec::new();
+        write_zonefile(&mut out, &response).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "example.com.\t3600\tIN\tA\t93.184.216.34\n",
+                "example.com.\t300\tIN\tMX\t10 mail.example.com.\n",
+                "example.com.\t300\tIN\tTXT\t\"v=spf1 -all\" \"say \\\"hi\\\"\" \"\\001\\\\\"\n",
+                "_sip._udp.example.com.\t60\tIN\tSRV\t10 5 5060 sip.example.com.\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::SocketAddr;
+
+use crate::capture::CaptureEntry;
+use crate::dns::DnsSocket;
+
+/// SocketPool keeps one connected socket per server, so that repeated
+/// queries to a server, such as a batch of lookups, reuse it instead of
+/// binding a new socket each time. Each socket hands out its own
+/// transaction ids, and only has one query in flight at a time, so ids
+/// don't collide.
+#[derive(Debug, Default)]
+pub struct SocketPool {
+    sockets: HashMap<SocketAddr, DnsSocket>,
+    /// Whether sockets keep their exchanges. See `DnsSocket::set_recording`.
+    recording: bool,
+}
+
+impl SocketPool {
+    pub fn new() -> Self {
+        SocketPool::default()
+    }
+
+    /// The socket for `server`, connecting one on first use.
+    pub fn get(&mut self, server: SocketAddr) -> io::Result<&mut DnsSocket> {
+        match self.sockets.entry(server) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let mut socket = DnsSocket::new(server)?;
+                socket.set_recording(self.recording);
+                Ok(entry.insert(socket))
+            }
+        }
+    }
+
+    /// Has every socket, including those opened later, keep its exchanges.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        for socket in self.sockets.values_mut() {
+            socket.set_recording(recording);
+        }
+    }
+
+    /// The exchanges every socket kept since the last call.
+    pub fn take_exchanges(&mut self) -> Vec<CaptureEntry> {
+        self.sockets.values_mut().flat_map(DnsSocket::take_exchanges).collect()
+    }
+
+    /// The number of sockets open.
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQueryType, DnsRecordType};
+    use crate::test_util::{mock_server, query_id, question, Packet};
+
+    #[test]
+    fn test_it_reuses_a_socket_per_server() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let mut pool = SocketPool::new();
+        let mut local_addrs = Vec::new();
+        for name in &["example.com", "example.org"] {
+            let socket = pool.get(server).unwrap();
+            socket
+                .query(name.to_string(), DnsQueryType::Recursive, DnsRecordType::A)
+                .unwrap();
+            local_addrs.push(socket.local_addr().unwrap());
+        }
+        assert_eq!(pool.len(), 1);
+        assert_eq!(local_addrs[0], local_addrs[1]);
+    }
+}
@@ -0,0 +1,111 @@
+/// The parameters of Punycode as IDNA uses it (RFC 3492, section 5).
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// The prefix that marks an A-label, a label holding Punycode.
+const ACE_PREFIX: &str = "xn--";
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+/// Decodes a Punycode string, the part of an A-label after `xn--`, or
+/// returns `None` if it isn't valid Punycode.
+pub fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut w = 1u32;
+        let mut k = BASE;
+        loop {
+            let digit = match digits.next()? {
+                c @ b'a'..=b'z' => c - b'a',
+                c @ b'A'..=b'Z' => c - b'A',
+                c @ b'0'..=b'9' => c - b'0' + 26,
+                _ => return None,
+            } as u32;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+            let t = if k <= bias {
+                TMIN
+            } else if k >= bias + TMAX {
+                TMAX
+            } else {
+                k - bias
+            };
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+/// Renders the A-labels of `name` as the Unicode U-labels they encode.
+/// A label that doesn't decode to something outside ASCII, as valid
+/// A-labels always do, is left as it is.
+pub fn to_unicode(name: &str) -> String {
+    name.split('.')
+        .map(|label| {
+            let encoded = match label.get(..ACE_PREFIX.len()) {
+                Some(prefix) if prefix.eq_ignore_ascii_case(ACE_PREFIX) => &label[ACE_PREFIX.len()..],
+                _ => return label.to_string(),
+            };
+            match decode(encoded) {
+                Some(decoded) if !decoded.is_ascii() => decoded,
+                _ => label.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_decodes_a_labels() {
+        assert_eq!(decode("caf-dma").unwrap(), "café");
+        assert_eq!(decode("bcher-kva").unwrap(), "bücher");
+        assert_eq!(to_unicode("xn--caf-dma.example.com"), "café.example.com");
+        assert_eq!(to_unicode("XN--bcher-kva.example"), "bücher.example");
+    }
+
+    #[test]
+    fn test_it_keeps_invalid_labels_raw() {
+        assert_eq!(decode("caf-d!a"), None);
+        assert_eq!(to_unicode("xn--caf-d!a.example.com"), "xn--caf-d!a.example.com");
+        // Decodes, but only to ASCII, which no real A-label does.
+        assert_eq!(to_unicode("xn--abc-.example.com"), "xn--abc-.example.com");
+        assert_eq!(to_unicode("xn--99999999999.example"), "xn--99999999999.example");
+    }
+}
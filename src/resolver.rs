@@ -0,0 +1,2093 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::dns::{
+    Attempt, DnsError, DnsMessage, DnsQueryType, DnsRecordType, DnsSocket, QueryOptions, QueryStats, RecordData, ResourceRecord,
+    RetryPolicy, Soa, DEFAULT_TIMEOUT,
+};
+use crate::capture::CaptureEntry;
+use crate::hosts::{Hosts, HOSTS_FILE};
+use crate::pool::SocketPool;
+
+/// ServerResponse is what one particular nameserver answered.
+#[derive(Debug)]
+pub struct ServerResponse {
+    pub server: SocketAddr,
+    pub response: Result<DnsMessage, DnsError>,
+    /// How the query went on the wire, if it was answered.
+    pub stats: Option<QueryStats>,
+}
+
+/// The parts of a record that must match for two servers to agree on it.
+/// The TTL is left out, since caches count it down independently.
+type RecordKey = (String, u16, u16, Vec<u8>);
+
+fn record_key(record: &ResourceRecord) -> RecordKey {
+    (
+        record.name().to_ascii_lowercase(),
+        record.record_type(),
+        record.class(),
+        record.data().to_vec(),
+    )
+}
+
+fn answer_set(response: &DnsMessage) -> BTreeSet<RecordKey> {
+    response.answers().iter().map(record_key).collect()
+}
+
+/// Comparison holds every server's answer to the same question, for
+/// spotting poisoned caches or split-horizon setups.
+#[derive(Debug)]
+pub struct Comparison {
+    pub responses: Vec<ServerResponse>,
+    /// The records that every server returned.
+    common: BTreeSet<RecordKey>,
+}
+
+impl Comparison {
+    fn new(responses: Vec<ServerResponse>) -> Self {
+        let mut sets = responses
+            .iter()
+            .map(|r| r.response.as_ref().map(answer_set).unwrap_or_default());
+        let first = sets.next().unwrap_or_default();
+        let common = sets.fold(first, |common, set| &common & &set);
+        Comparison { responses, common }
+    }
+
+    /// Whether every server answered, and with the same set of records.
+    pub fn agree(&self) -> bool {
+        self.responses.iter().all(|r| match &r.response {
+            Ok(response) => answer_set(response) == self.common,
+            Err(_) => false,
+        })
+    }
+
+    /// Whether `record` is missing from at least one server's answers.
+    pub fn differs(&self, record: &ResourceRecord) -> bool {
+        !self.common.contains(&record_key(record))
+    }
+}
+
+/// ZoneSerial is one authoritative server's view of a zone, as found by
+/// `Resolver::nssearch`.
+#[derive(Debug)]
+pub struct ZoneSerial {
+    /// The nameserver's name, from the zone's NS records.
+    pub nameserver: String,
+    /// Where the nameserver was queried, if its name could be resolved.
+    pub address: Option<SocketAddr>,
+    /// The SOA the nameserver returned for the zone.
+    pub soa: Result<Soa, DnsError>,
+}
+
+/// DelegationProblem is one way a zone's delegation and the zone itself
+/// disagree, as `Resolver::check_delegation` finds them.
+#[derive(Debug, PartialEq)]
+pub enum DelegationProblem {
+    /// The parent delegates to a nameserver the zone does not list.
+    OnlyAtParent(String),
+    /// The zone lists a nameserver the parent does not delegate to.
+    OnlyAtChild(String),
+    /// A nameserver inside the zone, which can only be found through
+    /// glue, has none at the parent.
+    MissingGlue(String),
+    /// A delegated nameserver that does not answer authoritatively for
+    /// the zone.
+    Lame { nameserver: String, reason: String },
+}
+
+impl fmt::Display for DelegationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelegationProblem::OnlyAtParent(ns) => write!(f, "{} is delegated to but not listed by the zone", ns),
+            DelegationProblem::OnlyAtChild(ns) => write!(f, "{} is listed by the zone but not delegated to", ns),
+            DelegationProblem::MissingGlue(ns) => write!(f, "{} has no glue at the parent", ns),
+            DelegationProblem::Lame { nameserver, reason } => write!(f, "{} is lame: {}", nameserver, reason),
+        }
+    }
+}
+
+/// DelegationCheck compares the NS records a zone's parent delegates with
+/// to those the zone's own servers give.
+#[derive(Debug)]
+pub struct DelegationCheck {
+    pub zone: String,
+    /// The nameservers in the parent's delegation.
+    pub parent: Vec<String>,
+    /// The nameservers the delegated servers list, together.
+    pub child: Vec<String>,
+    pub problems: Vec<DelegationProblem>,
+}
+
+/// The text of a TXT record, or of the legacy SPF record that shares its
+/// format. A record split into several character-strings, as long ones
+/// have to be, is joined back together without separators (RFC 7208,
+/// section 3.3).
+fn txt_text(record: &ResourceRecord) -> Option<String> {
+    match record.rdata() {
+        RecordData::Txt(strings) | RecordData::Spf(strings) => Some(strings.concat()),
+        _ => None,
+    }
+}
+
+/// Finds the TXT answer whose text starts with the version tag `tag`,
+/// such as `v=spf1`.
+fn policy_record(response: &DnsMessage, tag: &str) -> Option<String> {
+    response.answers().iter().filter_map(txt_text).find(|text| {
+        text.get(..tag.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(tag))
+            && matches!(text[tag.len()..].chars().next(), None | Some(' ') | Some(';'))
+    })
+}
+
+/// A name lowercased and without its trailing dot, for comparing names.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn label_count(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+/// Whether `name` is `zone` or lies below it. Both must be normalized,
+/// and the root zone is the empty name.
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// The normalized targets of the NS records for `zone` among `records`.
+fn ns_targets<'a>(records: impl Iterator<Item = &'a ResourceRecord>, zone: &str) -> Vec<String> {
+    records
+        .filter(|record| record.record_type() == DnsRecordType::NS.value() && normalize(record.name()) == zone)
+        .filter_map(|record| record.target_name())
+        .map(|nameserver| normalize(&nameserver))
+        .collect()
+}
+
+/// The types `Resolver::resolve_all` asks for.
+pub const RESOLVE_ALL_TYPES: [DnsRecordType; 6] = [
+    DnsRecordType::A,
+    DnsRecordType::AAAA,
+    DnsRecordType::MX,
+    DnsRecordType::TXT,
+    DnsRecordType::NS,
+    DnsRecordType::SOA,
+];
+
+/// RecordSets is what `Resolver::resolve_all` found for each type, or
+/// why it found nothing.
+pub type RecordSets = HashMap<DnsRecordType, Result<Vec<ResourceRecord>, DnsError>>;
+
+/// The most referrals followed for one iterative lookup, counting those
+/// followed to find nameservers that came without glue.
+pub const MAX_REFERRALS: usize = 16;
+
+/// Delegation is a referral to the nameservers of a zone.
+struct Delegation {
+    zone: String,
+    nameservers: Vec<String>,
+    /// The addresses given for the nameservers in the additional section.
+    glue: Vec<IpAddr>,
+}
+
+/// If `response` refers a query from `zone` on to the nameservers of a
+/// zone closer to `target`, that delegation.
+fn referral(response: &DnsMessage, zone: &str, target: &str) -> Option<Delegation> {
+    if response.rcode() != 0 || !response.answers().is_empty() {
+        return None;
+    }
+    let ns_records: Vec<&ResourceRecord> = response
+        .authority()
+        .iter()
+        .filter(|record| record.record_type() == DnsRecordType::NS.value())
+        .collect();
+    let child = normalize(ns_records.first()?.name());
+    if !in_zone(target, &child) || label_count(&child) <= label_count(zone) {
+        return None;
+    }
+    let nameservers: Vec<String> = ns_records
+        .iter()
+        .filter(|record| normalize(record.name()) == child)
+        .filter_map(|record| record.target_name())
+        .map(|nameserver| normalize(&nameserver))
+        .collect();
+    let glue = response
+        .additional()
+        .iter()
+        .filter(|record| nameservers.contains(&normalize(record.name())))
+        .filter_map(|record| record.ip_address())
+        .collect();
+    Some(Delegation {
+        zone: child,
+        nameservers,
+        glue,
+    })
+}
+
+/// The longest a negative answer is cached, however long its SOA allows;
+/// RFC 2308 recommends one to three hours.
+pub const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// How long `connect_service` waits on each address before trying the
+/// next one.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Orders addresses the way Happy Eyeballs tries them (RFC 8305, section
+/// 4): alternating between families, starting with IPv6.
+fn happy_eyeballs_order(v6: Vec<IpAddr>, v4: Vec<IpAddr>) -> Vec<IpAddr> {
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// A lookup failure as an `io::Error`, for APIs that return those.
+fn into_io_error(e: DnsError) -> io::Error {
+    match e {
+        DnsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// The key negative answers are cached under: the lowercased name and
+/// the type that was asked for.
+type CacheKey = (String, DnsRecordType);
+
+fn cache_key(name: &str, record: DnsRecordType) -> CacheKey {
+    (normalize(name), record)
+}
+
+/// A stand-in for `e` where it has to be reported more than once, as
+/// `DnsError` cannot be cloned. It keeps the message, and the kind of an
+/// I/O error.
+fn copy_error(e: &DnsError) -> DnsError {
+    let kind = match e {
+        DnsError::Io(e) => e.kind(),
+        _ => io::ErrorKind::Other,
+    };
+    DnsError::Io(io::Error::new(kind, e.to_string()))
+}
+
+/// ResolverSettings is everything a `Resolver` is built from, so callers
+/// of the library can set one up without going through `AppConfig`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolverSettings {
+    /// The nameservers, in the order they are failed over to.
+    pub servers: Vec<SocketAddr>,
+    /// Whether to start each query at the next server (`options rotate`).
+    pub rotate: bool,
+    pub options: QueryOptions,
+    /// A fixed transaction id for every query, as `--qid` sets.
+    pub transaction_id: Option<u16>,
+    /// The most time one query may take across every server.
+    pub budget: Option<Duration>,
+    /// How long each server is waited on, `DEFAULT_TIMEOUT` unless changed.
+    pub timeout: Duration,
+    /// How often each server is sent a query it leaves unanswered.
+    pub retry: RetryPolicy,
+    /// Domains `resolve` appends to relative names, as resolv.conf's
+    /// `search` line lists them.
+    pub search: Vec<String>,
+    /// How many dots a name needs for `resolve` to try it as it is before
+    /// the search domains (`options ndots`).
+    pub ndots: usize,
+    pub address_queries: AddressQueries,
+    /// Where `lookup_ip` looks for addresses, in order, as resolv.conf's
+    /// `lookup` line says. DNS alone when empty.
+    pub lookup: Vec<LookupSource>,
+    /// The host table `LookupSource::File` reads.
+    pub hosts_file: PathBuf,
+}
+
+/// LookupSource is one of the places resolv.conf's `lookup` line (as on
+/// OpenBSD) lists for finding a host's addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LookupSource {
+    /// Ask the nameservers (`bind`).
+    Bind,
+    /// Read the host table (`file`).
+    File,
+}
+
+/// AddressQueries is how `lookup_ip` sends its A and AAAA queries, which
+/// resolv.conf's `single-request` options control.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AddressQueries {
+    /// Both at once, from the same socket.
+    #[default]
+    Parallel,
+    /// One after the other (`single-request`).
+    Sequential,
+    /// One after the other, with a new socket for the second
+    /// (`single-request-reopen`).
+    SequentialReopen,
+    /// AAAA alone, and A only when the name has no AAAA records, for
+    /// those who would rather use IPv6 whenever they can.
+    Ipv6First,
+}
+
+impl Default for ResolverSettings {
+    fn default() -> Self {
+        ResolverSettings {
+            servers: Vec::new(),
+            rotate: false,
+            options: QueryOptions::default(),
+            transaction_id: None,
+            budget: None,
+            timeout: DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+            search: Vec::new(),
+            ndots: 1,
+            address_queries: AddressQueries::default(),
+            lookup: Vec::new(),
+            hosts_file: PathBuf::from(HOSTS_FILE),
+        }
+    }
+}
+
+/// The names `resolve` tries for `name`, in order (resolv.conf(5)). A
+/// name ending in a dot is only ever tried as it is.
+fn search_candidates(name: &str, search: &[String], ndots: usize) -> Vec<String> {
+    if name.ends_with('.') || search.is_empty() {
+        return vec![name.to_string()];
+    }
+    let suffixed = search
+        .iter()
+        .map(|domain| format!("{}.{}", name, domain.trim_end_matches('.')));
+    if name.matches('.').count() >= ndots {
+        std::iter::once(name.to_string()).chain(suffixed).collect()
+    } else {
+        suffixed.chain(std::iter::once(name.to_string())).collect()
+    }
+}
+
+/// Resolver sends queries to a list of nameservers, failing over to the
+/// next one in order when a server does not answer.
+#[derive(Debug)]
+pub struct Resolver {
+    /// One socket per nameserver, reused across queries.
+    sockets: Vec<DnsSocket>,
+    /// Whether to start each query at the next server (`options rotate`).
+    rotate: bool,
+    /// The index of the server the next query starts with.
+    next_start: usize,
+    /// The stats of the last query answered by `query`.
+    last_stats: Option<QueryStats>,
+    /// The raw bytes of the last answer to `query`.
+    last_response: Vec<u8>,
+    /// The raw bytes of the query that got that answer.
+    last_request: Vec<u8>,
+    /// The servers the last call to `query` tried, whether or not one of
+    /// them answered.
+    last_trace: Vec<Attempt>,
+    /// NXDOMAIN and NODATA answers, with when they expire.
+    negative_cache: HashMap<CacheKey, (Instant, DnsMessage)>,
+    /// Sockets for servers other than the configured nameservers, such
+    /// as the authoritative servers found by `nssearch`.
+    pool: SocketPool,
+    /// The most time one call to `query` may take across every server.
+    budget: Option<Duration>,
+    /// How long each server is waited on.
+    timeout: Duration,
+    /// The search list and ndots threshold `resolve` applies.
+    search: Vec<String>,
+    ndots: usize,
+    /// How `lookup_ip` sequences its queries.
+    address_queries: AddressQueries,
+    /// Where `lookup_ip` looks, and the host table it may look in.
+    lookup: Vec<LookupSource>,
+    hosts: Hosts,
+}
+
+impl Resolver {
+    pub fn new<T: ToSocketAddrs>(servers: &[T], rotate: bool) -> Result<Self, DnsError> {
+        let sockets = servers
+            .iter()
+            .map(DnsSocket::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Resolver {
+            sockets,
+            rotate,
+            next_start: 0,
+            last_stats: None,
+            last_response: Vec::new(),
+            last_request: Vec::new(),
+            last_trace: Vec::new(),
+            negative_cache: HashMap::new(),
+            pool: SocketPool::new(),
+            budget: None,
+            timeout: DEFAULT_TIMEOUT,
+            search: Vec::new(),
+            ndots: 1,
+            address_queries: AddressQueries::default(),
+            lookup: Vec::new(),
+            hosts: Hosts::default(),
+        })
+    }
+
+    /// Builds a resolver with everything in `settings` applied.
+    pub fn from_settings(settings: &ResolverSettings) -> Result<Self, DnsError> {
+        let mut resolver = Resolver::new(&settings.servers, settings.rotate)?;
+        resolver.set_options(settings.options);
+        resolver.set_transaction_id(settings.transaction_id);
+        resolver.set_timeout(settings.timeout)?;
+        resolver.set_retry_policy(settings.retry);
+        resolver.set_budget(settings.budget)?;
+        resolver.search = settings.search.clone();
+        resolver.ndots = settings.ndots;
+        resolver.address_queries = settings.address_queries;
+        resolver.lookup = settings.lookup.clone();
+        if resolver.lookup.contains(&LookupSource::File) {
+            // A missing host table just has nothing in it.
+            resolver.hosts = Hosts::load(&settings.hosts_file).unwrap_or_else(|e| {
+                debug!("not reading {}: {}", settings.hosts_file.display(), e);
+                Hosts::default()
+            });
+        }
+        Ok(resolver)
+    }
+
+    /// Looks up `name` the way a stub resolver does, trying it with each
+    /// of the search domains until one of them exists. Unlike `query`,
+    /// which asks for exactly the name given, as dig does, the answer may
+    /// be for a longer name.
+    pub fn resolve(&mut self, name: &str, record: DnsRecordType) -> Result<DnsMessage, DnsError> {
+        let mut candidates = search_candidates(name, &self.search, self.ndots).into_iter().peekable();
+        loop {
+            let candidate = candidates.next().unwrap();
+            let response = self.query(&candidate, DnsQueryType::Recursive, record)?;
+            if !response.is_nxdomain() || candidates.peek().is_none() {
+                return Ok(response);
+            }
+            debug!("{} does not exist, trying the next search domain", candidate);
+        }
+    }
+
+    /// Like `query`, but A and AAAA lookups follow the `lookup` order,
+    /// answering from the host table when it comes first or DNS fails or
+    /// has no addresses. Nothing goes on the wire for a host table answer,
+    /// so it has no stats.
+    pub fn lookup(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        record: DnsRecordType,
+    ) -> Result<DnsMessage, DnsError> {
+        if self.lookup.is_empty() || !matches!(record, DnsRecordType::A | DnsRecordType::AAAA) {
+            return self.query(hostname, query, record);
+        }
+        let mut from_dns = None;
+        for source in self.lookup.clone() {
+            match source {
+                LookupSource::File => {
+                    if let Some(answer) = self.hosts.answer(hostname, record) {
+                        debug!("answering {} {} from the host table", hostname, record);
+                        self.last_stats = None;
+                        self.last_trace.clear();
+                        self.last_request.clear();
+                        self.last_response = answer.to_bytes();
+                        return Ok(answer);
+                    }
+                }
+                LookupSource::Bind => match self.query(hostname, query, record) {
+                    Ok(response) if !response.answers().is_empty() => return Ok(response),
+                    result => {
+                        if let Err(e) = &result {
+                            debug!("{} {} failed in DNS, trying the other sources: {}", hostname, record, e);
+                        }
+                        from_dns = Some(result);
+                    }
+                },
+            }
+        }
+        from_dns.unwrap_or(Err(DnsError::MissingRecord(record)))
+    }
+
+    /// The IPv4 and IPv6 addresses of `name`, from the first of the
+    /// `lookup` sources that has any. In DNS the search list is tried as
+    /// `resolve` does; the host table only ever has the name as given.
+    /// Should DNS fail, its error is returned only if no other source has
+    /// an address.
+    pub fn lookup_ip(&mut self, name: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let sources = if self.lookup.is_empty() {
+            vec![LookupSource::Bind]
+        } else {
+            self.lookup.clone()
+        };
+        let mut dns_error = None;
+        for source in sources {
+            let addresses = match source {
+                LookupSource::File => self.hosts.lookup(name),
+                LookupSource::Bind => self.lookup_ip_in_dns(name).unwrap_or_else(|e| {
+                    debug!("looking up {} in DNS failed, trying the other sources: {}", name, e);
+                    dns_error = Some(e);
+                    Vec::new()
+                }),
+            };
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        dns_error.map_or(Ok(Vec::new()), Err)
+    }
+
+    fn lookup_ip_in_dns(&mut self, name: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let mut candidates = search_candidates(name, &self.search, self.ndots).into_iter().peekable();
+        loop {
+            let candidate = candidates.next().unwrap();
+            let responses = self.query_addresses(&candidate)?;
+            if !responses[0].is_nxdomain() || candidates.peek().is_none() {
+                let answers = responses.iter().flat_map(|r| r.answers());
+                return Ok(answers.filter_map(|r| r.ip_address()).collect());
+            }
+            debug!("{} does not exist, trying the next search domain", candidate);
+        }
+    }
+
+    /// Looks up every type in `RESOLVE_ALL_TYPES` for `name`, for an
+    /// overview of it. The queries all go out at once, failing over
+    /// between servers as `query` does until one answers any of them.
+    /// Only the types left unanswered are asked again, one at a time, and
+    /// a type whose query still fails gets the error in its place.
+    pub fn resolve_all(&mut self, name: &str) -> RecordSets {
+        let query = DnsQueryType::Recursive;
+        let mut sets = RecordSets::new();
+        let mut pending = Vec::new();
+        for &record in &RESOLVE_ALL_TYPES {
+            match self.cached_negative(&cache_key(name, record)) {
+                Some(response) => {
+                    sets.insert(record, Ok(response.answers().to_vec()));
+                }
+                None => pending.push(record),
+            }
+        }
+        if pending.is_empty() {
+            self.last_stats = None;
+            self.last_trace.clear();
+            return sets;
+        }
+
+        let what = format!("{} {:?}", name, pending);
+        let mut retry = Vec::new();
+        match self.failover(&what, |socket| socket.query_many(name, query, &pending)) {
+            Ok(responses) => {
+                for (&record, response) in pending.iter().zip(responses) {
+                    match response {
+                        Ok(response) => {
+                            self.cache_negative(cache_key(name, record), &response);
+                            sets.insert(record, Ok(response.answers().to_vec()));
+                        }
+                        Err(e) => {
+                            debug!("{} {} went unanswered, asking again: {}", name, record, e);
+                            retry.push(record);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                // Every server has already failed every type, so asking
+                // again one at a time would only fail more slowly.
+                for &record in &pending {
+                    sets.insert(record, Err(copy_error(&e)));
+                }
+            }
+        }
+        for record in retry {
+            let response = self.query(name, query, record);
+            sets.insert(record, response.map(|response| response.answers().to_vec()));
+        }
+        sets
+    }
+
+    /// Looks up the A and AAAA records of `name`, sequenced as
+    /// `AddressQueries` says, returning the responses in the order their
+    /// addresses should be used.
+    fn query_addresses(&mut self, name: &str) -> Result<Vec<DnsMessage>, DnsError> {
+        let query = DnsQueryType::Recursive;
+        match self.address_queries {
+            AddressQueries::Parallel => {
+                let records = [DnsRecordType::A, DnsRecordType::AAAA];
+                // A cached answer for either type leaves only one to send.
+                if records.iter().any(|&record| self.cached_negative(&cache_key(name, record)).is_some()) {
+                    let a = self.query(name, query, DnsRecordType::A)?;
+                    return Ok(vec![a, self.query(name, query, DnsRecordType::AAAA)?]);
+                }
+                let what = format!("{} A+AAAA", name);
+                let responses = self.failover(&what, |socket| socket.query_pair(name, query, records))?;
+                for (&record, response) in records.iter().zip(&responses) {
+                    self.cache_negative(cache_key(name, record), response);
+                }
+                Ok(responses.to_vec())
+            }
+            AddressQueries::Sequential => {
+                let a = self.query(name, query, DnsRecordType::A)?;
+                Ok(vec![a, self.query(name, query, DnsRecordType::AAAA)?])
+            }
+            AddressQueries::SequentialReopen => {
+                let a = self.query(name, query, DnsRecordType::A)?;
+                for socket in &mut self.sockets {
+                    socket.reopen()?;
+                }
+                Ok(vec![a, self.query(name, query, DnsRecordType::AAAA)?])
+            }
+            AddressQueries::Ipv6First => {
+                let aaaa = self.query(name, query, DnsRecordType::AAAA)?;
+                // A CNAME to a name without AAAA records is no answer
+                // either, so this looks for addresses rather than records.
+                if aaaa.is_nxdomain() || aaaa.answers().iter().any(|r| r.ip_address().is_some()) {
+                    return Ok(vec![aaaa]);
+                }
+                debug!("{} has no AAAA records, asking for A", name);
+                Ok(vec![aaaa, self.query(name, query, DnsRecordType::A)?])
+            }
+        }
+    }
+
+    pub fn query(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        record: DnsRecordType,
+    ) -> Result<DnsMessage, DnsError> {
+        let key = cache_key(hostname, record);
+        if let Some(response) = self.cached_negative(&key) {
+            debug!("answering {} {} from the negative cache", hostname, record);
+            self.last_stats = None;
+            self.last_trace.clear();
+            return Ok(response);
+        }
+
+        let what = format!("{} {}", hostname, record);
+        let response = self.failover(&what, |socket| socket.query(hostname.to_string(), query, record))?;
+        self.cache_negative(key, &response);
+        Ok(response)
+    }
+
+    /// Runs `send` against each server in turn, starting from the next
+    /// one in rotation, until one succeeds, within the budget. `what`
+    /// names the query in the log.
+    fn failover<T, F>(&mut self, what: &str, mut send: F) -> Result<T, DnsError>
+    where
+        F: FnMut(&mut DnsSocket) -> Result<T, DnsError>,
+    {
+        let count = self.sockets.len();
+        if count == 0 {
+            return Err(DnsError::NoServers);
+        }
+        let start = self.next_start;
+        if self.rotate {
+            self.next_start = (self.next_start + 1) % count;
+        }
+        let deadline = self.budget.map(|budget| Instant::now() + budget);
+
+        let mut last_error = None;
+        self.last_trace.clear();
+        for i in 0..count {
+            let socket = &mut self.sockets[(start + i) % count];
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    debug!("query for {} ran out of time", what);
+                    return Err(DnsError::Timeout);
+                }
+                socket.set_timeout(remaining.min(self.timeout))?;
+            }
+            // Short of the last server, failing over is quicker than
+            // falling back to a query without EDNS.
+            let server_deadline = if i + 1 < count {
+                socket.time_allowed().map(|allowed| Instant::now() + allowed)
+            } else {
+                None
+            };
+            socket.set_deadline([deadline, server_deadline].iter().flatten().min().copied());
+            let started = Instant::now();
+            let result = send(socket);
+            socket.set_deadline(None);
+            let attempt = Attempt {
+                server: socket.server(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                elapsed: started.elapsed(),
+            };
+            debug!(
+                "{} to {}: {} after {:?}",
+                what,
+                attempt.server,
+                attempt.error.as_deref().unwrap_or("answered"),
+                attempt.elapsed
+            );
+            self.last_trace.push(attempt);
+            match result {
+                Ok(response) => {
+                    let attempts = self.last_trace.clone();
+                    self.last_stats = socket.last_stats().cloned().map(|stats| QueryStats { attempts, ..stats });
+                    self.last_response = socket.last_response().to_vec();
+                    self.last_request = socket.last_request().to_vec();
+                    return Ok(response);
+                }
+                Err(e) => {
+                    debug!("query for {} to {} failed, trying next server: {}", what, socket.server(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(DnsError::Timeout),
+            _ => Err(last_error.unwrap()),
+        }
+    }
+
+    /// Sends a prebuilt query as it is, choosing and failing over between
+    /// servers, within the budget, as `query` does. See
+    /// `DnsSocket::query_raw`.
+    pub fn query_raw(&mut self, request: &[u8]) -> Result<DnsMessage, DnsError> {
+        self.failover("raw query", |socket| socket.query_raw(request))
+    }
+
+    /// Caches `response` under `key` if it is a negative answer.
+    fn cache_negative(&mut self, key: CacheKey, response: &DnsMessage) {
+        if let Some(ttl) = response.negative_ttl() {
+            let ttl = Duration::from_secs(ttl.into()).min(MAX_NEGATIVE_TTL);
+            self.negative_cache.insert(key, (Instant::now() + ttl, response.clone()));
+        }
+    }
+
+    /// A cached negative answer for `key`, unless it has expired.
+    fn cached_negative(&mut self, key: &CacheKey) -> Option<DnsMessage> {
+        let (expires, response) = self.negative_cache.get(key)?;
+        if Instant::now() < *expires {
+            return Some(response.clone());
+        }
+        self.negative_cache.remove(key);
+        None
+    }
+
+    /// Limits how long each call to `query` may take in total, across
+    /// failing over to every server, after which it fails with
+    /// `DnsError::Timeout`. Without a budget, each server gets the socket
+    /// timeout in full.
+    pub fn set_budget(&mut self, budget: Option<Duration>) -> io::Result<()> {
+        self.budget = budget;
+        // Undo whatever shorter timeouts the last budget left behind.
+        self.set_timeout(self.timeout)
+    }
+
+    /// Sets how long each server is waited on, `DEFAULT_TIMEOUT` unless
+    /// changed.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        for socket in &mut self.sockets {
+            socket.set_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Sets how each server retries an unanswered query. Under a budget,
+    /// no attempt waits past what is left of it.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        for socket in &mut self.sockets {
+            socket.set_retry_policy(retry);
+        }
+    }
+
+    /// Keeps every exchange with any server, failed ones included, for
+    /// `take_exchanges` to collect into a capture log.
+    pub fn set_recording(&mut self, recording: bool) {
+        for socket in &mut self.sockets {
+            socket.set_recording(recording);
+        }
+        self.pool.set_recording(recording);
+    }
+
+    /// The exchanges kept since the last call, in the order they were
+    /// sent.
+    pub fn take_exchanges(&mut self) -> Vec<CaptureEntry> {
+        let mut exchanges: Vec<CaptureEntry> = self.sockets.iter_mut().flat_map(DnsSocket::take_exchanges).collect();
+        exchanges.extend(self.pool.take_exchanges());
+        exchanges.sort_by_key(|entry| entry.timestamp_ms);
+        exchanges
+    }
+
+    /// Applies `options` to the queries sent to every nameserver.
+    pub fn set_options(&mut self, options: QueryOptions) {
+        for socket in &mut self.sockets {
+            socket.set_options(options);
+        }
+    }
+
+    /// Pins the transaction id of every query to the configured servers,
+    /// as `--qid` does, or goes back to random ids on `None`.
+    pub fn set_transaction_id(&mut self, trans_id: Option<u16>) {
+        for socket in &mut self.sockets {
+            socket.set_transaction_id(trans_id);
+        }
+    }
+
+    /// The stats of the last query answered by `query`, including which
+    /// server it failed over to.
+    pub fn last_stats(&self) -> Option<&QueryStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// The raw bytes of the last answer to `query`, for saving as a test
+    /// fixture.
+    pub fn last_response(&self) -> &[u8] {
+        &self.last_response
+    }
+
+    /// The raw bytes of the query `last_response` answered.
+    pub fn last_request(&self) -> &[u8] {
+        &self.last_request
+    }
+
+    /// The servers the last call to `query` tried, in order, with how
+    /// each one went. Unlike `last_stats`, this is kept when every server
+    /// failed too. It's empty for an answer from the negative cache.
+    pub fn last_trace(&self) -> &[Attempt] {
+        &self.last_trace
+    }
+
+    /// Sends the same query to every nameserver in turn, collecting each
+    /// one's response or failure.
+    pub fn query_all(
+        &mut self,
+        hostname: &str,
+        query: DnsQueryType,
+        record: DnsRecordType,
+    ) -> Vec<ServerResponse> {
+        self.sockets
+            .iter_mut()
+            .map(|socket| {
+                let response = socket.query(hostname.to_string(), query, record);
+                ServerResponse {
+                    server: socket.server(),
+                    stats: response.as_ref().ok().and(socket.last_stats().cloned()),
+                    response,
+                }
+            })
+            .collect()
+    }
+
+    /// Asks every nameserver the same question and compares the answers.
+    pub fn compare(&mut self, hostname: &str, query: DnsQueryType, record: DnsRecordType) -> Comparison {
+        Comparison::new(self.query_all(hostname, query, record))
+    }
+
+    /// Looks up the SPF policy published for `domain`, if there is one.
+    /// Policies are published as TXT, but zones that still only have one
+    /// of the obsolete SPF type are checked for that as well.
+    pub fn lookup_spf(&mut self, domain: &str) -> Result<Option<String>, DnsError> {
+        let response = self.query(domain, DnsQueryType::Recursive, DnsRecordType::TXT)?;
+        if let Some(policy) = policy_record(&response, "v=spf1") {
+            return Ok(Some(policy));
+        }
+        let response = self.query(domain, DnsQueryType::Recursive, DnsRecordType::SPF)?;
+        Ok(policy_record(&response, "v=spf1"))
+    }
+
+    /// Looks up the DMARC policy published for `domain`, which lives at
+    /// `_dmarc.<domain>` (RFC 7489).
+    pub fn lookup_dmarc(&mut self, domain: &str) -> Result<Option<String>, DnsError> {
+        let name = format!("_dmarc.{}", domain);
+        let response = self.query(&name, DnsQueryType::Recursive, DnsRecordType::TXT)?;
+        Ok(policy_record(&response, "v=DMARC1"))
+    }
+
+    /// Looks up the DKIM key record for `selector` at
+    /// `<selector>._domainkey.<domain>` (RFC 6376), joining its split
+    /// strings into the full record.
+    pub fn lookup_dkim(&mut self, selector: &str, domain: &str) -> Result<Option<String>, DnsError> {
+        let name = format!("{}._domainkey.{}", selector, domain);
+        let response = self.query(&name, DnsQueryType::Recursive, DnsRecordType::TXT)?;
+        Ok(response.answers().iter().find_map(txt_text))
+    }
+
+    /// Looks up the current SOA serial of `zone`, which is all that
+    /// monitoring for replication lag needs.
+    pub fn soa_serial(&mut self, zone: &str) -> Result<u32, DnsError> {
+        let response = self.query(zone, DnsQueryType::Recursive, DnsRecordType::SOA)?;
+        response
+            .answers()
+            .iter()
+            .find_map(|record| record.soa())
+            .map(|soa| soa.serial)
+            .ok_or(DnsError::MissingRecord(DnsRecordType::SOA))
+    }
+
+    /// Resolves `name` and opens a TCP connection to it on `port`, trying
+    /// its addresses in Happy Eyeballs order, one at a time, until one
+    /// accepts. The error is the last address's if none do.
+    pub fn connect_service(&mut self, name: &str, port: u16) -> io::Result<TcpStream> {
+        let mut addresses = |record| -> io::Result<Vec<IpAddr>> {
+            let response = self.query(name, DnsQueryType::Recursive, record).map_err(into_io_error)?;
+            // Only the addresses at the end of any CNAME or DNAME chain.
+            let canonical = response.canonical_name(name);
+            Ok(response
+                .answers()
+                .iter()
+                .filter(|r| r.name().eq_ignore_ascii_case(&canonical))
+                .filter_map(|r| r.ip_address())
+                .collect())
+        };
+        // One family failing to resolve still leaves the other to try.
+        let v6 = addresses(DnsRecordType::AAAA);
+        let v4 = addresses(DnsRecordType::A);
+        let (v6, v4) = match (v6, v4) {
+            (Err(e), Err(_)) => return Err(e),
+            (v6, v4) => (v6.unwrap_or_default(), v4.unwrap_or_default()),
+        };
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", name));
+        for ip in happy_eyeballs_order(v6, v4) {
+            let address = SocketAddr::new(ip, port);
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("connecting to {} failed: {}", address, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Finds the nameservers for `zone` and asks each of them directly,
+    /// on `port`, for the zone's SOA. Comparing the serials shows whether
+    /// a secondary has fallen behind. A failing server is reported in its
+    /// `ZoneSerial` rather than aborting the search.
+    pub fn nssearch(&mut self, zone: &str, port: u16) -> Result<Vec<ZoneSerial>, DnsError> {
+        let response = self.query(zone, DnsQueryType::Recursive, DnsRecordType::NS)?;
+        let nameservers: Vec<String> = response
+            .answers()
+            .iter()
+            .filter_map(|record| record.target_name())
+            .collect();
+
+        let mut serials = Vec::with_capacity(nameservers.len());
+        for nameserver in nameservers {
+            let address = self
+                .nameserver_address(&response, &nameserver)
+                .map(|ip| SocketAddr::new(ip, port));
+            let soa = match address {
+                Some(address) => self.query_soa(address, zone),
+                None => Err(DnsError::InvalidServer(nameserver.clone())),
+            };
+            serials.push(ZoneSerial {
+                nameserver,
+                address,
+                soa,
+            });
+        }
+        Ok(serials)
+    }
+
+    /// Checks the delegation of `zone`: asks the parent zone's servers for
+    /// its NS records, then asks each nameserver they delegate to, on
+    /// `port`, for the NS records the zone itself has. Differences between
+    /// the two sets, lame servers and missing glue are reported as
+    /// problems rather than errors.
+    pub fn check_delegation(&mut self, zone: &str, port: u16) -> Result<DelegationCheck, DnsError> {
+        let zone = normalize(zone);
+        let parent = zone.split_once('.').map(|(_, parent)| parent).unwrap_or("");
+        let response = self.query(parent, DnsQueryType::Recursive, DnsRecordType::NS)?;
+        let parent_servers: Vec<SocketAddr> = response
+            .answers()
+            .iter()
+            .filter_map(|record| record.target_name())
+            .filter_map(|nameserver| self.nameserver_address(&response, &nameserver))
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        let delegation = self.ask_any(&parent_servers, &zone, DnsRecordType::NS)?;
+        // A parent serving the zone too answers rather than refers.
+        let parent_ns = ns_targets(delegation.answers().iter().chain(delegation.authority()), &zone);
+        if parent_ns.is_empty() {
+            return Err(DnsError::MissingRecord(DnsRecordType::NS));
+        }
+
+        let mut child_ns: Vec<String> = Vec::new();
+        let mut problems = Vec::new();
+        for nameserver in &parent_ns {
+            let glue = delegation
+                .additional()
+                .iter()
+                .any(|record| normalize(record.name()) == *nameserver && record.ip_address().is_some());
+            if !glue && in_zone(nameserver, &zone) {
+                problems.push(DelegationProblem::MissingGlue(nameserver.clone()));
+            }
+            let listed = match self.nameserver_address(&delegation, nameserver) {
+                Some(ip) => self.pool.get(SocketAddr::new(ip, port)).map_err(DnsError::from).and_then(|socket| {
+                    socket.query(zone.clone(), DnsQueryType::Iterative, DnsRecordType::NS)
+                }),
+                None => Err(DnsError::InvalidServer(nameserver.clone())),
+            };
+            match listed {
+                Ok(response) if response.flags().aa => {
+                    for ns in ns_targets(response.answers().iter(), &zone) {
+                        if !child_ns.contains(&ns) {
+                            child_ns.push(ns);
+                        }
+                    }
+                }
+                Ok(_) => problems.push(DelegationProblem::Lame {
+                    nameserver: nameserver.clone(),
+                    reason: "not authoritative for the zone".to_string(),
+                }),
+                Err(e) => problems.push(DelegationProblem::Lame {
+                    nameserver: nameserver.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        // Without any authoritative answer there is nothing to compare.
+        if !child_ns.is_empty() {
+            for ns in parent_ns.iter().filter(|ns| !child_ns.contains(ns)) {
+                problems.push(DelegationProblem::OnlyAtParent(ns.clone()));
+            }
+            for ns in child_ns.iter().filter(|ns| !parent_ns.contains(ns)) {
+                problems.push(DelegationProblem::OnlyAtChild(ns.clone()));
+            }
+        }
+        Ok(DelegationCheck {
+            zone,
+            parent: parent_ns,
+            child: child_ns,
+            problems,
+        })
+    }
+
+    /// Asks a single server, without recursion, for the SOA of `zone`.
+    fn query_soa(&mut self, server: SocketAddr, zone: &str) -> Result<Soa, DnsError> {
+        let socket = self.pool.get(server)?;
+        let response = socket.query(zone.to_string(), DnsQueryType::Iterative, DnsRecordType::SOA)?;
+        response
+            .answers()
+            .iter()
+            .find_map(|record| record.soa())
+            .ok_or(DnsError::MissingRecord(DnsRecordType::SOA))
+    }
+
+    /// Resolves `name` by starting at `roots` and following referrals
+    /// down to an authoritative server, instead of asking a recursive
+    /// one. Each server is only told as much of the name as it needs to
+    /// refer us onwards: the next label down, asked for as NS (QNAME
+    /// minimization, RFC 9156). If a server fails such a query with
+    /// anything but NXDOMAIN, the rest of the way is asked with the full
+    /// name. An NXDOMAIN for part of the name is returned as is, since
+    /// nothing can exist below it (RFC 8020).
+    ///
+    /// Referrals are followed to the glue addresses they come with. When
+    /// there is no glue, the nameservers' own addresses are resolved the
+    /// same way, starting again from the roots. At most `MAX_REFERRALS`
+    /// are followed in all. Delegated servers are queried on the same port
+    /// as the roots.
+    pub fn resolve_iteratively(
+        &mut self,
+        name: &str,
+        record: DnsRecordType,
+        roots: &[SocketAddr],
+    ) -> Result<DnsMessage, DnsError> {
+        let mut referrals = 0;
+        self.resolve_from(name, record, roots, &mut referrals)
+    }
+
+    fn resolve_from(
+        &mut self,
+        name: &str,
+        record: DnsRecordType,
+        roots: &[SocketAddr],
+        referrals: &mut usize,
+    ) -> Result<DnsMessage, DnsError> {
+        let target = normalize(name);
+        let labels: Vec<&str> = target.split('.').filter(|label| !label.is_empty()).collect();
+        let port = roots.first().ok_or(DnsError::NoServers)?.port();
+        let mut zone = String::new();
+        let mut servers = roots.to_vec();
+        // How many of the name's trailing labels have been asked about.
+        let mut known = 0;
+        let mut minimize = true;
+        loop {
+            let minimized = minimize && known + 1 < labels.len();
+            let (qname, qtype) = if minimized {
+                (labels[labels.len() - known - 1..].join("."), DnsRecordType::NS)
+            } else {
+                (target.clone(), record)
+            };
+            let response = self.ask_any(&servers, &qname, qtype)?;
+
+            if let Some(delegation) = referral(&response, &zone, &target) {
+                *referrals += 1;
+                if *referrals > MAX_REFERRALS {
+                    return Err(DnsError::ReferralLimit);
+                }
+                debug!("referred from {:?} to {:?}", zone, delegation.zone);
+                let addresses = if delegation.glue.is_empty() {
+                    self.resolve_nameservers(&delegation.nameservers, roots, referrals)?
+                } else {
+                    delegation.glue
+                };
+                known = label_count(&delegation.zone);
+                servers = addresses.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                zone = delegation.zone;
+                continue;
+            }
+            if !minimized {
+                return Ok(response);
+            }
+            match response.rcode() {
+                // No zone cut here, so the same servers know the next label.
+                0 => known += 1,
+                3 => return Ok(response),
+                rcode => {
+                    debug!("minimized query for {} failed with rcode {}, asking for the full name", qname, rcode);
+                    minimize = false;
+                }
+            }
+        }
+    }
+
+    /// Finds the addresses of the first of `nameservers` that resolves,
+    /// for a referral that came without glue.
+    fn resolve_nameservers(
+        &mut self,
+        nameservers: &[String],
+        roots: &[SocketAddr],
+        referrals: &mut usize,
+    ) -> Result<Vec<IpAddr>, DnsError> {
+        for nameserver in nameservers {
+            let addresses: Vec<IpAddr> = match self.resolve_from(nameserver, DnsRecordType::A, roots, referrals) {
+                Ok(response) => response.answers().iter().filter_map(|record| record.ip_address()).collect(),
+                Err(DnsError::ReferralLimit) => return Err(DnsError::ReferralLimit),
+                Err(e) => {
+                    debug!("could not resolve nameserver {}: {}", nameserver, e);
+                    continue;
+                }
+            };
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        Err(DnsError::MissingRecord(DnsRecordType::A))
+    }
+
+    /// Sends a non-recursive query to each of `servers` in turn until one
+    /// of them answers.
+    fn ask_any(&mut self, servers: &[SocketAddr], name: &str, record: DnsRecordType) -> Result<DnsMessage, DnsError> {
+        let mut last_error = DnsError::NoServers;
+        for &server in servers {
+            let response = self
+                .pool
+                .get(server)
+                .map_err(DnsError::from)
+                .and_then(|socket| socket.query(name.to_string(), DnsQueryType::Iterative, record));
+            match response {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    debug!("query for {} {} to {} failed, trying next server: {}", name, record, server, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Finds the address of a nameserver, preferring glue records from
+    /// the additional section of `response` over a fresh lookup.
+    fn nameserver_address(&mut self, response: &DnsMessage, nameserver: &str) -> Option<IpAddr> {
+        let glue = response
+            .additional()
+            .iter()
+            .filter(|record| record.name().eq_ignore_ascii_case(nameserver))
+            .find_map(|record| record.ip_address());
+        if glue.is_some() {
+            return glue;
+        }
+        self.query(nameserver, DnsQueryType::Recursive, DnsRecordType::A)
+            .ok()?
+            .answers()
+            .iter()
+            .find_map(|record| record.ip_address())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_server, mock_server_at, name, query_id, question, soa_rdata, Packet};
+    use crate::dns::Backoff;
+
+    fn answering_server(address: u8) -> std::net::SocketAddr {
+        mock_server(move |query| {
+            Some(
+                Packet::response(query_id(query))
+                    .question("example.com", 1)
+                    .answer("example.com", 1, 60, vec![10, 0, 0, address])
+                    .build(),
+            )
+        })
+    }
+
+    #[test]
+    fn test_it_pins_the_transaction_id() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let server = mock_server(move |query| {
+            sent.send(query_id(query)).unwrap();
+            let (qname, qtype) = question(query);
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        resolver.set_transaction_id(Some(4242));
+        let response = resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(received.recv().unwrap(), 4242);
+        assert_eq!(&resolver.last_request()[..2], &4242u16.to_be_bytes());
+
+        let mut out = Vec::new();
+        crate::output::write_response(&mut out, "example.com", DnsRecordType::A, &response, None, crate::output::Style::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with(";; example.com A status: NOERROR, id: 4242\n"));
+    }
+
+    #[test]
+    fn test_it_resolves_through_the_search_list() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let mut response = Packet::response(query_id(query)).question(&qname, qtype);
+            if qname == "www.corp.example" && qtype == 1 {
+                response = response.answer(&qname, 1, 60, vec![10, 0, 0, 7]);
+            } else if qname != "www.corp.example" {
+                response = response.rcode(3);
+            }
+            Some(response.build())
+        });
+        let settings = ResolverSettings {
+            servers: vec![server],
+            search: vec!["lab.example".to_string(), "corp.example.".to_string()],
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        assert_eq!(resolver.lookup_ip("www").unwrap(), vec!["10.0.0.7".parse::<IpAddr>().unwrap()]);
+        assert!(resolver.resolve("www.", DnsRecordType::A).unwrap().is_nxdomain());
+    }
+
+    #[test]
+    fn test_it_prefers_the_host_table_when_told_to() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let server = mock_server(move |query| {
+            let (qname, qtype) = question(query);
+            sent.send(qname.clone()).unwrap();
+            let mut response = Packet::response(query_id(query)).question(&qname, qtype);
+            if qtype == 1 {
+                response = response.answer(&qname, 1, 60, vec![10, 0, 0, 9]);
+            }
+            Some(response.build())
+        });
+        let settings = ResolverSettings {
+            servers: vec![server],
+            lookup: vec![LookupSource::File, LookupSource::Bind],
+            hosts_file: PathBuf::from("test/hosts"),
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let fileserver = resolver.lookup_ip("fileserver.example.com").unwrap();
+        assert_eq!(fileserver, vec!["192.0.2.10".parse::<IpAddr>().unwrap()]);
+        assert_eq!(received.try_iter().count(), 0);
+
+        // Names the table lacks still go to the nameservers.
+        let other = resolver.lookup_ip("other.example.com").unwrap();
+        assert_eq!(other, vec!["10.0.0.9".parse::<IpAddr>().unwrap()]);
+        assert!(received.try_iter().all(|qname| qname == "other.example.com"));
+    }
+
+    #[test]
+    fn test_it_looks_up_in_the_order_resolv_conf_gives() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let server = mock_server(move |query| {
+            let (qname, qtype) = question(query);
+            sent.send(qname.clone()).unwrap();
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let settings = ResolverSettings {
+            servers: vec![server],
+            lookup: vec![LookupSource::File, LookupSource::Bind],
+            hosts_file: PathBuf::from("test/hosts"),
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let response = resolver.lookup("fileserver", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.10".parse().unwrap()));
+        assert!(resolver.last_stats().is_none());
+        assert_eq!(received.try_iter().count(), 0);
+
+        // Other types, and names the table lacks, go to the nameservers.
+        resolver.lookup("fileserver", DnsQueryType::Recursive, DnsRecordType::MX).unwrap();
+        resolver.lookup("other.example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), vec!["fileserver", "other.example.com"]);
+
+        // With DNS first, the table only fills in for a name without
+        // addresses there.
+        let settings = ResolverSettings {
+            lookup: vec![LookupSource::Bind, LookupSource::File],
+            ..settings
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let response = resolver.lookup("fileserver", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.10".parse().unwrap()));
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), vec!["fileserver"]);
+    }
+
+    #[test]
+    fn test_it_falls_back_to_the_host_table_when_dns_fails() {
+        let settings = ResolverSettings {
+            servers: vec![mock_server(|_| None)],
+            timeout: Duration::from_millis(100),
+            lookup: vec![LookupSource::Bind, LookupSource::File],
+            hosts_file: PathBuf::from("test/hosts"),
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let response = resolver.lookup("fileserver", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.10".parse().unwrap()));
+        let addresses = resolver.lookup_ip("fileserver").unwrap();
+        assert_eq!(addresses, vec!["192.0.2.10".parse::<IpAddr>().unwrap()]);
+
+        // Without an entry in the table, the DNS error stands.
+        assert!(resolver.lookup("other.example.com", DnsQueryType::Recursive, DnsRecordType::A).is_err());
+        assert!(resolver.lookup_ip("other.example.com").is_err());
+    }
+
+    /// Starts a server that, after each query, waits a moment for another
+    /// before answering. It reports the source port and type of each query,
+    /// and how many earlier ones were still unanswered when it arrived.
+    fn sequencing_server() -> (SocketAddr, std::sync::mpsc::Receiver<(u16, u16, usize)>) {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let (sent, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                sock.set_read_timeout(None).unwrap();
+                let mut pending = Vec::new();
+                while let Ok((len, peer)) = sock.recv_from(&mut buf) {
+                    let (qname, qtype) = question(&buf[..len]);
+                    sent.send((peer.port(), qtype, pending.len())).unwrap();
+                    let rdata = if qtype == 1 { vec![10, 0, 0, 1] } else { vec![0; 16] };
+                    let reply = Packet::response(query_id(&buf[..len]))
+                        .question(&qname, qtype)
+                        .answer(&qname, qtype, 60, rdata)
+                        .build();
+                    pending.push((reply, peer));
+                    sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+                }
+                for (reply, peer) in pending {
+                    sock.send_to(&reply, peer).unwrap();
+                }
+            }
+        });
+        (addr, received)
+    }
+
+    #[test]
+    fn test_it_sequences_address_queries_as_configured() {
+        let mut sequences = Vec::new();
+        for address_queries in &[AddressQueries::Parallel, AddressQueries::Sequential, AddressQueries::SequentialReopen] {
+            let (server, received) = sequencing_server();
+            let settings = ResolverSettings {
+                servers: vec![server],
+                address_queries: *address_queries,
+                ..ResolverSettings::default()
+            };
+            let mut resolver = Resolver::from_settings(&settings).unwrap();
+            assert_eq!(resolver.lookup_ip("example.com").unwrap().len(), 2);
+            sequences.push((received.recv().unwrap(), received.recv().unwrap()));
+        }
+
+        assert!(sequences.iter().all(|(a, aaaa)| a.1 == 1 && a.2 == 0 && aaaa.1 == 28));
+        // By default the AAAA query arrives before the A is answered, from
+        // the same port.
+        let (a, aaaa) = sequences[0];
+        assert_eq!((aaaa.0, aaaa.2), (a.0, 1));
+        // With single-request it waits for the answer.
+        let (a, aaaa) = sequences[1];
+        assert_eq!((aaaa.0, aaaa.2), (a.0, 0));
+        // With single-request-reopen it also comes from a new port.
+        let (a, aaaa) = sequences[2];
+        assert_eq!(aaaa.2, 0);
+        assert_ne!(aaaa.0, a.0);
+    }
+
+    #[test]
+    fn test_it_fails_over_paired_address_queries() {
+        let answering = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let reply = Packet::response(query_id(query)).question(&qname, qtype);
+            Some(match qtype {
+                1 => reply.answer(&qname, 1, 60, vec![10, 0, 0, 2]).build(),
+                _ => reply.build(),
+            })
+        });
+        let servers = [mock_server(|_| None), answering];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        resolver.sockets[0].set_timeout(Duration::from_millis(200)).unwrap();
+        let started = Instant::now();
+        assert_eq!(resolver.lookup_ip("example.com").unwrap(), vec!["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        // The silent server is waited on once, not again for each type.
+        assert!(started.elapsed() < Duration::from_millis(380));
+        let trace = resolver.last_trace();
+        assert_eq!(trace.len(), 2);
+        assert_eq!((trace[0].server, trace[1].server), (servers[0], servers[1]));
+        assert!(trace[0].error.is_some() && trace[1].error.is_none());
+    }
+
+    #[test]
+    fn test_it_falls_back_to_a_when_ipv6_first_gets_nodata() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let server = mock_server(move |query| {
+            let (qname, qtype) = question(query);
+            sent.send(qtype).unwrap();
+            let reply = Packet::response(query_id(query)).question(&qname, qtype);
+            Some(match qtype {
+                1 => reply.answer(&qname, 1, 60, vec![10, 0, 0, 1]).build(),
+                _ => reply.build(),
+            })
+        });
+        let settings = ResolverSettings {
+            servers: vec![server],
+            address_queries: AddressQueries::Ipv6First,
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let addresses = resolver.lookup_ip("example.com").unwrap();
+        assert_eq!(addresses, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(received.try_iter().collect::<Vec<u16>>(), vec![28, 1]);
+    }
+
+    #[test]
+    fn test_it_resolves_every_type_of_a_name() {
+        // NS queries go unanswered, so one type fails.
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match qtype {
+                1 => packet.answer(&qname, 1, 60, vec![192, 0, 2, 1]),
+                15 => packet.answer(&qname, 15, 60, [vec![0, 10], name("mail.example.com")].concat()),
+                16 => packet.answer(&qname, 16, 60, b"\x05hello".to_vec()),
+                6 => packet.answer(&qname, 6, 60, soa_rdata("ns.example.com", 7)),
+                2 => return None,
+                _ => packet,
+            };
+            Some(packet.build())
+        });
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        resolver.sockets[0].set_timeout(Duration::from_millis(200)).unwrap();
+        let sets = resolver.resolve_all("example.com");
+
+        assert_eq!(sets.len(), RESOLVE_ALL_TYPES.len());
+        let records = |record| sets[&record].as_ref().unwrap();
+        assert_eq!(records(DnsRecordType::A)[0].ip_address(), Some("192.0.2.1".parse().unwrap()));
+        assert!(records(DnsRecordType::AAAA).is_empty());
+        assert_eq!(records(DnsRecordType::MX)[0].mx().unwrap().exchange, "mail.example.com");
+        assert_eq!(records(DnsRecordType::TXT)[0].rdata(), &RecordData::Txt(vec!["hello".to_string()]));
+        assert_eq!(records(DnsRecordType::SOA)[0].soa().unwrap().serial, 7);
+        assert!(sets[&DnsRecordType::NS].is_err());
+    }
+
+    #[test]
+    fn test_it_asks_again_only_for_unanswered_types() {
+        // The first NS query goes unanswered; the rest are answered.
+        let queries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = queries.clone();
+        let server = mock_server(move |query| {
+            let count = counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let (qname, qtype) = question(query);
+            if qtype == 2 && count < RESOLVE_ALL_TYPES.len() {
+                return None;
+            }
+            Some(Packet::response(query_id(query)).question(&qname, qtype).build())
+        });
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        resolver.sockets[0].set_timeout(Duration::from_millis(200)).unwrap();
+        let sets = resolver.resolve_all("example.com");
+
+        assert!(sets.values().all(Result::is_ok));
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), RESOLVE_ALL_TYPES.len() + 1);
+    }
+
+    #[test]
+    fn test_it_orders_search_candidates_by_ndots() {
+        let search = vec!["example.com".to_string()];
+        assert_eq!(search_candidates("www", &search, 1), vec!["www.example.com", "www"]);
+        assert_eq!(search_candidates("a.b", &search, 1), vec!["a.b", "a.b.example.com"]);
+        assert_eq!(search_candidates("a.b", &[], 1), vec!["a.b"]);
+    }
+
+    #[test]
+    fn test_it_orders_addresses_for_happy_eyeballs() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let ordered = happy_eyeballs_order(vec![ip("::1"), ip("::2")], vec![ip("10.0.0.1")]);
+        assert_eq!(ordered, vec![ip("::1"), ip("10.0.0.1"), ip("::2")]);
+    }
+
+    #[test]
+    fn test_it_connects_to_a_resolved_service() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            // Nothing listens on ::1, so the IPv4 address has to be tried.
+            let packet = match qtype {
+                1 => packet.answer("localhost", 1, 60, vec![127, 0, 0, 1]),
+                _ => packet.answer("localhost", 28, 60, "::1".parse::<std::net::Ipv6Addr>().unwrap().octets().to_vec()),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        let stream = resolver.connect_service("localhost", port).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    fn answered_by(resolver: &mut Resolver) -> u8 {
+        let response = resolver
+            .query("example.com", DnsQueryType::Recursive, DnsRecordType::A)
+            .unwrap();
+        response.answers()[0].data()[3]
+    }
+
+    #[test]
+    fn test_it_rotates_the_starting_server() {
+        let servers = [answering_server(1), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, true).unwrap();
+        assert_eq!(answered_by(&mut resolver), 1);
+        assert_eq!(answered_by(&mut resolver), 2);
+        assert_eq!(resolver.last_stats().unwrap().server, servers[1]);
+        assert_eq!(answered_by(&mut resolver), 1);
+    }
+
+    #[test]
+    fn test_it_always_starts_with_the_first_server_without_rotate() {
+        let servers = [answering_server(1), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        assert_eq!(answered_by(&mut resolver), 1);
+        assert_eq!(answered_by(&mut resolver), 1);
+    }
+
+    /// TXT rdata holding each of `strings` as a character-string.
+    fn txt(strings: &[&str]) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        for string in strings {
+            rdata.push(string.len() as u8);
+            rdata.extend_from_slice(string.as_bytes());
+        }
+        rdata
+    }
+
+    #[test]
+    fn test_it_fetches_the_soa_serial() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match qname.as_str() {
+                "example.com" => packet.answer("example.com", 6, 300, soa_rdata("ns.example.com", 2021041301)),
+                _ => packet,
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        assert_eq!(resolver.soa_serial("example.com").unwrap(), 2021041301);
+        assert!(matches!(
+            resolver.soa_serial("www.example.com"),
+            Err(DnsError::MissingRecord(DnsRecordType::SOA))
+        ));
+    }
+
+    #[test]
+    fn test_it_finds_spf_and_dmarc_among_txt_records() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match qname.as_str() {
+                "example.com" => packet
+                    .answer("example.com", 16, 300, txt(&["google-site-verification=abc"]))
+                    .answer("example.com", 16, 300, txt(&["v=spf10 not a policy"]))
+                    .answer("example.com", 16, 300, txt(&["v=spf1 include:_spf.example.com ", "-all"])),
+                "_dmarc.example.com" => packet.answer("_dmarc.example.com", 16, 300, txt(&["v=DMARC1; p=reject"])),
+                "example.net" if qtype == 99 => packet.answer("example.net", 99, 300, txt(&["v=spf1 -all"])),
+                "example.net" => packet,
+                _ => packet.rcode(3),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        assert_eq!(
+            resolver.lookup_spf("example.com").unwrap().as_deref(),
+            Some("v=spf1 include:_spf.example.com -all")
+        );
+        assert_eq!(resolver.lookup_spf("example.net").unwrap().as_deref(), Some("v=spf1 -all"));
+        assert_eq!(resolver.lookup_dmarc("example.com").unwrap().as_deref(), Some("v=DMARC1; p=reject"));
+        assert_eq!(resolver.lookup_dmarc("example.org").unwrap(), None);
+    }
+
+    #[test]
+    fn test_it_joins_a_split_dkim_key() {
+        let server = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match qname.as_str() {
+                "mail2021._domainkey.example.com" => {
+                    packet.answer(&qname, 16, 300, txt(&["v=DKIM1; k=rsa; p=MIIBIjANBg", "kqhkiG9w0BAQEFAAOCAQ8A"]))
+                }
+                _ => packet.rcode(3),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        assert_eq!(
+            resolver.lookup_dkim("mail2021", "example.com").unwrap().as_deref(),
+            Some("v=DKIM1; k=rsa; p=MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A")
+        );
+        assert_eq!(resolver.lookup_dkim("other", "example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn test_it_stops_when_the_budget_runs_out() {
+        let silent = [mock_server(|_| None), mock_server(|_| None)];
+        let mut resolver = Resolver::new(&silent, false).unwrap();
+        let budget = Duration::from_millis(300);
+        resolver.set_budget(Some(budget)).unwrap();
+
+        let started = Instant::now();
+        let result = resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::Timeout)));
+        // Allow some slack for scheduling, but far less than a socket
+        // timeout per server.
+        assert!(started.elapsed() < budget + Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_it_keeps_raw_queries_to_the_budget() {
+        let silent = [mock_server(|_| None), mock_server(|_| None)];
+        let mut resolver = Resolver::new(&silent, true).unwrap();
+        let budget = Duration::from_millis(300);
+        resolver.set_budget(Some(budget)).unwrap();
+
+        let started = Instant::now();
+        let result = resolver.query_raw(&DnsMessage::new(7).to_bytes());
+        assert!(matches!(result, Err(DnsError::Timeout)));
+        assert!(started.elapsed() < budget + Duration::from_millis(200));
+        // The next query starts at the other server.
+        assert_eq!(resolver.next_start, 1);
+    }
+
+    #[test]
+    fn test_it_keeps_to_the_budget_when_falling_back_without_edns() {
+        let silent = [mock_server(|_| None)];
+        let settings = ResolverSettings {
+            servers: silent.to_vec(),
+            options: QueryOptions {
+                edns_version: Some(0),
+                ..QueryOptions::default()
+            },
+            budget: Some(Duration::from_millis(300)),
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let started = Instant::now();
+        let result = resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_it_keeps_retries_within_the_budget() {
+        let queries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = queries.clone();
+        let silent = mock_server(move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            None
+        });
+        let settings = ResolverSettings {
+            servers: vec![silent],
+            timeout: Duration::from_millis(200),
+            retry: RetryPolicy {
+                attempts: 3,
+                backoff: Backoff::Exponential,
+                ..RetryPolicy::default()
+            },
+            budget: Some(Duration::from_millis(300)),
+            ..ResolverSettings::default()
+        };
+        let mut resolver = Resolver::from_settings(&settings).unwrap();
+        let started = Instant::now();
+        let result = resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(matches!(result, Err(DnsError::Timeout)));
+        // The second attempt gets only what is left of the budget, not the
+        // 400ms the backoff would give it, and there is no time for a third.
+        assert!(started.elapsed() < Duration::from_millis(450));
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_it_fails_over_before_falling_back_without_edns() {
+        let servers = [mock_server(|_| None), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        resolver.set_options(QueryOptions {
+            edns_version: Some(0),
+            ..QueryOptions::default()
+        });
+        resolver.sockets[0].set_timeout(Duration::from_millis(200)).unwrap();
+        let started = Instant::now();
+        resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(380));
+        assert!(!resolver.sockets[0].edns_incapable());
+    }
+
+    #[test]
+    fn test_it_traces_a_failover() {
+        // The first server's answer has the query's id but is too short
+        // to parse.
+        let servers = [mock_server(|query| Some(vec![query[0], query[1], 0])), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+
+        let trace = resolver.last_trace();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].server, servers[0]);
+        assert!(trace[0].error.as_ref().unwrap().starts_with("malformed DNS message"));
+        assert_eq!(trace[1].server, servers[1]);
+        assert_eq!(trace[1].error, None);
+        assert_eq!(resolver.last_stats().unwrap().attempts, trace);
+    }
+
+    #[test]
+    fn test_it_traces_a_raw_query_failover() {
+        let servers = [mock_server(|_| Some(vec![0; 3])), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        // An id of 0 makes the first server's short answer count as a reply.
+        let mut request = DnsMessage::new(0);
+        request.set_query("example.com".to_string(), DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        resolver.query_raw(&request.to_bytes()).unwrap();
+
+        let trace = resolver.last_trace();
+        assert_eq!(trace.len(), 2);
+        assert!(trace[0].error.is_some());
+        assert_eq!(trace[1].server, servers[1]);
+        assert_eq!(resolver.last_stats().unwrap().attempts, trace);
+    }
+
+    #[test]
+    fn test_it_fails_over_from_a_refused_port() {
+        // Nothing listens on the port once the socket is gone.
+        let refused = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let servers = [refused, answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+
+        let started = Instant::now();
+        let response = resolver.query("example.com", DnsQueryType::Recursive, DnsRecordType::A).unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("10.0.0.2".parse().unwrap()));
+        assert!(started.elapsed() < DEFAULT_TIMEOUT);
+        let unreachable = DnsError::Unreachable(refused).to_string();
+        assert_eq!(resolver.last_trace()[0].error.as_deref(), Some(unreachable.as_str()));
+    }
+
+    #[test]
+    fn test_it_caches_negative_answers() {
+        let queries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = queries.clone();
+        let server = mock_server(move |query| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let (qname, qtype) = question(query);
+            Some(
+                Packet::response(query_id(query))
+                    .question(&qname, qtype)
+                    .rcode(3)
+                    .authority("example.com", 6, 900, soa_rdata("ns.example.com", 1))
+                    .build(),
+            )
+        });
+
+        let mut resolver = Resolver::new(&[server], false).unwrap();
+        for _ in 0..2 {
+            let response = resolver
+                .query("nope.example.com", DnsQueryType::Recursive, DnsRecordType::A)
+                .unwrap();
+            assert!(response.is_nxdomain());
+        }
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(resolver.last_stats().is_none());
+
+        // Other types for the same name are still asked.
+        resolver
+            .query("nope.example.com", DnsQueryType::Recursive, DnsRecordType::MX)
+            .unwrap();
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_it_keeps_the_raw_response() {
+        let servers = [answering_server(7)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        answered_by(&mut resolver);
+
+        let path = std::env::temp_dir().join(format!("dig-rs-raw-{}", std::process::id()));
+        std::fs::write(&path, resolver.last_response()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let response = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("10.0.0.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_it_reports_delegation_mismatches() {
+        // The parent delegates to ns1 and ns2, with glue for ns1 only.
+        let parent = mock_server_at("127.0.0.31:0".parse().unwrap(), |query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query))
+                .flags(0x8000)
+                .question(&qname, qtype)
+                .authority("sub.example.com", 2, 172800, name("ns1.sub.example.com"))
+                .authority("sub.example.com", 2, 172800, name("ns2.sub.example.com"))
+                .additional("ns1.sub.example.com", 1, 172800, vec![127, 0, 0, 32]);
+            Some(packet.build())
+        });
+        // ns1 lists itself and ns3.
+        mock_server_at(SocketAddr::new("127.0.0.32".parse().unwrap(), parent.port()), |query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query))
+                .flags(0x8400)
+                .question(&qname, qtype)
+                .answer("sub.example.com", 2, 300, name("ns1.sub.example.com"))
+                .answer("sub.example.com", 2, 300, name("ns3.sub.example.com"));
+            Some(packet.build())
+        });
+        // ns2 answers, but not authoritatively.
+        mock_server_at(SocketAddr::new("127.0.0.33".parse().unwrap(), parent.port()), |query| {
+            let (qname, qtype) = question(query);
+            Some(Packet::response(query_id(query)).flags(0x8000).question(&qname, qtype).build())
+        });
+        let recursive = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match (qname.as_str(), qtype) {
+                ("example.com", 2) => packet
+                    .answer("example.com", 2, 300, name("ns.example.com"))
+                    .additional("ns.example.com", 1, 300, vec![127, 0, 0, 31]),
+                ("ns2.sub.example.com", 1) => packet.answer("ns2.sub.example.com", 1, 300, vec![127, 0, 0, 33]),
+                _ => packet.rcode(3),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[recursive], false).unwrap();
+        let check = resolver.check_delegation("sub.example.com.", parent.port()).unwrap();
+        assert_eq!(check.parent, vec!["ns1.sub.example.com", "ns2.sub.example.com"]);
+        assert_eq!(check.child, vec!["ns1.sub.example.com", "ns3.sub.example.com"]);
+        assert_eq!(
+            check.problems,
+            vec![
+                DelegationProblem::MissingGlue("ns2.sub.example.com".to_string()),
+                DelegationProblem::Lame {
+                    nameserver: "ns2.sub.example.com".to_string(),
+                    reason: "not authoritative for the zone".to_string(),
+                },
+                DelegationProblem::OnlyAtParent("ns2.sub.example.com".to_string()),
+                DelegationProblem::OnlyAtChild("ns3.sub.example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nssearch_reports_each_servers_serial() {
+        // Both authoritative servers must listen on the same port, since
+        // the port is not part of their A records.
+        let ns1 = mock_server_at("127.0.0.2:0".parse().unwrap(), |query| {
+            Some(
+                Packet::response(query_id(query))
+                    .question("example.com", 6)
+                    .answer("example.com", 6, 300, soa_rdata("ns1.example.com", 2021040101))
+                    .build(),
+            )
+        });
+        mock_server_at(SocketAddr::new("127.0.0.3".parse().unwrap(), ns1.port()), |query| {
+            Some(
+                Packet::response(query_id(query))
+                    .question("example.com", 6)
+                    .answer("example.com", 6, 300, soa_rdata("ns1.example.com", 2021033100))
+                    .build(),
+            )
+        });
+        // The recursive server has glue for ns1 but not for ns2.
+        let recursive = mock_server(|query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).question(&qname, qtype);
+            let packet = match (qname.as_str(), qtype) {
+                ("example.com", 2) => packet
+                    .answer("example.com", 2, 300, name("ns1.example.com"))
+                    .answer("example.com", 2, 300, name("ns2.example.com"))
+                    .additional("ns1.example.com", 1, 300, vec![127, 0, 0, 2]),
+                ("ns2.example.com", 1) => packet.answer("ns2.example.com", 1, 300, vec![127, 0, 0, 3]),
+                _ => packet.rcode(3),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new(&[recursive], false).unwrap();
+        let serials = resolver.nssearch("example.com", ns1.port()).unwrap();
+        assert_eq!(serials.len(), 2);
+        assert_eq!(serials[0].nameserver, "ns1.example.com");
+        assert_eq!(serials[0].address, Some(ns1));
+        assert_eq!(serials[0].soa.as_ref().unwrap().serial, 2021040101);
+        assert_eq!(serials[1].nameserver, "ns2.example.com");
+        assert_eq!(serials[1].soa.as_ref().unwrap().serial, 2021033100);
+    }
+
+    #[test]
+    fn test_it_minimizes_names_while_following_referrals() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let log = move |server: &'static str, query: &[u8]| {
+            let (qname, qtype) = question(query);
+            tx.send((server, qname.clone(), qtype)).unwrap();
+            (qname.clone(), qtype, Packet::response(query_id(query)).flags(0x8000).question(&qname, qtype))
+        };
+
+        let root_log = log.clone();
+        let root = mock_server_at("127.0.0.11:0".parse().unwrap(), move |query| {
+            let (_, _, packet) = root_log("root", query);
+            let packet = packet
+                .authority("com", 2, 172800, name("a.gtld.test"))
+                .additional("a.gtld.test", 1, 172800, vec![127, 0, 0, 12]);
+            Some(packet.build())
+        });
+        let com_log = log.clone();
+        mock_server_at(SocketAddr::new("127.0.0.12".parse().unwrap(), root.port()), move |query| {
+            let (_, _, packet) = com_log("com", query);
+            let packet = packet
+                .authority("example.com", 2, 172800, name("ns.example.com"))
+                .additional("ns.example.com", 1, 172800, vec![127, 0, 0, 13]);
+            Some(packet.build())
+        });
+        mock_server_at(SocketAddr::new("127.0.0.13".parse().unwrap(), root.port()), move |query| {
+            let (qname, qtype, packet) = log("example.com", query);
+            let packet = packet.flags(0x8400);
+            let packet = match (qname.as_str(), qtype) {
+                // dept.example.com is not a separate zone.
+                ("dept.example.com", 2) => packet.authority("example.com", 6, 300, soa_rdata("ns.example.com", 1)),
+                ("www.dept.example.com", 1) => packet.answer("www.dept.example.com", 1, 300, vec![192, 0, 2, 80]),
+                _ => packet.rcode(3),
+            };
+            Some(packet.build())
+        });
+
+        let mut resolver = Resolver::new::<SocketAddr>(&[], false).unwrap();
+        let response = resolver
+            .resolve_iteratively("www.dept.example.com", DnsRecordType::A, &[root])
+            .unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.80".parse().unwrap()));
+
+        let queries: Vec<(&str, String, u16)> = rx.try_iter().collect();
+        let expected = [
+            ("root", "com", 2),
+            ("com", "example.com", 2),
+            ("example.com", "dept.example.com", 2),
+            ("example.com", "www.dept.example.com", 1),
+        ];
+        let expected: Vec<(&str, String, u16)> = expected.iter().map(|(s, n, t)| (*s, n.to_string(), *t)).collect();
+        assert_eq!(queries, expected);
+    }
+
+    /// A root server on `address` that delegates example.com to `ns`, with
+    /// glue pointing at `auth` if `glue` is set. Names under `test` it
+    /// answers itself, with ns.other.test at `auth`.
+    fn delegating_root(address: &str, auth: IpAddr, ns: &'static str, glue: bool) -> SocketAddr {
+        let auth = match auth {
+            IpAddr::V4(auth) => auth.octets().to_vec(),
+            IpAddr::V6(auth) => auth.octets().to_vec(),
+        };
+        mock_server_at(address.parse().unwrap(), move |query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).flags(0x8000).question(&qname, qtype);
+            let packet = if qname == "ns.other.test" && qtype == 1 {
+                packet.flags(0x8400).answer(&qname, 1, 300, auth.clone())
+            } else if qname.ends_with("test") {
+                packet.flags(0x8400).authority("test", 6, 300, soa_rdata("ns.other.test", 1))
+            } else if glue {
+                packet
+                    .authority("example.com", 2, 172800, name(ns))
+                    .additional(ns, 1, 172800, auth.clone())
+            } else {
+                packet.authority("example.com", 2, 172800, name(ns))
+            };
+            Some(packet.build())
+        })
+    }
+
+    /// The authoritative server for example.com, sharing the root's port.
+    fn example_com_server(auth: IpAddr, port: u16) -> SocketAddr {
+        mock_server_at(SocketAddr::new(auth, port), |query| {
+            let (qname, qtype) = question(query);
+            let packet = Packet::response(query_id(query)).flags(0x8400).question(&qname, qtype);
+            Some(packet.answer(&qname, 1, 300, vec![192, 0, 2, 80]).build())
+        })
+    }
+
+    #[test]
+    fn test_it_follows_a_referral_to_the_authoritative_answer() {
+        let auth = "127.0.0.22".parse().unwrap();
+        let root = delegating_root("127.0.0.21:0", auth, "ns.example.com", true);
+        example_com_server(auth, root.port());
+        let mut resolver = Resolver::new::<SocketAddr>(&[], false).unwrap();
+        let response = resolver
+            .resolve_iteratively("www.example.com", DnsRecordType::A, &[root])
+            .unwrap();
+        assert!(response.flags().aa);
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_it_resolves_nameservers_that_come_without_glue() {
+        let auth = "127.0.0.24".parse().unwrap();
+        let root = delegating_root("127.0.0.23:0", auth, "ns.other.test", false);
+        example_com_server(auth, root.port());
+        let mut resolver = Resolver::new::<SocketAddr>(&[], false).unwrap();
+        let response = resolver
+            .resolve_iteratively("www.example.com", DnsRecordType::A, &[root])
+            .unwrap();
+        assert_eq!(response.answers()[0].ip_address(), Some("192.0.2.80".parse().unwrap()));
+
+        // Nameservers that can only be found through their own zone loop.
+        let root = delegating_root("127.0.0.25:0", auth, "ns.example.com", false);
+        let result = resolver.resolve_iteratively("www.example.com", DnsRecordType::A, &[root]);
+        assert!(matches!(result, Err(DnsError::ReferralLimit)));
+    }
+
+    #[test]
+    fn test_compare_flags_mismatched_answers() {
+        let servers = [answering_server(1), answering_server(2)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        let comparison = resolver.compare("example.com", DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(!comparison.agree());
+        for response in &comparison.responses {
+            let answers = response.response.as_ref().unwrap().answers();
+            assert!(comparison.differs(&answers[0]));
+        }
+    }
+
+    #[test]
+    fn test_compare_accepts_matching_answers() {
+        let servers = [answering_server(1), answering_server(1)];
+        let mut resolver = Resolver::new(&servers, false).unwrap();
+        let comparison = resolver.compare("example.com", DnsQueryType::Recursive, DnsRecordType::A);
+        assert!(comparison.agree());
+        let answers = comparison.responses[0].response.as_ref().unwrap().answers();
+        assert!(!comparison.differs(&answers[0]));
+    }
+}
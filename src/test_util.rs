@@ -0,0 +1,179 @@
+//! Helpers for building canned DNS packets and mock servers in tests.
+
+// Not every test module uses every helper.
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+
+/// Encodes a name as uncompressed labels, for use inside rdata.
+pub fn name(name: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf
+}
+
+/// Packet builds the wire bytes of a message field by field, without
+/// going through the encoder under test.
+pub struct Packet {
+    id: u16,
+    flags: u16,
+    questions: Vec<(String, u16, u16)>,
+    answers: Vec<(String, u16, u16, u32, Vec<u8>)>,
+    authority: Vec<(String, u16, u16, u32, Vec<u8>)>,
+    additional: Vec<(String, u16, u16, u32, Vec<u8>)>,
+}
+
+impl Packet {
+    /// A recursive NOERROR response with the QR, RD and RA bits set.
+    pub fn response(id: u16) -> Self {
+        Packet {
+            id,
+            flags: 0x8180,
+            questions: vec![],
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        }
+    }
+
+    pub fn flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn rcode(mut self, rcode: u8) -> Self {
+        self.flags = (self.flags & !0x000f) | rcode as u16;
+        self
+    }
+
+    pub fn question(mut self, name: &str, rtype: u16) -> Self {
+        self.questions.push((name.to_string(), rtype, 1));
+        self
+    }
+
+    pub fn question_in_class(mut self, name: &str, rtype: u16, class: u16) -> Self {
+        self.questions.push((name.to_string(), rtype, class));
+        self
+    }
+
+    pub fn answer_in_class(mut self, name: &str, rtype: u16, class: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        self.answers.push((name.to_string(), rtype, class, ttl, rdata));
+        self
+    }
+
+    pub fn answer(mut self, name: &str, rtype: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        self.answers.push((name.to_string(), rtype, 1, ttl, rdata));
+        self
+    }
+
+    pub fn authority(mut self, name: &str, rtype: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        self.authority.push((name.to_string(), rtype, 1, ttl, rdata));
+        self
+    }
+
+    pub fn additional(mut self, name: &str, rtype: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        self.additional.push((name.to_string(), rtype, 1, ttl, rdata));
+        self
+    }
+
+    /// Adds an OPT record to the additional section, whose class is the
+    /// advertised UDP size and TTL the extended rcode, version and flags.
+    pub fn opt(mut self, udp_size: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        self.additional.push((String::new(), 41, udp_size, ttl, rdata));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for field in &[
+            self.id,
+            self.flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            self.authority.len() as u16,
+            self.additional.len() as u16,
+        ] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+        for (qname, qtype, qclass) in &self.questions {
+            buf.extend(name(qname));
+            buf.extend_from_slice(&qtype.to_be_bytes());
+            buf.extend_from_slice(&qclass.to_be_bytes());
+        }
+        for (rname, rtype, rclass, ttl, rdata) in self
+            .answers
+            .iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter())
+        {
+            buf.extend(name(rname));
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&rclass.to_be_bytes());
+            buf.extend_from_slice(&ttl.to_be_bytes());
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(rdata);
+        }
+        buf
+    }
+}
+
+/// Starts a UDP server on loopback that answers each datagram with
+/// whatever `handler` returns for it, or stays silent on `None`.
+pub fn mock_server<F>(handler: F) -> SocketAddr
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    mock_server_at("127.0.0.1:0".parse().unwrap(), handler)
+}
+
+/// Like `mock_server`, but bound to a specific address, e.g. one of the
+/// other 127.0.0.0/8 addresses to stand in for a server on port 53.
+pub fn mock_server_at<F>(addr: SocketAddr, handler: F) -> SocketAddr
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    let sock = UdpSocket::bind(addr).unwrap();
+    let addr = sock.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while let Ok((len, peer)) = sock.recv_from(&mut buf) {
+            if let Some(reply) = handler(&buf[..len]) {
+                let _ = sock.send_to(&reply, peer);
+            }
+        }
+    });
+    addr
+}
+
+/// Reads the transaction id out of a raw query.
+pub fn query_id(query: &[u8]) -> u16 {
+    u16::from_be_bytes([query[0], query[1]])
+}
+
+/// Reads the first question's name and type out of a raw query.
+pub fn question(query: &[u8]) -> (String, u16) {
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    while query[pos] != 0 {
+        let len = query[pos] as usize;
+        labels.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + len]).into_owned());
+        pos += len + 1;
+    }
+    let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+    (labels.join("."), qtype)
+}
+
+/// Builds SOA rdata with the given primary nameserver and serial.
+pub fn soa_rdata(mname: &str, serial: u32) -> Vec<u8> {
+    let mut rdata = name(mname);
+    rdata.extend(name(&format!("hostmaster.{}", mname)));
+    for field in &[serial, 7200, 3600, 1209600, 300] {
+        rdata.extend_from_slice(&field.to_be_bytes());
+    }
+    rdata
+}